@@ -1,5 +1,43 @@
-use crate::lowerer::{ByteCodeInstruction, StackFrame};
+use crate::lowerer::{ByteCodeInstruction, ListElement, OverflowMode, StackFrame};
+use std::cmp::Ordering;
 use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::Write;
+
+//How many values the stack and heap can hold before a program is stopped with a runtime error, rather than
+// running until it's used up all the memory there is
+const MAX_STACK: usize = 1 << 20;
+const MAX_HEAP: usize = 1 << 26;
+
+//What stopped a program part way through, e.g. "division by zero"
+#[derive(Debug)]
+pub struct RuntimeError {
+    pub message: String,
+}
+
+impl RuntimeError {
+    fn new(message: impl Into<String>) -> RuntimeError {
+        RuntimeError {
+            message: message.into(),
+        }
+    }
+}
+
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "runtime error: {}", self.message)
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+//A program that can't write its output can't go on
+impl From<io::Error> for RuntimeError {
+    fn from(error: io::Error) -> RuntimeError {
+        RuntimeError::new(format!("cannot write output: {}", error))
+    }
+}
 
 pub struct BytecodeInterpreter {
     pc: usize,
@@ -8,11 +46,18 @@ pub struct BytecodeInterpreter {
     pub heap: Vec<usize>,
     locals: Vec<usize>,
     labels: Vec<usize>,
+    //The list made for each block the first time it's pushed, which is shared by every push after that
+    blocks: HashMap<usize, usize>,
     return_stack: Vec<usize>,
+    overflow_mode: OverflowMode,
+    //Print each instruction and the stack it leaves to stderr as it runs
+    pub trace: bool,
+    pub max_stack: usize,
+    pub max_heap: usize,
 }
 
 impl BytecodeInterpreter {
-    pub fn new() -> BytecodeInterpreter {
+    pub fn new(overflow_mode: OverflowMode) -> BytecodeInterpreter {
         let locals: Vec<usize> = vec![0; 8]; //probably not correct, we'll be using rbp for locals
         BytecodeInterpreter {
             pc: 0,
@@ -21,11 +66,36 @@ impl BytecodeInterpreter {
             heap: Vec::new(),
             locals,
             labels: Vec::new(),
+            blocks: HashMap::new(),
             return_stack: Vec::new(),
+            overflow_mode,
+            trace: false,
+            max_stack: MAX_STACK,
+            max_heap: MAX_HEAP,
         }
     }
 
-    pub fn interpret(&mut self, program: &[(String, StackFrame)], constants: &[String]) {
+    //Anything the program prints is written to `out`. A runtime error stops it where it is, leaving the stack as it
+    // was when the error happened
+    pub fn interpret(
+        &mut self,
+        program: &[(String, StackFrame)],
+        constants: &[String],
+        out: &mut dyn Write,
+    ) -> Result<(), RuntimeError> {
+        //A runtime error can leave calls unreturned from, which the next program mustn't return to
+        self.return_stack.clear();
+
+        //Every frame shares the same locals, so there needs to be room for the most any of them uses
+        let max_locals = program
+            .iter()
+            .map(|(_, frame)| frame.max_locals)
+            .max()
+            .unwrap_or(0);
+        if self.locals.len() < max_locals {
+            self.locals.resize(max_locals, 0);
+        }
+
         let mut functions = HashMap::new();
 
         for (name, function) in program {
@@ -50,14 +120,31 @@ impl BytecodeInterpreter {
         }
 
         while self.pc < self.rom.len() {
+            let pc = self.pc;
             let opcode = self.rom[self.pc];
             let (bytecode_instruction, words_consumed) =
                 ByteCodeInstruction::decode(opcode, &self.rom[self.pc + 1..]);
 
             self.pc += words_consumed;
 
-            self.interpret_op(&bytecode_instruction, constants, &functions);
+            self.interpret_op(&bytecode_instruction, constants, &functions, out)?;
+
+            if self.stack.len() > self.max_stack {
+                return Err(RuntimeError::new("stack overflow"));
+            }
+
+            if self.trace {
+                let stack: Vec<i64> = self.stack.iter().map(|value| *value as i64).collect();
+                eprintln!(
+                    "{:>6} {:<32} {:?}",
+                    pc,
+                    format!("{:?}", bytecode_instruction),
+                    stack
+                );
+            }
         }
+
+        Ok(())
     }
 
     fn interpret_op(
@@ -65,8 +152,8 @@ impl BytecodeInterpreter {
         opcode: &ByteCodeInstruction,
         constants: &[String],
         functions: &HashMap<&String, usize>,
-    ) {
-        // println!(">pc: {}, op: {:?}", self.pc, opcode);
+        out: &mut dyn Write,
+    ) -> Result<(), RuntimeError> {
         match opcode {
             ByteCodeInstruction::Push(value) => {
                 self.stack.push(*value);
@@ -74,7 +161,7 @@ impl BytecodeInterpreter {
             ByteCodeInstruction::NewList => {
                 let length = self.stack.pop().unwrap();
 
-                let ptr = self.alloc(length + 1);
+                let ptr = self.alloc(length + 1)?;
                 self.heap[ptr] = length;
 
                 for i in 0..length {
@@ -84,7 +171,18 @@ impl BytecodeInterpreter {
                 self.stack.push(ptr);
             }
             ByteCodeInstruction::PushBlock { index } => {
-                self.stack.push(*index);
+                //A block captures nothing, so it's a list of just its code
+                let ptr = match self.blocks.get(index) {
+                    Some(ptr) => *ptr,
+                    None => {
+                        let ptr = self.alloc(2)?;
+                        self.heap[ptr] = 1;
+                        self.heap[ptr + 1] = *index;
+                        self.blocks.insert(*index, ptr);
+                        ptr
+                    }
+                };
+                self.stack.push(ptr);
             }
             ByteCodeInstruction::ListLen => {
                 let ptr = self.stack.pop().unwrap();
@@ -94,6 +192,10 @@ impl BytecodeInterpreter {
             ByteCodeInstruction::ListGet => {
                 let index = self.stack.pop().unwrap();
                 let ptr = self.stack.pop().unwrap();
+                //Negative indices wrap around to huge ones, so they're caught here too
+                if index >= self.heap[ptr] {
+                    return Err(RuntimeError::new("list index out of bounds"));
+                }
                 let element = self.heap[ptr + index + 1];
                 self.stack.push(element);
             }
@@ -118,6 +220,7 @@ impl BytecodeInterpreter {
                 self.stack.push(a);
                 self.stack.push(b);
             }
+            //Brings the third item to the top: a b c -- b c a
             ByteCodeInstruction::Rot => {
                 let a = self.stack.pop().unwrap();
                 let b = self.stack.pop().unwrap();
@@ -126,57 +229,82 @@ impl BytecodeInterpreter {
                 self.stack.push(a);
                 self.stack.push(c);
             }
-            ByteCodeInstruction::Inc => {
+            ByteCodeInstruction::Unrot => {
                 let a = self.stack.pop().unwrap();
-                self.stack.push(a + 1);
+                let b = self.stack.pop().unwrap();
+                let c = self.stack.pop().unwrap();
+                self.stack.push(a);
+                self.stack.push(c);
+                self.stack.push(b);
             }
-            ByteCodeInstruction::Dec => {
+            ByteCodeInstruction::TwoOver => {
+                let d = self.stack.pop().unwrap();
+                let c = self.stack.pop().unwrap();
+                let b = self.stack.pop().unwrap();
                 let a = self.stack.pop().unwrap();
-                self.stack.push(a - 1);
+                self.stack.push(a);
+                self.stack.push(b);
+                self.stack.push(c);
+                self.stack.push(d);
+                self.stack.push(a);
+                self.stack.push(b);
             }
-            ByteCodeInstruction::Add => {
-                let a = self.stack.pop().unwrap();
+            ByteCodeInstruction::TwoSwap => {
+                let d = self.stack.pop().unwrap();
+                let c = self.stack.pop().unwrap();
                 let b = self.stack.pop().unwrap();
-                self.stack.push(b + a);
-            }
-            ByteCodeInstruction::Sub => {
                 let a = self.stack.pop().unwrap();
-                let b = self.stack.pop().unwrap();
-                self.stack.push(b - a);
+                self.stack.push(c);
+                self.stack.push(d);
+                self.stack.push(a);
+                self.stack.push(b);
             }
-            ByteCodeInstruction::Mul => {
-                let a = self.stack.pop().unwrap();
-                let b = self.stack.pop().unwrap();
-                self.stack.push(b * a);
+            //Only the lowerer's loop counters are incremented or decremented, so unlike the arithmetic a program asks
+            // for these never wrap, and a counter going below zero means a loop indexed outside its list
+            ByteCodeInstruction::Inc => {
+                let a = self.stack.pop().unwrap() as i64;
+                let Some(result) = a.checked_add(1) else {
+                    return Err(RuntimeError::new("loop counter overflowed"));
+                };
+                self.stack.push(result as usize);
             }
+            ByteCodeInstruction::Dec => {
+                let a = self.stack.pop().unwrap() as i64;
+                if a <= 0 {
+                    return Err(RuntimeError::new("loop counter went below zero"));
+                }
+                self.stack.push((a - 1) as usize);
+            }
+            ByteCodeInstruction::Add => self.arithmetic(i64::checked_add, i64::wrapping_add)?,
+            ByteCodeInstruction::Sub => self.arithmetic(i64::checked_sub, i64::wrapping_sub)?,
+            ByteCodeInstruction::Mul => self.arithmetic(i64::checked_mul, i64::wrapping_mul)?,
             ByteCodeInstruction::Div => {
-                let a = self.stack.pop().unwrap();
-                let b = self.stack.pop().unwrap();
-                self.stack.push(b / a);
+                self.check_divisor()?;
+                self.arithmetic(i64::checked_div, i64::wrapping_div)?
             }
             ByteCodeInstruction::Mod => {
-                let a = self.stack.pop().unwrap();
-                let b = self.stack.pop().unwrap();
-                self.stack.push(b % a);
+                self.check_divisor()?;
+                self.arithmetic(i64::checked_rem, i64::wrapping_rem)?
             }
+            //Values are signed, so they're compared as such
             ByteCodeInstruction::Gt => {
-                let a = self.stack.pop().unwrap();
-                let b = self.stack.pop().unwrap();
+                let a = self.stack.pop().unwrap() as i64;
+                let b = self.stack.pop().unwrap() as i64;
                 self.stack.push(if b > a { 1 } else { 0 });
             }
             ByteCodeInstruction::GtEq => {
-                let a = self.stack.pop().unwrap();
-                let b = self.stack.pop().unwrap();
+                let a = self.stack.pop().unwrap() as i64;
+                let b = self.stack.pop().unwrap() as i64;
                 self.stack.push(if b >= a { 1 } else { 0 });
             }
             ByteCodeInstruction::Lt => {
-                let a = self.stack.pop().unwrap();
-                let b = self.stack.pop().unwrap();
+                let a = self.stack.pop().unwrap() as i64;
+                let b = self.stack.pop().unwrap() as i64;
                 self.stack.push(if b < a { 1 } else { 0 });
             }
             ByteCodeInstruction::LtEq => {
-                let a = self.stack.pop().unwrap();
-                let b = self.stack.pop().unwrap();
+                let a = self.stack.pop().unwrap() as i64;
+                let b = self.stack.pop().unwrap() as i64;
                 self.stack.push(if b <= a { 1 } else { 0 });
             }
             ByteCodeInstruction::Eq => {
@@ -184,31 +312,57 @@ impl BytecodeInterpreter {
                 let b = self.stack.pop().unwrap();
                 self.stack.push(if b == a { 1 } else { 0 });
             }
+            ByteCodeInstruction::Compare => {
+                let a = self.stack.pop().unwrap() as i64;
+                let b = self.stack.pop().unwrap() as i64;
+                self.stack.push(b.cmp(&a) as i64 as usize);
+            }
+            ByteCodeInstruction::CompareList { depth } => {
+                let a = self.stack.pop().unwrap();
+                let b = self.stack.pop().unwrap();
+                let ordering = self.compare_lists(b, a, *depth);
+                self.stack.push(ordering as i64 as usize);
+            }
             ByteCodeInstruction::Print => {
-                println!("{}", self.stack.pop().unwrap());
+                writeln!(out, "{}", self.stack.pop().unwrap() as i64)?;
+            }
+            ByteCodeInstruction::Not => {
+                let b = self.pop_bool();
+                self.stack.push(if b == 0 { 1 } else { 0 });
+            }
+            ByteCodeInstruction::PrintFunction { index } => {
+                self.stack.pop().unwrap();
+                writeln!(out, "{}", constants[*index])?;
             }
             ByteCodeInstruction::PrintBool => {
-                let b = self.stack.pop().unwrap();
-                println!("{}", if b > 0 { "true" } else { "false" });
+                let b = self.pop_bool();
+                writeln!(out, "{}", if b > 0 { "true" } else { "false" })?;
             }
-            ByteCodeInstruction::PrintList => {
+            ByteCodeInstruction::PrintList { depth, element } => {
                 let ptr = self.stack.pop().unwrap();
-
-                let len = self.heap[ptr];
-
-                print!("[");
-                for i in 0..len {
-                    print!("{}", self.heap[ptr + 1 + i]);
-                    if i < len - 1 {
-                        print!(" ");
-                    }
-                }
-                println!("]");
+                writeln!(out, "{}", self.format_list(ptr, *depth, *element))?;
             }
             ByteCodeInstruction::CallDynamic => {
                 let func = self.stack.pop().unwrap();
-                let name = &constants[func];
-                let addr = functions.get(name).unwrap();
+                //The type checker makes sure it's a function, but if anything else slips through it shouldn't be
+                // taken as an index into the constants, nor its length read past the end of the heap
+                let Some(addr) = self
+                    .heap
+                    .get(func)
+                    .filter(|len| **len > 0 && **len < self.heap.len() - func)
+                    .and_then(|_| self.heap.get(func + 1))
+                    .and_then(|code| constants.get(*code))
+                    .and_then(|name| functions.get(name))
+                else {
+                    return Err(RuntimeError::new(format!(
+                        "`{}` is not a function",
+                        func as i64
+                    )));
+                };
+
+                //The values it captured go on top of its arguments, first to last
+                let captures = func + 2..func + 1 + self.heap[func];
+                self.stack.extend_from_slice(&self.heap[captures]);
 
                 self.return_stack.push(self.pc);
 
@@ -226,18 +380,14 @@ impl BytecodeInterpreter {
                 self.pc = self.return_stack.pop().unwrap();
             }
             ByteCodeInstruction::Store { index } => {
-                if self.locals.len() <= *index {
-                    self.locals.insert(*index, self.stack.pop().unwrap())
-                } else {
-                    self.locals[*index] = self.stack.pop().unwrap();
-                }
+                self.locals[*index] = self.stack.pop().unwrap();
             }
             ByteCodeInstruction::Load { index } => {
                 self.stack.push(self.locals[*index]);
             }
             ByteCodeInstruction::Label(_) => {}
             ByteCodeInstruction::JumpIfFalse { label } => {
-                let cond = self.stack.pop().unwrap();
+                let cond = self.pop_bool();
                 if cond == 0 {
                     self.pc = self.labels[*label];
                 }
@@ -246,16 +396,188 @@ impl BytecodeInterpreter {
                 self.pc = self.labels[*label];
             }
         }
-        // println!("(=) {:?}", self.stack);
-        // println!("(^) {:?}", self.heap);
-        // println!("(*) {:?}", self.locals);
+
+        Ok(())
+    }
+
+    //Every op that produces a bool pushes 0 or 1, anything else means a value was mistyped somewhere
+    fn pop_bool(&mut self) -> usize {
+        let b = self.stack.pop().unwrap();
+        debug_assert!(b <= 1, "expected a bool to be 0 or 1 but got {}", b);
+        b
     }
 
-    fn alloc(&mut self, size: usize) -> usize {
+    //Compares element-wise, falling back to the lengths if one list is a prefix of the other. Inner lists are
+    // compared the same way until `depth` reaches the innermost elements
+    fn compare_lists(&self, lhs: usize, rhs: usize, depth: usize) -> Ordering {
+        let lhs = &self.heap[lhs + 1..lhs + 1 + self.heap[lhs]];
+        let rhs = &self.heap[rhs + 1..rhs + 1 + self.heap[rhs]];
+        for (a, b) in lhs.iter().zip(rhs) {
+            let ordering = if depth > 1 {
+                self.compare_lists(*a, *b, depth - 1)
+            } else {
+                (*a as i64).cmp(&(*b as i64))
+            };
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+        lhs.len().cmp(&rhs.len())
+    }
+
+    //Lists are printed as their space separated elements in square brackets, e.g. `[[1 2] [3]]`
+    fn format_list(&self, ptr: usize, depth: usize, kind: ListElement) -> String {
+        let elements: Vec<String> = self.heap[ptr + 1..ptr + 1 + self.heap[ptr]]
+            .iter()
+            .map(|element| match (depth, kind) {
+                (1, ListElement::Int) => (*element as i64).to_string(),
+                (1, ListElement::Bool) => (*element != 0).to_string(),
+                (1, ListElement::Function) => "fn".to_string(),
+                _ => self.format_list(*element, depth - 1, kind),
+            })
+            .collect();
+        format!("[{}]", elements.join(" "))
+    }
+
+    //Values on the stack are signed integers, the overflow mode decides what happens when a result doesn't fit
+    fn arithmetic(
+        &mut self,
+        checked: fn(i64, i64) -> Option<i64>,
+        wrapping: fn(i64, i64) -> i64,
+    ) -> Result<(), RuntimeError> {
+        let a = self.stack.pop().unwrap() as i64;
+        let b = self.stack.pop().unwrap() as i64;
+        let result = match self.overflow_mode {
+            OverflowMode::Wrapping => wrapping(b, a),
+            OverflowMode::Checked => match checked(b, a) {
+                Some(result) => result,
+                None => return Err(RuntimeError::new("integer overflow")),
+            },
+        };
+        self.stack.push(result as usize);
+        Ok(())
+    }
+
+    //Dividing by zero is an error whatever the overflow mode, as there's no result to wrap to
+    fn check_divisor(&self) -> Result<(), RuntimeError> {
+        if self.stack.last() == Some(&0) {
+            return Err(RuntimeError::new("division by zero"));
+        }
+        Ok(())
+    }
+
+    fn alloc(&mut self, size: usize) -> Result<usize, RuntimeError> {
         let index = self.heap.len();
-        for _i in 0..size {
-            self.heap.push(0);
+        if size > self.max_heap - index {
+            return Err(RuntimeError::new("out of memory"));
         }
-        index
+        self.heap.resize(index + size, 0);
+        Ok(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //How many words of heap a program uses by the time it ends
+    fn heap_used(source: &str) -> usize {
+        let program = crate::compile(source).unwrap();
+        let mut interpreter = BytecodeInterpreter::new(OverflowMode::Wrapping);
+        interpreter
+            .interpret(&program.bytecode, &program.constants, &mut Vec::new())
+            .unwrap();
+        interpreter.heap.len()
+    }
+
+    #[test]
+    fn dup_of_a_list_shares_its_elements() {
+        let once = heap_used("1000 0 replicate len print");
+        let duplicated = heap_used("1000 0 replicate dup len print len print");
+        //The length and a thousand elements
+        assert_eq!(once, 1001);
+        assert_eq!(duplicated, once);
+    }
+
+    //Runs hand built bytecode as the whole program, against whatever the heap has been set up to hold
+    fn run_main(
+        interpreter: &mut BytecodeInterpreter,
+        instructions: Vec<ByteCodeInstruction>,
+    ) -> Result<(), RuntimeError> {
+        let frame = StackFrame {
+            instructions,
+            max_locals: 0,
+            max_stack_depth: None,
+        };
+        interpreter.interpret(
+            &[("main".to_string(), frame)],
+            &["main".to_string()],
+            &mut Vec::new(),
+        )
+    }
+
+    #[test]
+    fn calling_a_function_longer_than_the_heap_is_an_error() {
+        let mut interpreter = BytecodeInterpreter::new(OverflowMode::Wrapping);
+        //Says it has four captures after its code, but the heap ends straight after the code
+        interpreter.heap = vec![5, 0];
+        let error = run_main(
+            &mut interpreter,
+            vec![
+                ByteCodeInstruction::Push(0),
+                ByteCodeInstruction::CallDynamic,
+            ],
+        )
+        .unwrap_err();
+        assert_eq!(error.message, "`0` is not a function");
+    }
+
+    //Runs a program with the given limits, returning the error it stopped with
+    fn error_with_limits(source: &str, max_stack: usize, max_heap: usize) -> RuntimeError {
+        let program = crate::compile(source).unwrap();
+        let mut interpreter = BytecodeInterpreter::new(OverflowMode::Wrapping);
+        interpreter.max_stack = max_stack;
+        interpreter.max_heap = max_heap;
+        interpreter
+            .interpret(&program.bytecode, &program.constants, &mut Vec::new())
+            .unwrap_err()
+    }
+
+    #[test]
+    fn running_out_of_stack_is_an_error() {
+        let error = error_with_limits("1 2 3 4 5 + + + + print", 4, MAX_HEAP);
+        assert_eq!(error.message, "stack overflow");
+    }
+
+    #[test]
+    fn running_out_of_heap_is_an_error() {
+        let error = error_with_limits("[1 2 3] print [4 5 6] print", MAX_STACK, 6);
+        assert_eq!(error.message, "out of memory");
+    }
+
+    #[test]
+    fn calling_something_that_is_not_a_function_is_an_error() {
+        let mut interpreter = BytecodeInterpreter::new(OverflowMode::Wrapping);
+        let error = run_main(
+            &mut interpreter,
+            vec![
+                ByteCodeInstruction::Push(7),
+                ByteCodeInstruction::CallDynamic,
+            ],
+        )
+        .unwrap_err();
+        assert_eq!(error.message, "`7` is not a function");
+
+        //An empty list is on the heap, but has no code to call
+        let error = run_main(
+            &mut interpreter,
+            vec![
+                ByteCodeInstruction::Push(0),
+                ByteCodeInstruction::NewList,
+                ByteCodeInstruction::CallDynamic,
+            ],
+        )
+        .unwrap_err();
+        assert_eq!(error.message, "`0` is not a function");
     }
 }
@@ -0,0 +1,45 @@
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+//Colour is on unless it's turned off with --no-color or by setting NO_COLOR, see https://no-color.org
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn no_color_requested() -> bool {
+    std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty())
+}
+
+//An ANSI escape code that displays as nothing when colour is off
+#[derive(Clone, Copy)]
+pub enum Color {
+    Red,
+    BrightRed,
+    Green,
+    Yellow,
+    Cyan,
+    Grey,
+    Bold,
+    Reset,
+}
+
+impl Display for Color {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if !ENABLED.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        let code = match self {
+            Color::Red => "\x1b[31m",
+            Color::BrightRed => "\x1b[91m",
+            Color::Green => "\x1b[32m",
+            Color::Yellow => "\x1b[33m",
+            Color::Cyan => "\x1b[36m",
+            Color::Grey => "\x1b[2m",
+            Color::Bold => "\x1b[1m",
+            Color::Reset => "\x1b[0m",
+        };
+        write!(f, "{}", code)
+    }
+}
@@ -1,50 +1,189 @@
+use crate::color::Color;
 use crate::lexer::Span;
 
-#[derive(Clone)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    //Something that's probably a mistake but doesn't stop the program from running
+    Warning,
+}
+
+//What kind of problem a diagnostic is about, so tools can tell them apart without matching on the message.
+// The numbers are shown with the message, e.g. `error[E006]:`, and shouldn't change once given out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiagnosticCode {
+    UnexpectedCharacter,
+    MalformedNumber,
+    UnexpectedToken,
+    UnclosedDelimiter,
+    InvalidMatch,
+    TypeMismatch,
+    EmptyStack,
+    NonEmptyStackAtEnd,
+    UnknownIdentifier,
+    DuplicateDefinition,
+    InvalidImport,
+    InvalidStackEffect,
+    UninferableFunction,
+    MalformedBytecode,
+    Unused,
+}
+
+impl DiagnosticCode {
+    pub fn id(&self) -> &'static str {
+        match self {
+            DiagnosticCode::UnexpectedCharacter => "E001",
+            DiagnosticCode::MalformedNumber => "E002",
+            DiagnosticCode::UnexpectedToken => "E003",
+            DiagnosticCode::UnclosedDelimiter => "E004",
+            DiagnosticCode::InvalidMatch => "E005",
+            DiagnosticCode::TypeMismatch => "E006",
+            DiagnosticCode::EmptyStack => "E007",
+            DiagnosticCode::NonEmptyStackAtEnd => "E008",
+            DiagnosticCode::UnknownIdentifier => "E009",
+            DiagnosticCode::DuplicateDefinition => "E010",
+            DiagnosticCode::InvalidImport => "E011",
+            DiagnosticCode::InvalidStackEffect => "E012",
+            DiagnosticCode::UninferableFunction => "E013",
+            DiagnosticCode::MalformedBytecode => "E014",
+            DiagnosticCode::Unused => "W001",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Diagnostic {
+    severity: Severity,
+    code: DiagnosticCode,
     message: String,
     span: Span,
     hint: Option<(String, Span)>,
 }
 
-const RED: &str = "\x1b[31m";
-const YELLOW: &str = "\x1b[33m";
-const BOLD: &str = "\x1b[1m";
-const RESET: &str = "\x1b[0m";
-const CYAN: &str = "\x1b[36m";
-const BRIGHT_RED: &str = "\x1b[91m";
-
 impl Diagnostic {
-    pub fn report_error(message: String, span: Span) -> Diagnostic {
+    pub fn report_error(code: DiagnosticCode, message: String, span: Span) -> Diagnostic {
         Diagnostic {
+            severity: Severity::Error,
+            code,
             message,
             span,
             hint: None,
         }
     }
-    pub fn report_error_with_hint(message: String, span: Span, hint: (String, Span)) -> Diagnostic {
+    pub fn report_error_with_hint(
+        code: DiagnosticCode,
+        message: String,
+        span: Span,
+        hint: (String, Span),
+    ) -> Diagnostic {
         Diagnostic {
+            severity: Severity::Error,
+            code,
             message,
             span,
             hint: Some(hint),
         }
     }
+    pub fn report_warning(code: DiagnosticCode, message: String, span: Span) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Warning,
+            code,
+            message,
+            span,
+            hint: None,
+        }
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+
+    pub fn code(&self) -> DiagnosticCode {
+        self.code
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn span(&self) -> Span {
+        self.span
+    }
+
+    //What the hint says and the span it points at, if there is one
+    pub fn hint(&self) -> Option<&(String, Span)> {
+        self.hint.as_ref()
+    }
+
+    fn label(&self) -> String {
+        match self.severity {
+            Severity::Error => format!(
+                "{}{}error[{}]:{}",
+                Color::Bold,
+                Color::Red,
+                self.code.id(),
+                Color::Reset
+            ),
+            Severity::Warning => format!(
+                "{}{}warning[{}]:{}",
+                Color::Bold,
+                Color::Yellow,
+                self.code.id(),
+                Color::Reset
+            ),
+        }
+    }
+
+    fn marker_color(&self) -> Color {
+        match self.severity {
+            Severity::Error => Color::BrightRed,
+            Severity::Warning => Color::Yellow,
+        }
+    }
+
+    //Displays the diagnostic against whichever files its spans fall in
+    pub fn display_in(&self, sources: &SourceMap) {
+        eprint!("{}", self.render_in(sources));
+    }
 
     pub fn display_diagnostic(&self, filename: &str, source: &str) {
-        let message = format!("{}{}error:{} {}", BOLD, RED, RESET, self.message);
+        eprint!("{}", self.render(filename, source));
+    }
+
+    //The diagnostic as it's displayed, with the line each of its spans falls on
+    pub fn render_in(&self, sources: &SourceMap) -> String {
+        let message = format!("{} {}", self.label(), self.message);
+        let (filename, source, span) = sources.locate(self.span);
+        let mut rendered = self.render_message(filename, source, message, span);
+
+        if let Some((message, span)) = &self.hint {
+            let message = format!("{}hint:{} {}", Color::Yellow, Color::Reset, message);
+            let (filename, source, span) = sources.locate(*span);
+            rendered += &self.render_message(filename, source, message, span);
+        }
+        rendered
+    }
 
-        Self::display_message(filename, source, message, self.span);
+    pub fn render(&self, filename: &str, source: &str) -> String {
+        let message = format!("{} {}", self.label(), self.message);
+
+        let mut rendered = self.render_message(filename, source, message, self.span);
 
         if let Some((message, span)) = &self.hint {
-            let message = format!("{}hint:{} {}", YELLOW, RESET, message);
-            Self::display_message(filename, source, message, *span);
+            let message = format!("{}hint:{} {}", Color::Yellow, Color::Reset, message);
+            rendered += &self.render_message(filename, source, message, *span);
         }
+        rendered
     }
 
-    fn display_message(filename: &str, source: &str, message: String, span: Span) {
+    fn render_message(&self, filename: &str, source: &str, message: String, span: Span) -> String {
         let mut line_start = 0;
 
-        for (line_index, line) in source.lines().enumerate() {
+        //Split on `\n` keeping it, so the offsets stay in step with the source whether lines end in `\n` or `\r\n`
+        for (line_index, full_line) in source.split_inclusive('\n').enumerate() {
+            let line = full_line
+                .strip_suffix('\n')
+                .map_or(full_line, |line| line.strip_suffix('\r').unwrap_or(line));
             let line_len = line.len();
             let line_end = line_start + line_len;
 
@@ -60,25 +199,119 @@ impl Diagnostic {
 
                 let marker_line: String = " ".repeat(offset_in_line) + &"^".repeat(marker_len);
 
-                eprintln!(
-                    "{} {}{}{}\n {}|\t{}\n {}|\t{}{}{}",
+                return format!(
+                    "{} {}{}{}\n {}|\t{}\n {}|\t{}{}{}\n",
                     message,
-                    CYAN,
+                    Color::Cyan,
                     location,
-                    RESET,
+                    Color::Reset,
                     line_index + 1,
                     line,
                     " ".repeat((line_index + 1).to_string().len()),
-                    BRIGHT_RED,
+                    self.marker_color(),
                     marker_line,
-                    RESET
+                    Color::Reset
                 );
-                return;
             }
 
-            // Advance line_start to the beginning of the next line
-            // +2 assumes Windows newlines (\r\n)
-            line_start = line_end + 2; //TODO: handle newlines more gracefully
+            line_start += full_line.len();
+        }
+        String::new()
+    }
+}
+
+//Every file in a program is appended to one source, so a span's offset alone says which file it's in
+pub struct SourceMap {
+    pub source: String,
+    files: Vec<(String, usize)>,
+}
+
+impl Default for SourceMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SourceMap {
+    pub fn new() -> SourceMap {
+        SourceMap {
+            source: String::new(),
+            files: Vec::new(),
+        }
+    }
+
+    //Appends the file's contents and returns the offset they start at
+    pub fn add(&mut self, filename: &str, contents: &str) -> usize {
+        let start = self.source.len();
+        self.files.push((filename.to_string(), start));
+        self.source.push_str(contents);
+        //keep the last token of one file from running into the first of the next
+        self.source.push('\n');
+        start
+    }
+
+    fn locate(&self, span: Span) -> (&str, &str, Span) {
+        let index = self
+            .files
+            .iter()
+            .rposition(|(_, start)| *start <= span.offset)
+            .unwrap_or(0);
+        let (filename, start) = &self.files[index];
+        let end = self
+            .files
+            .get(index + 1)
+            .map(|(_, next_start)| *next_start)
+            .unwrap_or(self.source.len());
+
+        (
+            filename,
+            &self.source[*start..end],
+            Span {
+                offset: span.offset - start,
+                length: span.length,
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color;
+
+    #[test]
+    fn nothing_is_coloured_when_colour_is_off() {
+        color::set_enabled(false);
+        let span = Span {
+            offset: 2,
+            length: 4,
+        };
+        let diagnostic = Diagnostic::report_error_with_hint(
+            DiagnosticCode::TypeMismatch,
+            "expected int but got bool".to_string(),
+            span,
+            ("bool introduced at".to_string(), span),
+        );
+        assert_eq!(
+            diagnostic.render("test.do", "1 true +"),
+            "error[E006]: expected int but got bool test.do:1:3\n 1|\t1 true +\n  |\t  ^^^^\n\
+             hint: bool introduced at test.do:1:3\n 1|\t1 true +\n  |\t  ^^^^\n"
+        );
+    }
+
+    #[test]
+    fn location_is_the_same_whatever_the_line_endings() {
+        color::set_enabled(false);
+        for (source, offset) in [("1\n2 true +", 4), ("1\r\n2 true +", 5)] {
+            let diagnostic = Diagnostic::report_error(
+                DiagnosticCode::TypeMismatch,
+                "expected int but got bool".to_string(),
+                Span { offset, length: 4 },
+            );
+            assert_eq!(
+                diagnostic.render("test.do", source),
+                "error[E006]: expected int but got bool test.do:2:3\n 2|\t2 true +\n  |\t  ^^^^\n"
+            );
         }
     }
 }
@@ -1,4 +1,5 @@
-use crate::lowerer::{ByteCodeInstruction, StackFrame};
+use crate::lowerer::{ByteCodeInstruction, ListElement, OverflowMode, StackFrame};
+use std::collections::BTreeSet;
 use std::fs::File;
 use std::io::Result;
 use std::io::Write;
@@ -6,13 +7,15 @@ use std::io::Write;
 pub struct FasmEmitter {
     labels: usize,
     out_file: File,
+    overflow_mode: OverflowMode,
 }
 
 impl FasmEmitter {
-    pub fn new(out_file: File) -> Self {
+    pub fn new(out_file: File, overflow_mode: OverflowMode) -> Self {
         FasmEmitter {
             labels: 0,
             out_file,
+            overflow_mode,
         }
     }
 
@@ -22,6 +25,10 @@ impl FasmEmitter {
 
         for (name, frame) in program {
             writeln!(self.out_file, "{}:", name)?;
+            match frame.max_stack_depth {
+                Some(depth) => writeln!(self.out_file, ";max stack depth: {}", depth)?,
+                None => writeln!(self.out_file, ";max stack depth: only known at runtime")?,
+            }
             //subtract from rsp the number of locals
 
             let mut num_locals = frame.max_locals;
@@ -70,7 +77,7 @@ impl FasmEmitter {
             }
         }
 
-        self.emit_prologue()?;
+        self.emit_prologue(program, constants)?;
         Ok(())
     }
 
@@ -88,7 +95,35 @@ impl FasmEmitter {
     fn emit_helper_functions(&mut self) -> Result<()> {
         self.emit_print_intln_function()?;
         self.emit_print_int_function()?;
+        self.emit_print_bool_function()?;
+        self.emit_print_newline_function()?;
+        self.emit_print_strln_function()?;
         self.emit_print_list_function()?;
+        self.emit_compare_list_function()?;
+        self.emit_runtime_error_function("overflow_error", "overflow_msg")?;
+        self.emit_runtime_error_function("index_error", "index_msg")?;
+        self.emit_runtime_error_function("division_error", "division_msg")?;
+        Ok(())
+    }
+
+    //Prints the message and exits, jumped to rather than called
+    fn emit_runtime_error_function(&mut self, name: &str, message: &str) -> Result<()> {
+        writeln!(self.out_file, "{}:", name)?;
+        //we can get here from anywhere, so realign the stack before calling into msvcrt
+        writeln!(self.out_file, "\tand rsp, -16")?;
+        writeln!(self.out_file, "\tsub rsp, 32; shadow space for Win-x64 ABI")?;
+        writeln!(self.out_file, "\tlea rcx, [{}]", message)?;
+        writeln!(self.out_file, "\tcall [printf]")?;
+        writeln!(self.out_file, "\tmov ecx, 1")?;
+        writeln!(self.out_file, "\tcall [ExitProcess]")?;
+        Ok(())
+    }
+
+    //x86 arithmetic wraps on its own, checking only needs a jump on the overflow flag
+    fn emit_overflow_check(&mut self) -> Result<()> {
+        if self.overflow_mode == OverflowMode::Checked {
+            writeln!(self.out_file, "\tjo overflow_error")?;
+        }
         Ok(())
     }
 
@@ -105,64 +140,234 @@ impl FasmEmitter {
 
     fn emit_print_int_function(&mut self) -> Result<()> {
         writeln!(self.out_file, "print_int:")?;
-        writeln!(self.out_file, "\tsub rsp, 32; shadow space for Win-x64 ABI")?;
+        writeln!(
+            self.out_file,
+            "\tsub rsp, 40; shadow space for Win-x64 ABI, keeping rsp aligned"
+        )?;
         writeln!(self.out_file, "\tmov rdx, rcx")?;
         writeln!(self.out_file, "\tlea rcx, [fmt_int]")?;
         writeln!(self.out_file, "\tcall [printf]")?;
-        writeln!(self.out_file, "\tadd rsp, 32; clean shadow space")?;
+        writeln!(self.out_file, "\tadd rsp, 40; clean shadow space")?;
+        writeln!(self.out_file, "\tret")?;
+        Ok(())
+    }
+
+    fn emit_print_bool_function(&mut self) -> Result<()> {
+        writeln!(self.out_file, "print_bool:")?;
+        writeln!(
+            self.out_file,
+            "\tsub rsp, 40; shadow space for Win-x64 ABI, keeping rsp aligned"
+        )?;
+        writeln!(self.out_file, "\tlea rdx, [false_str]")?;
+        writeln!(self.out_file, "\tlea rax, [true_str]")?;
+        writeln!(self.out_file, "\ttest rcx, rcx")?;
+        writeln!(self.out_file, "\tcmovnz rdx, rax")?;
+        writeln!(self.out_file, "\tlea rcx, [fmt_str]")?;
+        writeln!(self.out_file, "\tcall [printf]")?;
+        writeln!(self.out_file, "\tadd rsp, 40")?;
+        writeln!(self.out_file, "\tret")?;
+        writeln!(self.out_file)?;
+
+        writeln!(self.out_file, "print_boolln:")?;
+        writeln!(self.out_file, "\tsub rsp, 40")?;
+        writeln!(self.out_file, "\tcall print_bool")?;
+        writeln!(self.out_file, "\tcall print_newline")?;
+        writeln!(self.out_file, "\tadd rsp, 40")?;
+        writeln!(self.out_file, "\tret")?;
+        writeln!(self.out_file)?;
+        Ok(())
+    }
+
+    fn emit_print_strln_function(&mut self) -> Result<()> {
+        writeln!(self.out_file, "print_strln:")?;
+        writeln!(self.out_file, "\tsub rsp, 40")?;
+        writeln!(self.out_file, "\tmov rdx, rcx")?;
+        writeln!(self.out_file, "\tlea rcx, [fmt_str]")?;
+        writeln!(self.out_file, "\tcall [printf]")?;
+        writeln!(self.out_file, "\tcall print_newline")?;
+        writeln!(self.out_file, "\tadd rsp, 40")?;
+        writeln!(self.out_file, "\tret")?;
+        writeln!(self.out_file)?;
+        Ok(())
+    }
+
+    fn emit_print_newline_function(&mut self) -> Result<()> {
+        writeln!(self.out_file, "print_newline:")?;
+        writeln!(self.out_file, "\tsub rsp, 40")?;
+        writeln!(self.out_file, "\tlea rcx, [fmt_str]")?;
+        writeln!(self.out_file, "\tlea rdx, [newline]")?;
+        writeln!(self.out_file, "\tcall [printf]")?;
+        writeln!(self.out_file, "\tadd rsp, 40")?;
         writeln!(self.out_file, "\tret")?;
+        writeln!(self.out_file)?;
         Ok(())
     }
 
+    //Prints the list in rcx in the same format as the VM, rdx holds how many lists deep the innermost
+    // elements are and r8 is what those elements are, as a `ListElement`
     fn emit_print_list_function(&mut self) -> Result<()> {
         writeln!(self.out_file, "print_list:")?;
 
-        //push the pointer to the list into rsi
-        writeln!(self.out_file, "\tmov rsi, rcx")?;
+        //print_list recurses for nested lists so it has to preserve everything it uses
+        writeln!(self.out_file, "\tpush rsi")?;
+        writeln!(self.out_file, "\tpush rbx")?;
+        writeln!(self.out_file, "\tpush r12")?;
+        writeln!(self.out_file, "\tpush r13")?;
+        writeln!(self.out_file, "\tpush r14")?;
+        writeln!(self.out_file, "\tpush r15")?;
+        writeln!(
+            self.out_file,
+            "\tsub rsp, 40; shadow space for Win-x64 ABI, keeping rsp aligned"
+        )?;
+
+        writeln!(self.out_file, "\tmov rsi, rcx")?; //rsi holds the pointer to the list
+        writeln!(self.out_file, "\tmov r15, rdx")?; //r15 holds the depth
+        writeln!(self.out_file, "\tmov rbx, r8")?; //rbx holds what the elements are
 
         writeln!(self.out_file, "; print opening '['")?;
-        writeln!(self.out_file, "\tsub rsp, 32")?;
         writeln!(self.out_file, "\tlea rcx, [fmt_str]")?;
         writeln!(self.out_file, "\tlea rdx, [lbracket]")?;
         writeln!(self.out_file, "\tcall [printf]")?;
-        writeln!(self.out_file, "\tadd rsp, 32")?;
 
         writeln!(self.out_file, "; prepare loop")?;
-        //r12-r14 are non volatile
         writeln!(self.out_file, "\tmov r12, 0")?; //r12 holds the loop counter
         writeln!(self.out_file, "\tmov r13, [rsi]")?; //r13 holds the list length
         writeln!(self.out_file, "\tlea r14, [rsi + 8]")?; //r14 holds the base of values
+        writeln!(self.out_file, "\ttest r13, r13")?;
+        writeln!(self.out_file, "\tjz .done")?;
 
-        //loop
         writeln!(self.out_file, ".loop:")?;
-        //print element
         writeln!(self.out_file, "; print element")?;
-        //TODO: this will fail spectacularly for nested lists
         writeln!(self.out_file, "\tmov rcx, [r14 + r12*8]")?;
+        writeln!(self.out_file, "\tcmp r15, 1")?;
+        writeln!(self.out_file, "\tjg .nested")?;
+        writeln!(self.out_file, "\tcmp rbx, {}", ListElement::Bool as usize)?;
+        writeln!(self.out_file, "\tje .bool")?;
+        writeln!(
+            self.out_file,
+            "\tcmp rbx, {}",
+            ListElement::Function as usize
+        )?;
+        writeln!(self.out_file, "\tje .function")?;
         writeln!(self.out_file, "\tcall print_int")?;
+        writeln!(self.out_file, "\tjmp .next")?;
+        writeln!(self.out_file, ".bool:")?;
+        writeln!(self.out_file, "\tcall print_bool")?;
+        writeln!(self.out_file, "\tjmp .next")?;
+        writeln!(self.out_file, ".function:")?;
+        writeln!(self.out_file, "\tlea rcx, [fmt_str]")?;
+        writeln!(self.out_file, "\tlea rdx, [fn_str]")?;
+        writeln!(self.out_file, "\tcall [printf]")?;
+        writeln!(self.out_file, "\tjmp .next")?;
+        writeln!(self.out_file, ".nested:")?;
+        writeln!(self.out_file, "\tlea rdx, [r15 - 1]")?;
+        writeln!(self.out_file, "\tmov r8, rbx")?;
+        writeln!(self.out_file, "\tcall print_list")?;
 
+        writeln!(self.out_file, ".next:")?;
         writeln!(self.out_file, "; exit loop if last")?;
         writeln!(self.out_file, "\tinc r12")?;
         writeln!(self.out_file, "\tcmp r12, r13")?;
         writeln!(self.out_file, "\tjge .done")?;
 
         writeln!(self.out_file, "; print space")?;
-        writeln!(self.out_file, "\tsub rsp, 32")?;
         writeln!(self.out_file, "\tlea rcx, [fmt_str]")?;
         writeln!(self.out_file, "\tlea rdx, [space]")?;
         writeln!(self.out_file, "\tcall [printf]")?;
-        writeln!(self.out_file, "\tadd rsp, 32")?;
         writeln!(self.out_file, "\tjmp .loop")?;
 
         writeln!(self.out_file, ".done:")?;
         writeln!(self.out_file, "; print closing ']'")?;
-        writeln!(self.out_file, "\tsub rsp, 32")?;
         writeln!(self.out_file, "\tlea rcx, [fmt_str]")?;
         writeln!(self.out_file, "\tlea rdx, [rbracket]")?;
         writeln!(self.out_file, "\tcall [printf]")?;
-        writeln!(self.out_file, "\tadd rsp, 32")?;
 
-        writeln!(self.out_file, "\txor ecx, ecx")?;
+        writeln!(self.out_file, "\tadd rsp, 40")?;
+        writeln!(self.out_file, "\tpop r15")?;
+        writeln!(self.out_file, "\tpop r14")?;
+        writeln!(self.out_file, "\tpop r13")?;
+        writeln!(self.out_file, "\tpop r12")?;
+        writeln!(self.out_file, "\tpop rbx")?;
+        writeln!(self.out_file, "\tpop rsi")?;
+        writeln!(self.out_file, "\tret")?;
+        writeln!(self.out_file)?;
+
+        writeln!(self.out_file, "print_listln:")?;
+        writeln!(self.out_file, "\tsub rsp, 40")?;
+        writeln!(self.out_file, "\tcall print_list")?;
+        writeln!(self.out_file, "\tcall print_newline")?;
+        writeln!(self.out_file, "\tadd rsp, 40")?;
+        writeln!(self.out_file, "\tret")?;
+        writeln!(self.out_file)?;
+        Ok(())
+    }
+
+    fn emit_compare_list_function(&mut self) -> Result<()> {
+        writeln!(self.out_file, "compare_list:")?;
+
+        //compare_list recurses for nested lists so it has to preserve everything it uses
+        writeln!(self.out_file, "\tpush rsi")?;
+        writeln!(self.out_file, "\tpush rdi")?;
+        writeln!(self.out_file, "\tpush r12")?;
+        writeln!(self.out_file, "\tpush r13")?;
+        writeln!(self.out_file, "\tpush r14")?;
+        writeln!(self.out_file, "\tpush r15")?;
+
+        writeln!(self.out_file, "\tmov rsi, rcx")?; //rsi holds the lhs list
+        writeln!(self.out_file, "\tmov rdi, rdx")?; //rdi holds the rhs list
+        writeln!(self.out_file, "\tmov r15, r8")?; //r15 holds the depth
+        writeln!(self.out_file, "\tmov r12, [rsi]")?; //r12 holds the lhs length
+        writeln!(self.out_file, "\tmov r13, [rdi]")?; //r13 holds the rhs length
+        writeln!(self.out_file, "\tmov r14, 0")?; //r14 holds the loop counter
+
+        writeln!(self.out_file, ".loop:")?;
+        writeln!(
+            self.out_file,
+            "; lhs is a prefix of rhs, fall back to comparing lengths"
+        )?;
+        writeln!(self.out_file, "\tcmp r14, r12")?;
+        writeln!(self.out_file, "\tjge .lengths")?;
+        writeln!(self.out_file, "\tcmp r14, r13")?;
+        writeln!(self.out_file, "\tjge .greater")?;
+
+        writeln!(self.out_file, "; compare elements")?;
+        writeln!(self.out_file, "\tmov rcx, [rsi + r14*8 + 8]")?;
+        writeln!(self.out_file, "\tmov rdx, [rdi + r14*8 + 8]")?;
+        writeln!(self.out_file, "\tcmp r15, 1")?;
+        writeln!(self.out_file, "\tjg .nested")?;
+        writeln!(self.out_file, "\tcmp rcx, rdx")?;
+        writeln!(self.out_file, "\tjl .less")?;
+        writeln!(self.out_file, "\tjg .greater")?;
+        writeln!(self.out_file, "\tjmp .next")?;
+        writeln!(self.out_file, ".nested:")?;
+        writeln!(self.out_file, "\tlea r8, [r15 - 1]")?;
+        writeln!(self.out_file, "\tcall compare_list")?;
+        writeln!(self.out_file, "\ttest rax, rax")?;
+        writeln!(self.out_file, "\tjnz .done")?;
+        writeln!(self.out_file, ".next:")?;
+        writeln!(self.out_file, "\tinc r14")?;
+        writeln!(self.out_file, "\tjmp .loop")?;
+
+        writeln!(self.out_file, ".lengths:")?;
+        writeln!(self.out_file, "\tcmp r14, r13")?;
+        writeln!(self.out_file, "\tjl .less")?;
+        writeln!(self.out_file, "\tmov rax, 0")?;
+        writeln!(self.out_file, "\tjmp .done")?;
+
+        writeln!(self.out_file, ".less:")?;
+        writeln!(self.out_file, "\tmov rax, -1")?;
+        writeln!(self.out_file, "\tjmp .done")?;
+
+        writeln!(self.out_file, ".greater:")?;
+        writeln!(self.out_file, "\tmov rax, 1")?;
+
+        writeln!(self.out_file, ".done:")?;
+        writeln!(self.out_file, "\tpop r15")?;
+        writeln!(self.out_file, "\tpop r14")?;
+        writeln!(self.out_file, "\tpop r13")?;
+        writeln!(self.out_file, "\tpop r12")?;
+        writeln!(self.out_file, "\tpop rdi")?;
+        writeln!(self.out_file, "\tpop rsi")?;
         writeln!(self.out_file, "\tret")?;
         writeln!(self.out_file)?;
         Ok(())
@@ -173,6 +378,9 @@ impl FasmEmitter {
         match opcode {
             ByteCodeInstruction::Push(value) => writeln!(self.out_file, "\tpush {}", value),
             ByteCodeInstruction::NewList => {
+                let loop_label = self.next_label("new_list_loop");
+                let end_label = self.next_label("new_list_end");
+
                 //Get length in words and in bytes
                 writeln!(self.out_file, "\tpop r12")?; //r12 holds the list length
                 writeln!(self.out_file, "\tmov r13, r12")?;
@@ -194,13 +402,9 @@ impl FasmEmitter {
                 //set elements
                 //organise loop
                 writeln!(self.out_file, "\tmov rdx, 0")?;
-                writeln!(self.out_file, ".loop_{}:", self.labels)?;
-                let loop_label = self.labels;
-                self.labels += 1;
+                writeln!(self.out_file, "{}:", loop_label)?;
                 writeln!(self.out_file, "\tcmp rdx, r12")?;
-                writeln!(self.out_file, "\tjge .end_{}", self.labels)?;
-                let end_label = self.labels;
-                self.labels += 1;
+                writeln!(self.out_file, "\tjge {}", end_label)?;
 
                 //pop element i into rax
                 writeln!(self.out_file, "\tpop rax")?;
@@ -211,10 +415,9 @@ impl FasmEmitter {
                 //store element
                 writeln!(self.out_file, "\tmov qword [rbx + rdx*8], rax")?;
 
-                writeln!(self.out_file, "\tjmp .loop_{}", loop_label)?;
-                self.labels += 1;
+                writeln!(self.out_file, "\tjmp {}", loop_label)?;
 
-                writeln!(self.out_file, ".end_{}:", end_label)?;
+                writeln!(self.out_file, "{}:", end_label)?;
                 //push pointer onto the stack
                 writeln!(self.out_file, "\tpush rbx")
 
@@ -233,6 +436,7 @@ impl FasmEmitter {
                 writeln!(self.out_file, "\tpush rax")?;
                 writeln!(self.out_file, "\tpush rbx")
             }
+            //Must match the VM: a b c -- b c a
             ByteCodeInstruction::Rot => {
                 writeln!(self.out_file, "\tpop rax")?;
                 writeln!(self.out_file, "\tpop rbx")?;
@@ -247,37 +451,67 @@ impl FasmEmitter {
                 writeln!(self.out_file, "\tpush rax")?;
                 writeln!(self.out_file, "\tpush rbx")
             }
+            ByteCodeInstruction::Unrot => {
+                writeln!(self.out_file, "\tpop rax")?;
+                writeln!(self.out_file, "\tpop rbx")?;
+                writeln!(self.out_file, "\tpop rcx")?;
+                writeln!(self.out_file, "\tpush rax")?;
+                writeln!(self.out_file, "\tpush rcx")?;
+                writeln!(self.out_file, "\tpush rbx")
+            }
+            ByteCodeInstruction::TwoOver => {
+                writeln!(self.out_file, "\tpush qword [rsp + 24]")?;
+                writeln!(self.out_file, "\tpush qword [rsp + 24]")
+            }
+            ByteCodeInstruction::TwoSwap => {
+                writeln!(self.out_file, "\tpop rax")?;
+                writeln!(self.out_file, "\tpop rbx")?;
+                writeln!(self.out_file, "\tpop rcx")?;
+                writeln!(self.out_file, "\tpop rdx")?;
+                writeln!(self.out_file, "\tpush rbx")?;
+                writeln!(self.out_file, "\tpush rax")?;
+                writeln!(self.out_file, "\tpush rdx")?;
+                writeln!(self.out_file, "\tpush rcx")
+            }
             ByteCodeInstruction::Inc => {
                 writeln!(self.out_file, "\tpop rax")?;
                 writeln!(self.out_file, "\tinc rax")?;
+                self.emit_overflow_check()?;
                 writeln!(self.out_file, "\tpush rax")
             }
             ByteCodeInstruction::Dec => {
                 writeln!(self.out_file, "\tpop rax")?;
                 writeln!(self.out_file, "\tdec rax")?;
+                self.emit_overflow_check()?;
                 writeln!(self.out_file, "\tpush rax")
             }
             ByteCodeInstruction::Add => {
                 writeln!(self.out_file, "\tpop rax")?;
                 writeln!(self.out_file, "\tpop rbx")?;
                 writeln!(self.out_file, "\tadd rax, rbx")?;
+                self.emit_overflow_check()?;
                 writeln!(self.out_file, "\tpush rax")
             }
             ByteCodeInstruction::Sub => {
                 writeln!(self.out_file, "\tpop rax")?;
                 writeln!(self.out_file, "\tpop rbx")?;
                 writeln!(self.out_file, "\tsub rbx, rax")?;
+                self.emit_overflow_check()?;
                 writeln!(self.out_file, "\tpush rbx")
             }
             ByteCodeInstruction::Mul => {
                 writeln!(self.out_file, "\tpop rax")?;
                 writeln!(self.out_file, "\tpop rbx")?;
                 writeln!(self.out_file, "\timul rax, rbx")?;
+                self.emit_overflow_check()?;
                 writeln!(self.out_file, "\tpush rax")
             }
             ByteCodeInstruction::Div => {
                 writeln!(self.out_file, "\tpop rbx")?;
                 writeln!(self.out_file, "\tpop rax")?;
+                //idiv faults on a zero divisor, so it's caught first
+                writeln!(self.out_file, "\ttest rbx, rbx")?;
+                writeln!(self.out_file, "\tjz division_error")?;
                 writeln!(self.out_file, "\tcqo")?;
                 writeln!(self.out_file, "\tidiv rbx")?;
                 writeln!(self.out_file, "\tpush rdx")?;
@@ -286,6 +520,8 @@ impl FasmEmitter {
             ByteCodeInstruction::Mod => {
                 writeln!(self.out_file, "\tpop rbx")?;
                 writeln!(self.out_file, "\tpop rax")?;
+                writeln!(self.out_file, "\ttest rbx, rbx")?;
+                writeln!(self.out_file, "\tjz division_error")?;
                 writeln!(self.out_file, "\tcqo")?;
                 writeln!(self.out_file, "\tidiv rbx")?;
                 writeln!(self.out_file, "\tpush rax")?;
@@ -300,6 +536,15 @@ impl FasmEmitter {
                 writeln!(self.out_file, "\tcmove rcx, rdx")?;
                 writeln!(self.out_file, "\tpush rcx")
             }
+            ByteCodeInstruction::Not => {
+                //Anything other than 0 counts as true, so compare against 0 rather than flipping a bit
+                writeln!(self.out_file, "\tmov rcx, 0")?;
+                writeln!(self.out_file, "\tmov rdx, 1")?;
+                writeln!(self.out_file, "\tpop rax")?;
+                writeln!(self.out_file, "\ttest rax, rax")?;
+                writeln!(self.out_file, "\tcmovz rcx, rdx")?;
+                writeln!(self.out_file, "\tpush rcx")
+            }
             ByteCodeInstruction::Gt => {
                 writeln!(self.out_file, "\tmov rcx, 0")?;
                 writeln!(self.out_file, "\tmov rdx, 1")?;
@@ -336,22 +581,47 @@ impl FasmEmitter {
                 writeln!(self.out_file, "\tcmovle rcx, rdx")?;
                 writeln!(self.out_file, "\tpush rcx")
             }
+            ByteCodeInstruction::Compare => {
+                writeln!(self.out_file, "\tmov rcx, 0")?;
+                writeln!(self.out_file, "\tmov rdx, 1")?;
+                writeln!(self.out_file, "\tmov r8, -1")?;
+                writeln!(self.out_file, "\tpop rax")?;
+                writeln!(self.out_file, "\tpop rbx")?;
+                writeln!(self.out_file, "\tcmp rbx, rax")?;
+                writeln!(self.out_file, "\tcmovg rcx, rdx")?;
+                writeln!(self.out_file, "\tcmovl rcx, r8")?;
+                writeln!(self.out_file, "\tpush rcx")
+            }
+            ByteCodeInstruction::CompareList { depth } => {
+                writeln!(self.out_file, "\tpop rdx")?;
+                writeln!(self.out_file, "\tpop rcx")?;
+                writeln!(self.out_file, "\tmov r8, {}", depth)?;
+                writeln!(self.out_file, "\tcall compare_list")?;
+                writeln!(self.out_file, "\tpush rax")
+            }
             ByteCodeInstruction::Print => {
                 writeln!(self.out_file, "\tpop rcx")?;
                 writeln!(self.out_file, "\tcall print_intln")
             }
             ByteCodeInstruction::PrintBool => {
                 writeln!(self.out_file, "\tpop rcx")?;
-                writeln!(self.out_file, "\tcall print_boolln")?;
-                todo!("implement print_boolln")
+                writeln!(self.out_file, "\tcall print_boolln")
             }
-            ByteCodeInstruction::PrintList => {
+            ByteCodeInstruction::PrintList { depth, element } => {
                 writeln!(self.out_file, "\tpop rcx")?;
-                writeln!(self.out_file, "\tcall print_list")
+                writeln!(self.out_file, "\tmov rdx, {}", depth)?;
+                writeln!(self.out_file, "\tmov r8, {}", *element as usize)?;
+                writeln!(self.out_file, "\tcall print_listln")
+            }
+            ByteCodeInstruction::PrintFunction { index } => {
+                writeln!(self.out_file, "\tpop rax")?;
+                writeln!(self.out_file, "\tlea rcx, [signature_{}]", index)?;
+                writeln!(self.out_file, "\tcall print_strln")
             }
 
             ByteCodeInstruction::PushBlock { index } => {
-                writeln!(self.out_file, "\tlea rax, [block_{}]", index)?;
+                //A block captures nothing, so its list of just its code is made up front
+                writeln!(self.out_file, "\tlea rax, [closure_{}]", index)?;
                 writeln!(self.out_file, "\tpush rax")
             }
             ByteCodeInstruction::Load { index } => {
@@ -369,16 +639,33 @@ impl FasmEmitter {
             }
             ByteCodeInstruction::ListGet => {
                 writeln!(self.out_file, "\tpop rax")?; //index
-                writeln!(self.out_file, "\tinc rax")?; //index + 1
                 writeln!(self.out_file, "\tpop rbx")?; //list
-                writeln!(self.out_file, "\tmov rax, [rbx + rax*8]")?;
+                //unsigned comparison against the length, so negative indices are out of bounds too
+                writeln!(self.out_file, "\tcmp rax, [rbx]")?;
+                writeln!(self.out_file, "\tjae index_error")?;
+                writeln!(self.out_file, "\tmov rax, [rbx + rax*8 + 8]")?;
                 writeln!(self.out_file, "\tpush rax")
             }
             ByteCodeInstruction::Label(label) => writeln!(self.out_file, ".label_{}:", label),
             ByteCodeInstruction::CallDynamic => {
-                //Get pointer to function from the stack
+                let loop_label = self.next_label("captures_loop");
+                let end_label = self.next_label("captures_end");
+
+                //Get pointer to the function's list from the stack
                 writeln!(self.out_file, "\tpop rax")?;
-                writeln!(self.out_file, "\tcall rax")?;
+                writeln!(self.out_file, "\tmov rcx, [rax]")?;
+
+                //push the values it captured, which start after the code in element 0
+                writeln!(self.out_file, "\tmov rdx, 1")?;
+                writeln!(self.out_file, "{}:", loop_label)?;
+                writeln!(self.out_file, "\tcmp rdx, rcx")?;
+                writeln!(self.out_file, "\tjge {}", end_label)?;
+                writeln!(self.out_file, "\tpush qword [rax + rdx*8 + 8]")?;
+                writeln!(self.out_file, "\tinc rdx")?;
+                writeln!(self.out_file, "\tjmp {}", loop_label)?;
+                writeln!(self.out_file, "{}:", end_label)?;
+
+                writeln!(self.out_file, "\tcall qword [rax + 8]")?;
                 Ok(())
             }
             ByteCodeInstruction::CallStatic { index } => {
@@ -400,7 +687,21 @@ impl FasmEmitter {
         }
     }
 
-    fn emit_prologue(&mut self) -> Result<()> {
+    //Local labels for control flow the emitter adds itself, e.g. `.new_list_loop_3`.
+    // The counter is shared by every function and `name` is never "label", so these can't
+    // clash with each other or with the `.label_N` labels that come from the bytecode
+    fn next_label(&mut self, name: &str) -> String {
+        assert_ne!(name, "label", "`.label_N` is reserved for bytecode labels");
+        let label = format!(".{}_{}", name, self.labels);
+        self.labels += 1;
+        label
+    }
+
+    fn emit_prologue(
+        &mut self,
+        program: &[(String, StackFrame)],
+        constants: &[String],
+    ) -> Result<()> {
         writeln!(self.out_file)?;
         writeln!(self.out_file, "section '.data' data readable writeable")?;
         writeln!(self.out_file, "fmt_intln db \"%lld\",10, 0")?;
@@ -408,7 +709,47 @@ impl FasmEmitter {
         writeln!(self.out_file, "fmt_str   db \"%s\",      0")?;
         writeln!(self.out_file, "lbracket  db \"[\",       0")?;
         writeln!(self.out_file, "space     db \" \",       0")?;
-        writeln!(self.out_file, "rbracket  db \"]\",10,    0")?;
+        writeln!(self.out_file, "rbracket  db \"]\",       0")?;
+        writeln!(self.out_file, "newline   db 10,        0")?;
+        writeln!(self.out_file, "true_str  db \"true\",    0")?;
+        writeln!(self.out_file, "false_str db \"false\",   0")?;
+        writeln!(self.out_file, "fn_str    db \"fn\",      0")?;
+        writeln!(
+            self.out_file,
+            "overflow_msg db \"runtime error: integer overflow\",10, 0"
+        )?;
+        writeln!(
+            self.out_file,
+            "index_msg db \"runtime error: list index out of bounds\",10, 0"
+        )?;
+        writeln!(
+            self.out_file,
+            "division_msg db \"runtime error: division by zero\",10, 0"
+        )?;
+        //The signatures of the functions that are printed, which only exist as text in the constant pool
+        for (_, frame) in program {
+            for instruction in &frame.instructions {
+                if let ByteCodeInstruction::PrintFunction { index } = instruction {
+                    writeln!(
+                        self.out_file,
+                        "signature_{} db \"{}\", 0",
+                        index, constants[*index]
+                    )?;
+                }
+            }
+        }
+        //The lists of the blocks that are pushed: a length of one and their code
+        let blocks: BTreeSet<usize> = program
+            .iter()
+            .flat_map(|(_, frame)| &frame.instructions)
+            .filter_map(|instruction| match instruction {
+                ByteCodeInstruction::PushBlock { index } => Some(*index),
+                _ => None,
+            })
+            .collect();
+        for index in blocks {
+            writeln!(self.out_file, "closure_{} dq 1, block_{}", index, index)?;
+        }
         writeln!(self.out_file)?;
 
         writeln!(self.out_file, "section '.idata' import data readable")?;
@@ -0,0 +1,301 @@
+use crate::lexer::{Span, Token, TokenKind};
+use crate::parser::{Op, OpKind};
+
+const INDENT: &str = "    ";
+
+//Prints a parsed file back out in a consistent layout. The ops themselves come from their `Display` impls, but where
+// lines break is taken from the source: a block written across several lines gets its body on lines of its own,
+// indented one level, while one written on a single line stays on one line. Runs of blank lines are collapsed to one
+// and a line indented further than the one before it is kept as a continuation of it.
+// The tokens must have been lexed with their comments, which are put back before the op that followed them
+pub fn format(source: &str, tokens: &[Token], ops: &[Op]) -> String {
+    let mut printer = Printer {
+        source,
+        tokens,
+        comments: tokens
+            .iter()
+            .filter(|token| matches!(token.kind, TokenKind::Comment(_)))
+            .collect(),
+        next_comment: 0,
+        output: String::new(),
+        line_indent: 0,
+        last_end: 0,
+    };
+
+    printer.write_lines(ops, usize::MAX, 0);
+
+    let mut output = printer.output.trim_end().to_string();
+    if !output.is_empty() {
+        output.push('\n');
+    }
+    output
+}
+
+struct Printer<'a> {
+    source: &'a str,
+    tokens: &'a [Token],
+    comments: Vec<&'a Token>,
+    //The first comment that hasn't been printed yet
+    next_comment: usize,
+    output: String,
+    //How many levels the line being printed is indented
+    line_indent: usize,
+    //The end of the last op or comment printed, to see how many lines the source had between it and the next
+    last_end: usize,
+}
+
+impl Printer<'_> {
+    //Prints a sequence of ops keeping the line breaks between them, along with any comments before `end`
+    fn write_lines(&mut self, ops: &[Op], end: usize, depth: usize) {
+        //The column of the first line in the source, any line indented further is a continuation of the one before
+        let mut base_column = None;
+        let mut first = true;
+
+        for op in ops {
+            self.write_comments(op.span.offset, depth, &mut first);
+            self.separate(op.span.offset, depth, &mut base_column, first);
+            self.write_op(op);
+            first = false;
+        }
+        self.write_comments(end, depth, &mut first);
+    }
+
+    fn write_comments(&mut self, before: usize, depth: usize, first: &mut bool) {
+        while let Some(comment) = self.comments.get(self.next_comment)
+            && comment.span.offset < before
+        {
+            let comment = *comment;
+            self.next_comment += 1;
+
+            //Comments are indented with the ops around them rather than as continuations
+            self.separate(comment.span.offset, depth, &mut Some(usize::MAX), *first);
+            let TokenKind::Comment(text) = &comment.kind else {
+                unreachable!()
+            };
+            self.output.push_str(&format!("//{}", text.trim_end()));
+            self.last_end = end_of(comment.span);
+            *first = false;
+        }
+    }
+
+    //Puts a space, a line break or a blank line before the op or comment starting at `offset`, depending on what was
+    // between it and the last one in the source. The first op in a block always starts a new line
+    fn separate(
+        &mut self,
+        offset: usize,
+        depth: usize,
+        base_column: &mut Option<usize>,
+        first: bool,
+    ) {
+        let newlines = self.source[self.last_end..offset].matches('\n').count();
+
+        if !self.output.is_empty() {
+            if newlines == 0 && !first {
+                self.output.push(' ');
+                return;
+            }
+            self.newline();
+            if newlines > 1 && !first {
+                self.output.push('\n');
+            }
+        }
+
+        let line_start = self.source[..offset].rfind('\n').map_or(0, |i| i + 1);
+        let starts_line = self.source[line_start..offset].trim().is_empty();
+        let column = offset - line_start;
+
+        self.line_indent = match *base_column {
+            Some(base) if starts_line && column > base => depth + 1,
+            None if starts_line => {
+                *base_column = Some(column);
+                depth
+            }
+            _ => depth,
+        };
+        self.output.push_str(&INDENT.repeat(self.line_indent));
+    }
+
+    fn newline(&mut self) {
+        let trimmed = self.output.trim_end_matches(' ').len();
+        self.output.truncate(trimmed);
+        self.output.push('\n');
+    }
+
+    fn write_op(&mut self, op: &Op) {
+        match &op.kind {
+            OpKind::PushList(elements) => self.write_block("[", "]", elements, op.span, false),
+            OpKind::PushFunction(body) => self.write_block("(", ")", body, op.span, false),
+            OpKind::DefineFunction { identifier, body } => {
+                let TokenKind::Identifier(name) = &identifier.kind else {
+                    unreachable!()
+                };
+                self.output.push_str(&format!("fn {} ", name));
+                self.write_op_body(body, "(", ")", false);
+            }
+            OpKind::Binding { bindings, body } => {
+                self.output.push_str("let");
+                for (identifier, annotation) in bindings {
+                    let TokenKind::Identifier(name) = &identifier.kind else {
+                        unreachable!()
+                    };
+                    self.output.push_str(&format!(" {}", name));
+                    if let Some((type_kind, _)) = annotation {
+                        self.output.push_str(&format!(": {}", type_kind));
+                    }
+                }
+                self.output.push(' ');
+                self.write_op_body(body, "{", "}", true);
+            }
+            OpKind::If { body, else_body } => {
+                let open = self.token_after(op.span.offset, TokenKind::OpenCurly);
+                let close = self.closing(open);
+                self.output.push_str("if ");
+                self.write_block("{", "}", body, Span::from_to(open, close), true);
+
+                if let Some(else_body) = else_body {
+                    let open = self.token_after(end_of(close), TokenKind::OpenCurly);
+                    let close = self.closing(open);
+                    self.output.push_str(" else ");
+                    self.write_block("{", "}", else_body, Span::from_to(open, close), true);
+                }
+            }
+            OpKind::Match { arms, default } => self.write_match(op, arms, default),
+            //Kept as written so that separators like `1_000` aren't lost
+            OpKind::PushInt(_) => self
+                .output
+                .push_str(&self.source[op.span.offset..end_of(op.span)]),
+            _ => self.output.push_str(&op.to_string()),
+        }
+        self.last_end = end_of(op.span);
+    }
+
+    fn write_op_body(&mut self, body: &Op, open: &str, close: &str, padded: bool) {
+        let OpKind::PushFunction(ops) = &body.kind else {
+            unreachable!()
+        };
+        self.write_block(open, close, ops, body.span, padded);
+    }
+
+    //`span` runs from the opening bracket to the closing one. Curly blocks are `padded` with a space inside the
+    // brackets when they're on one line, e.g. `{ x print }`
+    fn write_block(&mut self, open: &str, close: &str, ops: &[Op], span: Span, padded: bool) {
+        let end = span.offset + span.length - close.len();
+
+        self.output.push_str(open);
+        if !self.source[span.offset..end].contains('\n') {
+            if padded {
+                self.output.push(' ');
+            }
+            for (i, op) in ops.iter().enumerate() {
+                if i > 0 {
+                    self.output.push(' ');
+                }
+                self.write_op(op);
+            }
+            if padded && !ops.is_empty() {
+                self.output.push(' ');
+            }
+            self.output.push_str(close);
+            return;
+        }
+
+        let indent = self.line_indent;
+        self.last_end = span.offset + open.len();
+        self.write_lines(ops, end, indent + 1);
+        self.close_block(close, indent);
+    }
+
+    fn close_block(&mut self, close: &str, indent: usize) {
+        self.newline();
+        self.line_indent = indent;
+        self.output.push_str(&INDENT.repeat(indent));
+        self.output.push_str(close);
+    }
+
+    fn write_match(&mut self, op: &Op, arms: &[(i64, Vec<Op>)], default: &[Op]) {
+        let open = self.token_after(op.span.offset, TokenKind::OpenParenthesis);
+        let close = end_of(op.span) - 1;
+
+        self.output.push_str("match (");
+        let multiline = self.source[open.offset..close].contains('\n');
+        let indent = self.line_indent;
+        let mut base_column = None;
+        let mut first = true;
+        self.last_end = end_of(open);
+
+        let labels = arms
+            .iter()
+            .map(|(value, _)| format!("{} ->", value))
+            .chain(std::iter::once("else".to_string()));
+        let bodies = arms
+            .iter()
+            .map(|(_, body)| body.as_slice())
+            .chain([default]);
+
+        for (label, body) in labels.zip(bodies) {
+            let start = self.tokens[self.token_index(self.last_end)].span.offset;
+            if multiline {
+                self.write_comments(start, indent + 1, &mut first);
+                self.separate(start, indent + 1, &mut base_column, first);
+            } else if !first {
+                self.output.push(' ');
+            }
+            first = false;
+
+            let arm_open = self.token_after(start, TokenKind::OpenParenthesis);
+            let arm_close = self.closing(arm_open);
+            self.output.push_str(&format!("{} ", label));
+            self.write_block("(", ")", body, Span::from_to(arm_open, arm_close), false);
+            self.last_end = end_of(arm_close);
+        }
+
+        if multiline {
+            self.write_comments(close, indent + 1, &mut first);
+            self.close_block(")", indent);
+        } else {
+            self.output.push(')');
+        }
+    }
+
+    //The index of the first token other than a comment at or after `offset`
+    fn token_index(&self, offset: usize) -> usize {
+        self.tokens
+            .iter()
+            .position(|token| {
+                token.span.offset >= offset && !matches!(token.kind, TokenKind::Comment(_))
+            })
+            .expect("a parsed op is followed by its closing token")
+    }
+
+    fn token_after(&self, offset: usize, kind: TokenKind) -> Span {
+        self.tokens[self.token_index(offset)..]
+            .iter()
+            .find(|token| token.kind == kind)
+            .map(|token| token.span)
+            .expect("the parser checked this token is there")
+    }
+
+    //The bracket that closes the one opened at `open`
+    fn closing(&self, open: Span) -> Span {
+        let mut depth = 0;
+        for token in &self.tokens[self.token_index(open.offset)..] {
+            match token.kind {
+                TokenKind::OpenParenthesis | TokenKind::OpenCurly | TokenKind::OpenSquare => {
+                    depth += 1
+                }
+                TokenKind::CloseParenthesis | TokenKind::CloseCurly | TokenKind::CloseSquare => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return token.span;
+                    }
+                }
+                _ => {}
+            }
+        }
+        unreachable!("the parser checked every bracket is closed")
+    }
+}
+
+fn end_of(span: Span) -> usize {
+    span.offset + span.length
+}
@@ -1,4 +1,7 @@
-use crate::diagnostic::Diagnostic;
+use crate::diagnostic::{Diagnostic, DiagnosticCode};
+
+//A comment starting with this asserts the types on the stack, e.g. `// effect: int [bool]`
+const STACK_EFFECT: &str = "// effect:";
 
 #[derive(PartialEq, Debug, Clone)]
 pub enum TokenKind {
@@ -21,31 +24,58 @@ pub enum TokenKind {
     Equals,
     Bang,
     Dot,
+    Colon,
+    CompareKeyword,
     AndKeyword,
     OrKeyword,
     OpenSquare,
     CloseSquare,
     DupKeyword,
     LenKeyword,
+    DigitsKeyword,
+    MaximumKeyword,
+    MinimumKeyword,
+    ClampKeyword,
     OverKeyword,
     PopKeyword,
     RotKeyword,
+    UnrotKeyword,
     SwapKeyword,
+    TwoOverKeyword,
+    TwoSwapKeyword,
     PrintKeyword,
     ConcatKeyword,
     PushKeyword,
+    ReplicateKeyword,
+    RotateKeyword,
     HeadKeyword,
     TailKeyword,
+    UnconsKeyword,
     DoKeyword,
+    CurryKeyword,
     FilterKeyword,
+    CountKeyword,
     FoldKeyword,
+    FoldRightKeyword,
     ForeachKeyword,
+    EachWithIndexKeyword,
+    EachWhileKeyword,
     MapKeyword,
     TripleQuestion,
+    DotS,
     FnKeyword,
     IfKeyword,
     ElseKeyword,
+    MatchKeyword,
+    Arrow,
+    WhenKeyword,
+    UnlessKeyword,
     LetKeyword,
+    ImportKeyword,
+    //The tokens of a `// effect:` comment, up to the end of its line
+    StackEffect(Vec<Token>),
+    //The text of a comment after the `//`, only lexed for tools that need to keep them such as the formatter
+    Comment(String),
     Error(String),
 }
 
@@ -70,9 +100,34 @@ pub struct Token {
     pub span: Span,
 }
 
+//How wide the integers a program is written for are, which decides how large a literal can be. Values are still
+// 64 bit once the program runs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IntWidth {
+    I64,
+    I32,
+}
+
+impl IntWidth {
+    pub fn max(self) -> i64 {
+        match self {
+            IntWidth::I64 => i64::MAX,
+            IntWidth::I32 => i32::MAX as i64,
+        }
+    }
+}
+
 pub struct Lexer {
     cursor: usize,
     pub diagnostics: Vec<Diagnostic>,
+    keep_comments: bool,
+    int_width: IntWidth,
+}
+
+impl Default for Lexer {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Lexer {
@@ -80,9 +135,31 @@ impl Lexer {
         Lexer {
             cursor: 0,
             diagnostics: Vec::new(),
+            keep_comments: false,
+            int_width: IntWidth::I64,
         }
     }
 
+    //Lexes comments as tokens rather than skipping them
+    pub fn with_comments() -> Lexer {
+        Lexer {
+            keep_comments: true,
+            ..Lexer::new()
+        }
+    }
+
+    //Rejects int literals that don't fit in the given width
+    pub fn with_int_width(self, int_width: IntWidth) -> Lexer {
+        Lexer { int_width, ..self }
+    }
+
+    //Lexes the input starting at the given offset rather than the beginning, so that several files can share
+    // one source and spans from different files never overlap
+    pub fn lex_from(&mut self, input: &str, offset: usize) -> Vec<Token> {
+        self.cursor = offset;
+        self.lex(input)
+    }
+
     pub fn lex(&mut self, input: &str) -> Vec<Token> {
         let mut tokens: Vec<Token> = vec![];
         while let Some(token) = self.next(input) {
@@ -92,13 +169,22 @@ impl Lexer {
     }
 
     fn next(&mut self, input: &str) -> Option<Token> {
-        self.skip_whitespace_and_comments(input);
+        if self.keep_comments {
+            self.skip_single_whitespace(input);
+        } else {
+            self.skip_whitespace_and_comments(input);
+        }
 
         let token = match self.peek(input) {
             Some(c) => match c {
                 '+' => self.lex_token(c, TokenKind::Plus),
-                '-' => self.lex_token(c, TokenKind::Minus),
+                '-' if input[self.cursor..].starts_with("-rot") => self.lex_keyword(input),
+                '-' => self.lex_multichar_token(input, c, '>', TokenKind::Arrow, TokenKind::Minus),
                 '*' => self.lex_token(c, TokenKind::Star),
+                '/' if input[self.cursor..].starts_with(STACK_EFFECT) => {
+                    self.lex_stack_effect(input)
+                }
+                '/' if input[self.cursor..].starts_with("//") => self.lex_comment(input),
                 '/' => self.lex_token(c, TokenKind::Slash),
                 '%' => self.lex_token(c, TokenKind::Percent),
                 '(' => self.lex_token(c, TokenKind::OpenParenthesis),
@@ -121,10 +207,21 @@ impl Lexer {
                 ),
                 '=' => self.lex_token(c, TokenKind::Equals),
                 '!' => self.lex_token(c, TokenKind::Bang),
+                '.' if input[self.cursor..].starts_with(".s")
+                    && !input[self.cursor + 2..]
+                        .starts_with(|c: char| c.is_alphanumeric() || c == '_' || c == '?') =>
+                {
+                    self.lex_keyword(input)
+                }
                 '.' => self.lex_token(c, TokenKind::Dot),
+                ':' => self.lex_token(c, TokenKind::Colon),
                 '[' => self.lex_token(c, TokenKind::OpenSquare),
                 ']' => self.lex_token(c, TokenKind::CloseSquare),
                 x if x.is_ascii_digit() => self.lex_number(input),
+                //`_1` would otherwise be an identifier, which hides that it's a misplaced underscore
+                '_' if input[self.cursor + 1..].starts_with(|c: char| c.is_ascii_digit()) => {
+                    self.lex_number(input)
+                }
                 x if x.is_alphabetic() || x == '_' || x == '?' => self.lex_keyword(input),
                 _ => {
                     let error = Token {
@@ -136,13 +233,14 @@ impl Lexer {
                     };
 
                     self.diagnostics.push(Diagnostic::report_error(
+                        DiagnosticCode::UnexpectedCharacter,
                         format!("Unexpected character `{}`", c),
                         Span {
                             offset: self.cursor,
                             length: c.len_utf8(),
                         },
                     ));
-                    self.cursor += 1;
+                    self.cursor += c.len_utf8();
 
                     return Some(error);
                 }
@@ -161,27 +259,28 @@ impl Lexer {
         if_match: TokenKind,
         if_not_match: TokenKind,
     ) -> Token {
-        if self.cursor < input.len() {
-            self.cursor += 1;
-            return match self.peek(input) {
-                Some(c) if c == next => {
-                    let token = Token {
-                        kind: if_match,
-                        span: Span {
-                            offset: self.cursor,
-                            length: 2,
-                        },
-                    };
-                    self.cursor += 1;
-                    token
-                }
-                _ => {
-                    self.cursor -= 1; //backpedal
-                    self.lex_token(c, if_not_match)
+        let offset = self.cursor;
+        self.cursor += c.len_utf8();
+
+        match self.peek(input) {
+            Some(n) if n == next => {
+                self.cursor += n.len_utf8();
+                Token {
+                    kind: if_match,
+                    span: Span {
+                        offset,
+                        length: self.cursor - offset,
+                    },
                 }
-            };
+            }
+            _ => Token {
+                kind: if_not_match,
+                span: Span {
+                    offset,
+                    length: c.len_utf8(),
+                },
+            },
         }
-        self.lex_token(c, if_not_match)
     }
 
     fn lex_token(&mut self, c: char, kind: TokenKind) -> Token {
@@ -192,12 +291,13 @@ impl Lexer {
                 length: c.len_utf8(),
             },
         };
-        self.cursor += 1;
+        self.cursor += c.len_utf8();
         token
     }
 
+    //The cursor is a byte offset into the input, so peeking doesn't need to walk the whole string
     fn peek(&mut self, input: &str) -> Option<char> {
-        input.chars().nth(self.cursor)
+        input[self.cursor..].chars().next()
     }
 
     fn skip_whitespace_and_comments(&mut self, input: &str) {
@@ -222,19 +322,54 @@ impl Lexer {
     }
 
     fn skip_comment(&mut self, input: &str) {
-        if let Some('/') = self.peek(input) {
-            self.cursor += 1;
-            if let Some('/') = self.peek(input) {
-                self.cursor += 1;
-                while let Some(c) = self.peek(input) {
-                    self.cursor += 1;
-                    if c == '\n' {
-                        break;
-                    }
-                }
-            } else {
-                self.cursor -= 1; //backpedal
-            }
+        if input[self.cursor..].starts_with(STACK_EFFECT) {
+            return;
+        }
+        if input[self.cursor..].starts_with("//") {
+            self.cursor = self.end_of_line(input, self.cursor);
+        }
+    }
+
+    //Where the line containing `from` ends, before its `\n` or `\r\n`, so a comment never takes part of the line
+    // ending with it whichever kind the file uses. The line ending is left to be skipped as whitespace
+    fn end_of_line(&self, input: &str, from: usize) -> usize {
+        input[from..]
+            .find(['\r', '\n'])
+            .map_or(input.len(), |index| from + index)
+    }
+
+    //The rest of the line is lexed as usual and handed to the parser as a single token, so it can't be mixed up with the ops around it
+    fn lex_stack_effect(&mut self, input: &str) -> Token {
+        let offset = self.cursor;
+        let start = offset + STACK_EFFECT.len();
+        let end = self.end_of_line(input, start);
+
+        let mut lexer = Lexer::new();
+        let tokens = lexer.lex_from(&input[..end], start);
+        self.diagnostics.extend(lexer.diagnostics);
+        self.cursor = end;
+
+        Token {
+            kind: TokenKind::StackEffect(tokens),
+            span: Span {
+                offset,
+                length: end - offset,
+            },
+        }
+    }
+
+    //Only reached when keeping comments, otherwise they've already been skipped
+    fn lex_comment(&mut self, input: &str) -> Token {
+        let offset = self.cursor;
+        let end = self.end_of_line(input, offset);
+        self.cursor = end;
+
+        Token {
+            kind: TokenKind::Comment(input[offset + 2..end].to_string()),
+            span: Span {
+                offset,
+                length: end - offset,
+            },
         }
     }
 
@@ -242,28 +377,89 @@ impl Lexer {
         let offset = self.cursor;
 
         while let Some(c) = self.peek(input) {
-            if c.is_ascii_digit() {
+            if c.is_ascii_digit() || c == '_' {
                 self.cursor += 1;
             } else {
                 break;
             }
         }
 
-        let number = &input[offset..self.cursor].parse::<i64>().unwrap();
-        Token {
-            kind: TokenKind::IntLiteral(*number),
-            span: Span {
-                offset,
-                length: self.cursor - offset,
+        //Words like `2swap` start with a digit, so they're handed back to the keyword lexer
+        if let Some(c) = self.peek(input)
+            && c.is_alphabetic()
+        {
+            self.cursor = offset;
+            return self.lex_keyword(input);
+        }
+
+        let literal = &input[offset..self.cursor];
+        let span = Span {
+            offset,
+            length: self.cursor - offset,
+        };
+
+        //Underscores are only allowed between digits, e.g. `1_000_000`
+        if literal.starts_with('_') || literal.ends_with('_') || literal.contains("__") {
+            self.diagnostics.push(Diagnostic::report_error(
+                DiagnosticCode::MalformedNumber,
+                format!(
+                    "Malformed number `{}`, underscores must separate digits",
+                    literal
+                ),
+                span,
+            ));
+            return Token {
+                kind: TokenKind::Error(literal.to_string()),
+                span,
+            };
+        }
+
+        match literal.replace('_', "").parse::<i64>() {
+            Ok(number) if number <= self.int_width.max() => Token {
+                kind: TokenKind::IntLiteral(number),
+                span,
             },
+            Ok(_) => {
+                self.diagnostics.push(Diagnostic::report_error(
+                    DiagnosticCode::MalformedNumber,
+                    format!("Number `{}` is too large for a 32 bit int", literal),
+                    span,
+                ));
+                Token {
+                    kind: TokenKind::Error(literal.to_string()),
+                    span,
+                }
+            }
+            Err(_) => {
+                self.diagnostics.push(Diagnostic::report_error(
+                    DiagnosticCode::MalformedNumber,
+                    format!("Number `{}` is too large", literal),
+                    span,
+                ));
+                Token {
+                    kind: TokenKind::Error(literal.to_string()),
+                    span,
+                }
+            }
         }
     }
 
     fn lex_keyword(&mut self, input: &str) -> Token {
         let offset = self.cursor;
 
+        //Only reachable for `-rot`, the other words never start with a `-`. `.s` gets its leading dot from the loop
+        // below, as it's followed by a letter
+        if let Some('-') = self.peek(input) {
+            self.cursor += 1;
+        }
+
         while let Some(c) = self.peek(input) {
             if c.is_alphanumeric() || c == '_' || c == '?' {
+                self.cursor += c.len_utf8();
+            } else if c == '.'
+                && input[self.cursor + 1..].starts_with(|c: char| c.is_alphabetic() || c == '_')
+            {
+                //A dot between two words qualifies a function with the module it's from, e.g. `lib.helper`
                 self.cursor += 1;
             } else {
                 break;
@@ -290,10 +486,22 @@ impl Lexer {
                 kind: TokenKind::RotKeyword,
                 span: Span { offset, length },
             },
+            "-rot" => Token {
+                kind: TokenKind::UnrotKeyword,
+                span: Span { offset, length },
+            },
             "swap" => Token {
                 kind: TokenKind::SwapKeyword,
                 span: Span { offset, length },
             },
+            "2over" => Token {
+                kind: TokenKind::TwoOverKeyword,
+                span: Span { offset, length },
+            },
+            "2swap" => Token {
+                kind: TokenKind::TwoSwapKeyword,
+                span: Span { offset, length },
+            },
             "print" => Token {
                 kind: TokenKind::PrintKeyword,
                 span: Span { offset, length },
@@ -306,6 +514,10 @@ impl Lexer {
                 kind: TokenKind::BoolLiteral(false),
                 span: Span { offset, length },
             },
+            "compare" => Token {
+                kind: TokenKind::CompareKeyword,
+                span: Span { offset, length },
+            },
             "and" => Token {
                 kind: TokenKind::AndKeyword,
                 span: Span { offset, length },
@@ -318,6 +530,14 @@ impl Lexer {
                 kind: TokenKind::ConcatKeyword,
                 span: Span { offset, length },
             },
+            "replicate" => Token {
+                kind: TokenKind::ReplicateKeyword,
+                span: Span { offset, length },
+            },
+            "rotate" => Token {
+                kind: TokenKind::RotateKeyword,
+                span: Span { offset, length },
+            },
             "push" => Token {
                 kind: TokenKind::PushKeyword,
                 span: Span { offset, length },
@@ -330,26 +550,66 @@ impl Lexer {
                 kind: TokenKind::TailKeyword,
                 span: Span { offset, length },
             },
+            "uncons" => Token {
+                kind: TokenKind::UnconsKeyword,
+                span: Span { offset, length },
+            },
             "do" => Token {
                 kind: TokenKind::DoKeyword,
                 span: Span { offset, length },
             },
+            "curry" => Token {
+                kind: TokenKind::CurryKeyword,
+                span: Span { offset, length },
+            },
             "filter" => Token {
                 kind: TokenKind::FilterKeyword,
                 span: Span { offset, length },
             },
+            "count" => Token {
+                kind: TokenKind::CountKeyword,
+                span: Span { offset, length },
+            },
             "fold" => Token {
                 kind: TokenKind::FoldKeyword,
                 span: Span { offset, length },
             },
+            "foldr" => Token {
+                kind: TokenKind::FoldRightKeyword,
+                span: Span { offset, length },
+            },
             "foreach" => Token {
                 kind: TokenKind::ForeachKeyword,
                 span: Span { offset, length },
             },
+            "eachWithIndex" => Token {
+                kind: TokenKind::EachWithIndexKeyword,
+                span: Span { offset, length },
+            },
+            "eachWhile" => Token {
+                kind: TokenKind::EachWhileKeyword,
+                span: Span { offset, length },
+            },
             "len" => Token {
                 kind: TokenKind::LenKeyword,
                 span: Span { offset, length },
             },
+            "digits" => Token {
+                kind: TokenKind::DigitsKeyword,
+                span: Span { offset, length },
+            },
+            "maximum" => Token {
+                kind: TokenKind::MaximumKeyword,
+                span: Span { offset, length },
+            },
+            "minimum" => Token {
+                kind: TokenKind::MinimumKeyword,
+                span: Span { offset, length },
+            },
+            "clamp" => Token {
+                kind: TokenKind::ClampKeyword,
+                span: Span { offset, length },
+            },
             "map" => Token {
                 kind: TokenKind::MapKeyword,
                 span: Span { offset, length },
@@ -358,6 +618,10 @@ impl Lexer {
                 kind: TokenKind::TripleQuestion,
                 span: Span { offset, length },
             },
+            ".s" => Token {
+                kind: TokenKind::DotS,
+                span: Span { offset, length },
+            },
             "fn" => Token {
                 kind: TokenKind::FnKeyword,
                 span: Span { offset, length },
@@ -370,10 +634,26 @@ impl Lexer {
                 kind: TokenKind::ElseKeyword,
                 span: Span { offset, length },
             },
+            "match" => Token {
+                kind: TokenKind::MatchKeyword,
+                span: Span { offset, length },
+            },
+            "when" => Token {
+                kind: TokenKind::WhenKeyword,
+                span: Span { offset, length },
+            },
+            "unless" => Token {
+                kind: TokenKind::UnlessKeyword,
+                span: Span { offset, length },
+            },
             "let" => Token {
                 kind: TokenKind::LetKeyword,
                 span: Span { offset, length },
             },
+            "import" => Token {
+                kind: TokenKind::ImportKeyword,
+                span: Span { offset, length },
+            },
             &_ => Token {
                 kind: TokenKind::Identifier(keyword.to_string()),
                 span: Span { offset, length },
@@ -381,3 +661,106 @@ impl Lexer {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lex_with(int_width: IntWidth, input: &str) -> (Vec<Token>, Vec<Diagnostic>) {
+        let mut lexer = Lexer::new().with_int_width(int_width);
+        let tokens = lexer.lex(input);
+        (tokens, lexer.diagnostics)
+    }
+
+    #[test]
+    fn largest_32_bit_literal_is_accepted() {
+        let (tokens, diagnostics) = lex_with(IntWidth::I32, "2_147_483_647");
+        assert!(diagnostics.is_empty());
+        assert_eq!(tokens[0].kind, TokenKind::IntLiteral(2_147_483_647));
+    }
+
+    #[test]
+    fn literal_too_large_for_32_bits_is_an_error() {
+        let (tokens, diagnostics) = lex_with(IntWidth::I32, "2_147_483_648");
+        assert_eq!(diagnostics.len(), 1);
+        assert!(
+            format!("{:?}", diagnostics[0])
+                .contains("Number `2_147_483_648` is too large for a 32 bit int")
+        );
+        assert_eq!(
+            tokens[0].kind,
+            TokenKind::Error("2_147_483_648".to_string())
+        );
+    }
+
+    #[test]
+    fn literal_too_large_for_32_bits_is_fine_with_64() {
+        let (tokens, diagnostics) = lex_with(IntWidth::I64, "2_147_483_648");
+        assert!(diagnostics.is_empty());
+        assert_eq!(tokens[0].kind, TokenKind::IntLiteral(2_147_483_648));
+    }
+
+    #[test]
+    fn underscores_between_digits_are_ignored() {
+        let (tokens, diagnostics) = lex_with(IntWidth::I64, "1_000_000");
+        assert!(diagnostics.is_empty());
+        assert_eq!(tokens[0].kind, TokenKind::IntLiteral(1_000_000));
+    }
+
+    #[test]
+    fn misplaced_underscores_are_malformed_numbers() {
+        for literal in ["1__0", "1_", "_1"] {
+            let (tokens, diagnostics) = lex_with(IntWidth::I64, literal);
+            assert_eq!(diagnostics.len(), 1, "{}", literal);
+            assert_eq!(diagnostics[0].code(), DiagnosticCode::MalformedNumber);
+            assert_eq!(tokens[0].kind, TokenKind::Error(literal.to_string()));
+        }
+    }
+
+    #[test]
+    fn identifiers_can_start_with_an_underscore() {
+        let (tokens, diagnostics) = lex_with(IntWidth::I64, "_a1");
+        assert!(diagnostics.is_empty());
+        assert_eq!(tokens[0].kind, TokenKind::Identifier("_a1".to_string()));
+    }
+
+    #[test]
+    fn comments_are_tokens_when_kept() {
+        let mut lexer = Lexer::with_comments();
+        let tokens = lexer.lex("1 // hi\n 2");
+        assert!(lexer.diagnostics.is_empty());
+        let kinds: Vec<TokenKind> = tokens.into_iter().map(|token| token.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                TokenKind::IntLiteral(1),
+                TokenKind::Comment(" hi".to_string()),
+                TokenKind::IntLiteral(2)
+            ]
+        );
+
+        let (tokens, _) = lex_with(IntWidth::I64, "1 // hi\n 2");
+        assert_eq!(tokens.len(), 2);
+    }
+
+    #[test]
+    fn comments_end_at_either_line_ending() {
+        for (input, second_offset) in [("1 // hi\n2", 8), ("1 // hi\r\n2", 9)] {
+            let mut lexer = Lexer::with_comments();
+            let tokens = lexer.lex(input);
+            assert!(lexer.diagnostics.is_empty());
+            assert_eq!(tokens.len(), 3, "{:?}", input);
+            assert_eq!(tokens[1].kind, TokenKind::Comment(" hi".to_string()));
+            assert_eq!(
+                tokens[2],
+                Token {
+                    kind: TokenKind::IntLiteral(2),
+                    span: Span {
+                        offset: second_offset,
+                        length: 1
+                    }
+                }
+            );
+        }
+    }
+}
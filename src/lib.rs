@@ -0,0 +1,218 @@
+//The compiler as a library, so it can be embedded in other programs. The `do` binary is a command line interface over
+// these modules, which adds the REPL and emits assembly
+use crate::bytecode_interpreter::{BytecodeInterpreter, RuntimeError};
+use crate::diagnostic::{Diagnostic, DiagnosticCode, SourceMap};
+use crate::lexer::{IntWidth, Lexer, Span};
+use crate::lowerer::{Lowerer, OverflowMode, StackFrame};
+use crate::parser::{Op, Parser};
+use crate::timings::Timings;
+use crate::typechecker::{TypeChecker, TypedOp};
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+pub mod bytecode_interpreter;
+pub mod color;
+pub mod diagnostic;
+pub mod emitter;
+pub mod formatter;
+pub mod lexer;
+mod loader;
+pub mod lowerer;
+pub mod optimizer;
+pub mod parser;
+pub mod timings;
+pub mod typechecker;
+pub mod verifier;
+
+//A compiled program, ready to be interpreted or emitted
+pub struct Program {
+    pub bytecode: Vec<(String, StackFrame)>,
+    //The function names the bytecode refers to by index
+    pub constants: Vec<String>,
+    //Anything the type checker warned about, which doesn't stop the program from compiling
+    pub warnings: Vec<Diagnostic>,
+}
+
+#[derive(Clone, Copy)]
+pub struct CompileOptions {
+    pub optimize: bool,
+    //Applies to every file, so an imported file can't use a literal the one importing it couldn't
+    pub int_width: IntWidth,
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        CompileOptions {
+            optimize: false,
+            int_width: IntWidth::I64,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CompileError {
+    //The file given to compile couldn't be read
+    Io {
+        path: PathBuf,
+        error: io::Error,
+    },
+    //The first phase to report an error, e.g. "Lexing", and everything reported up to then, warnings included
+    Diagnostics {
+        phase: &'static str,
+        diagnostics: Vec<Diagnostic>,
+    },
+}
+
+impl Display for CompileError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CompileError::Io { path, error } => {
+                write!(
+                    f,
+                    "Failed to read input file `{}`: {}",
+                    path.display(),
+                    error
+                )
+            }
+            CompileError::Diagnostics { phase, .. } => write!(f, "{} failed", phase),
+        }
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+#[derive(Debug)]
+pub enum RunError {
+    Compile(Vec<Diagnostic>),
+    Runtime(RuntimeError),
+}
+
+impl Display for RunError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RunError::Compile(diagnostics) => {
+                write!(f, "compiling failed with {} error(s)", diagnostics.len())
+            }
+            RunError::Runtime(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for RunError {}
+
+//Lowers a single file to bytecode. There's no file to resolve imports against, so any `import` is reported as
+// misplaced
+pub fn compile(source: &str) -> Result<Program, Vec<Diagnostic>> {
+    let mut lexer = Lexer::new();
+    let tokens = lexer.lex(source);
+    if !lexer.diagnostics.is_empty() {
+        return Err(lexer.diagnostics);
+    }
+
+    let mut parser = Parser::new();
+    let ops = parser.parse(&tokens);
+    if !parser.diagnostics.is_empty() {
+        return Err(parser.diagnostics);
+    }
+
+    compile_ops(&ops, &CompileOptions::default(), &mut Timings::new()).map_err(
+        |error| match error {
+            CompileError::Diagnostics { diagnostics, .. } => diagnostics,
+            CompileError::Io { .. } => unreachable!("nothing is read from disk"),
+        },
+    )
+}
+
+//Compiles the file at `path` along with everything it imports. Each file is added to `sources` as it's loaded, so
+// the diagnostics that come back can be displayed against them
+pub fn compile_file(
+    path: &Path,
+    options: &CompileOptions,
+    sources: &mut SourceMap,
+    timings: &mut Timings,
+) -> Result<Program, CompileError> {
+    let ops = loader::load_program(path, options.int_width, sources, timings)?;
+    compile_ops(&ops, options, timings)
+}
+
+//Stops after type checking, so errors can be found without running or compiling anything. Returns the warnings
+pub fn check_file(
+    path: &Path,
+    options: &CompileOptions,
+    sources: &mut SourceMap,
+    timings: &mut Timings,
+) -> Result<Vec<Diagnostic>, CompileError> {
+    let ops = loader::load_program(path, options.int_width, sources, timings)?;
+    let (_, warnings) = type_check(&ops, timings)?;
+    Ok(warnings)
+}
+
+//Compiles a single file and interprets it, wrapping on integer overflow. Anything it prints is written to `out`
+pub fn run(source: &str, out: &mut dyn Write) -> Result<(), RunError> {
+    let program = compile(source).map_err(RunError::Compile)?;
+
+    let mut interpreter = BytecodeInterpreter::new(OverflowMode::Wrapping);
+    interpreter
+        .interpret(&program.bytecode, &program.constants, out)
+        .map_err(RunError::Runtime)
+}
+
+fn type_check(
+    ops: &[Op],
+    timings: &mut Timings,
+) -> Result<(Vec<TypedOp>, Vec<Diagnostic>), CompileError> {
+    let mut type_checker = TypeChecker::new(true);
+    let typed_ops = timings.time("type checking", || type_checker.type_check(ops));
+
+    //Warnings are kept but don't stop the program
+    if type_checker.diagnostics.iter().any(Diagnostic::is_error) {
+        return Err(CompileError::Diagnostics {
+            phase: "Type checking",
+            diagnostics: type_checker.diagnostics,
+        });
+    }
+    Ok((typed_ops, type_checker.diagnostics))
+}
+
+//Everything after parsing, shared by compiling a single source and a file with imports
+fn compile_ops(
+    ops: &[Op],
+    options: &CompileOptions,
+    timings: &mut Timings,
+) -> Result<Program, CompileError> {
+    let (typed_ops, warnings) = type_check(ops, timings)?;
+
+    let mut lowerer = Lowerer::new();
+    let mut bytecode = timings.time("lowering", || lowerer.lower(&typed_ops));
+
+    if options.optimize {
+        timings.time("optimizing", || {
+            optimizer::optimize(&mut bytecode, &lowerer.constant_pool)
+        });
+    }
+
+    //Only a bug in the lowerer or optimizer gets this far, so there's nothing in the source to point at
+    timings
+        .time("verifying", || {
+            verifier::verify(&bytecode, &lowerer.constant_pool)
+        })
+        .map_err(|error| CompileError::Diagnostics {
+            phase: "Verifying",
+            diagnostics: vec![Diagnostic::report_error(
+                DiagnosticCode::MalformedBytecode,
+                error.to_string(),
+                Span {
+                    offset: 0,
+                    length: 0,
+                },
+            )],
+        })?;
+
+    Ok(Program {
+        bytecode,
+        constants: lowerer.constant_pool,
+        warnings,
+    })
+}
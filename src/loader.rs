@@ -0,0 +1,246 @@
+use crate::CompileError;
+use crate::diagnostic::{Diagnostic, DiagnosticCode, SourceMap};
+use crate::lexer::{IntWidth, Lexer, Span, Token, TokenKind};
+use crate::parser::{Op, OpKind, Parser};
+use crate::timings::Timings;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+//Loads the file at `path` and everything it imports, adding each to `sources` so diagnostics can point into them
+pub(crate) fn load_program(
+    path: &Path,
+    int_width: IntWidth,
+    sources: &mut SourceMap,
+    timings: &mut Timings,
+) -> Result<Vec<Op>, CompileError> {
+    let mut loader = Loader {
+        sources,
+        int_width,
+        importing: Vec::new(),
+        loaded: HashSet::new(),
+        modules: HashMap::new(),
+        definitions: HashMap::new(),
+        diagnostics: Vec::new(),
+        failed_phase: None,
+        timings,
+    };
+    let ops = loader.load(path, None);
+
+    if let Some(phase) = loader.failed_phase {
+        return Err(CompileError::Diagnostics {
+            phase,
+            diagnostics: loader.diagnostics,
+        });
+    }
+    ops
+}
+
+//Lexes and parses a file along with everything it imports, in the order they need to be type checked
+struct Loader<'a> {
+    sources: &'a mut SourceMap,
+    //Applies to every file, so an imported file can't use a literal the one importing it couldn't
+    int_width: IntWidth,
+    //The chain of files currently being imported, to detect cycles
+    importing: Vec<PathBuf>,
+    //Files that have already been spliced in, so importing them again is a no-op
+    loaded: HashSet<PathBuf>,
+    //The functions each loaded file defines, so `module.name` can be checked against them
+    modules: HashMap<PathBuf, HashSet<String>>,
+    //Where each function was first defined, to report the same name being defined by two files
+    definitions: HashMap<String, (PathBuf, Span)>,
+    diagnostics: Vec<Diagnostic>,
+    //The first phase to report an error, e.g. "Lexing"
+    failed_phase: Option<&'static str>,
+    timings: &'a mut Timings,
+}
+
+impl Loader<'_> {
+    //`import_span` is the import that asked for this file, or None for the file given on the command line
+    fn load(&mut self, path: &Path, import_span: Option<Span>) -> Result<Vec<Op>, CompileError> {
+        let input = match fs::read_to_string(path) {
+            Ok(input) => input,
+            Err(error) => {
+                return match import_span {
+                    Some(span) => {
+                        self.report(
+                            "Importing",
+                            vec![Diagnostic::report_error(
+                                DiagnosticCode::InvalidImport,
+                                format!("cannot import `{}`: {}", path.display(), error),
+                                span,
+                            )],
+                        );
+                        Ok(vec![])
+                    }
+                    None => Err(CompileError::Io {
+                        path: path.to_path_buf(),
+                        error,
+                    }),
+                };
+            }
+        };
+        let canonical = fs::canonicalize(path).map_err(|error| CompileError::Io {
+            path: path.to_path_buf(),
+            error,
+        })?;
+
+        if let Some(span) = import_span {
+            if self.importing.contains(&canonical) {
+                self.report(
+                    "Importing",
+                    vec![Diagnostic::report_error(
+                        DiagnosticCode::InvalidImport,
+                        format!(
+                            "import cycle, `{}` is already being imported",
+                            path.display()
+                        ),
+                        span,
+                    )],
+                );
+                return Ok(vec![]);
+            }
+            if self.loaded.contains(&canonical) {
+                return Ok(vec![]);
+            }
+        }
+
+        let offset = self.sources.add(&path.display().to_string(), &input);
+
+        let mut lexer = Lexer::new().with_int_width(self.int_width);
+        let source = &self.sources.source;
+        let tokens: Vec<Token> = self
+            .timings
+            .time("lexing", || lexer.lex_from(source, offset));
+        if !lexer.diagnostics.is_empty() {
+            self.report("Lexing", lexer.diagnostics);
+            return Ok(vec![]);
+        }
+
+        let mut parser = Parser::new();
+        let ops = self.timings.time("parsing", || parser.parse(&tokens));
+        if !parser.diagnostics.is_empty() {
+            self.report("Parsing", parser.diagnostics);
+            return Ok(vec![]);
+        }
+
+        self.importing.push(canonical.clone());
+
+        //Imported files are spliced in before the file that imports them, so their functions are defined first
+        let mut program = Vec::new();
+        let mut body = Vec::new();
+        let mut imports = HashMap::new();
+        for op in ops {
+            match &op.kind {
+                OpKind::Import(identifier) => {
+                    if let TokenKind::Identifier(name) = &identifier.kind {
+                        let import_path = path
+                            .parent()
+                            .unwrap_or(Path::new(""))
+                            .join(format!("{}.do", name));
+                        program.extend(self.load(&import_path, Some(op.span))?);
+                        if let Ok(module) = fs::canonicalize(&import_path) {
+                            imports.insert(name.clone(), module);
+                        }
+                    }
+                }
+                _ => body.push(op),
+            }
+        }
+
+        let mut functions = HashSet::new();
+        for op in &body {
+            if let OpKind::DefineFunction { identifier, .. } = &op.kind
+                && let TokenKind::Identifier(name) = &identifier.kind
+            {
+                self.define(name, &canonical, identifier.span);
+                functions.insert(name.clone());
+            }
+        }
+        self.modules.insert(canonical.clone(), functions);
+
+        for op in &mut body {
+            self.resolve_qualified_names(op, &imports);
+        }
+        program.extend(body);
+
+        self.importing.pop();
+        self.loaded.insert(canonical);
+
+        Ok(program)
+    }
+
+    //Every file shares one namespace once spliced together, so two files defining the same function is an error
+    fn define(&mut self, name: &str, file: &Path, span: Span) {
+        match self.definitions.get(name) {
+            Some((previous_file, previous_span)) if previous_file != file => {
+                let previous_span = *previous_span;
+                self.report(
+                    "Importing",
+                    vec![Diagnostic::report_error_with_hint(
+                        DiagnosticCode::DuplicateDefinition,
+                        format!("function `{}` is already defined", name),
+                        span,
+                        ("previously defined here".to_string(), previous_span),
+                    )],
+                );
+            }
+            Some(_) => {}
+            None => {
+                self.definitions
+                    .insert(name.to_string(), (file.to_path_buf(), span));
+            }
+        }
+    }
+
+    //Rewrites `module.name` to `name` once it's been checked that `module` is imported and defines `name`
+    fn resolve_qualified_names(&mut self, op: &mut Op, imports: &HashMap<String, PathBuf>) {
+        match &mut op.kind {
+            OpKind::Identifier(identifier) => {
+                let Some((module, name)) = identifier.split_once('.') else {
+                    return;
+                };
+                let error = match imports.get(module) {
+                    Some(path) if self.modules.get(path).is_some_and(|f| f.contains(name)) => {
+                        *identifier = name.to_string();
+                        return;
+                    }
+                    Some(_) => format!("no function `{}` in `{}`", name, module),
+                    None => format!("`{}` is not imported", module),
+                };
+                self.report(
+                    "Importing",
+                    vec![Diagnostic::report_error(
+                        DiagnosticCode::UnknownIdentifier,
+                        error,
+                        op.span,
+                    )],
+                );
+            }
+            OpKind::PushList(ops) | OpKind::PushFunction(ops) => {
+                for op in ops {
+                    self.resolve_qualified_names(op, imports);
+                }
+            }
+            OpKind::DefineFunction { body, .. } | OpKind::Binding { body, .. } => {
+                self.resolve_qualified_names(body, imports);
+            }
+            OpKind::If { body, else_body } => {
+                for op in body.iter_mut().chain(else_body.iter_mut().flatten()) {
+                    self.resolve_qualified_names(op, imports);
+                }
+            }
+            OpKind::Match { arms, default } => {
+                for op in arms.iter_mut().flat_map(|(_, body)| body).chain(default) {
+                    self.resolve_qualified_names(op, imports);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn report(&mut self, phase: &'static str, diagnostics: Vec<Diagnostic>) {
+        self.failed_phase.get_or_insert(phase);
+        self.diagnostics.extend(diagnostics);
+    }
+}
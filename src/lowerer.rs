@@ -1,8 +1,26 @@
-use std::cmp::max;
 use crate::typechecker::{TypeKind, TypedOp, TypedOpKind};
+use std::cmp::max;
 use std::collections::HashMap;
 
-#[derive(Debug, Clone, Ord, PartialOrd, Eq, PartialEq)]
+//How the arithmetic instructions behave when the result doesn't fit in a signed 64 bit integer
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverflowMode {
+    //Wrap around, which is what x86 does anyway
+    Wrapping,
+    //Stop the program with a runtime error
+    Checked,
+}
+
+//What the innermost elements of a printed list are, since the values themselves are all just numbers
+#[derive(Debug, Clone, Copy, Ord, PartialOrd, Eq, PartialEq, Hash)]
+pub enum ListElement {
+    Int,
+    Bool,
+    //Functions are printed as `fn` rather than whatever their pointer happens to be
+    Function,
+}
+
+#[derive(Debug, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
 pub enum ByteCodeInstruction {
     //Pushes a literal onto the stack
     Push(usize),
@@ -24,7 +42,13 @@ pub enum ByteCodeInstruction {
     Dup,
     Over,
     Rot,
+    //a b c -- c a b, the inverse of Rot
+    Unrot,
     Swap,
+    //a b c d -- a b c d a b
+    TwoOver,
+    //a b c d -- c d a b
+    TwoSwap,
     Inc,
     Dec,
     Add,
@@ -37,9 +61,21 @@ pub enum ByteCodeInstruction {
     GtEq,
     LtEq,
     Eq,
+    //Pops a bool and pushes 1 if it was 0, otherwise 0
+    Not,
+    //Pops two values and pushes -1, 0 or 1 depending on their ordering
+    Compare,
+    //Pops two list pointers and pushes -1, 0 or 1 depending on their lexicographic ordering, `depth` is how many
+    // lists deep the innermost elements are so that inner lists are compared by their contents
+    CompareList { depth: usize },
     Print,
     PrintBool,
-    PrintList,
+    //Pops a list pointer and prints the list, `depth` is how many lists deep the innermost elements are
+    // and `element` is what those elements are
+    PrintList { depth: usize, element: ListElement },
+    //Pops a function pointer and prints the signature at the index in the constant pool, as the function itself
+    // doesn't know its type
+    PrintFunction { index: usize },
     Label(usize),
     //Call a known function by the index in the constant pool
     CallStatic { index: usize },
@@ -51,6 +87,52 @@ pub enum ByteCodeInstruction {
 }
 
 impl ByteCodeInstruction {
+    //How many values the instruction pops and then pushes, or None if that depends on the instruction's operands:
+    // how long the list is for NewList, and which function is called for the calls
+    pub fn stack_effect(&self) -> Option<(usize, usize)> {
+        match self {
+            ByteCodeInstruction::Push(_)
+            | ByteCodeInstruction::PushBlock { .. }
+            | ByteCodeInstruction::Load { .. } => Some((0, 1)),
+            ByteCodeInstruction::Pop
+            | ByteCodeInstruction::Store { .. }
+            | ByteCodeInstruction::Print
+            | ByteCodeInstruction::PrintBool
+            | ByteCodeInstruction::PrintList { .. }
+            | ByteCodeInstruction::PrintFunction { .. }
+            | ByteCodeInstruction::JumpIfFalse { .. } => Some((1, 0)),
+            ByteCodeInstruction::ListLen
+            | ByteCodeInstruction::Inc
+            | ByteCodeInstruction::Dec
+            | ByteCodeInstruction::Not => Some((1, 1)),
+            ByteCodeInstruction::ListGet
+            | ByteCodeInstruction::Add
+            | ByteCodeInstruction::Sub
+            | ByteCodeInstruction::Mul
+            | ByteCodeInstruction::Div
+            | ByteCodeInstruction::Mod
+            | ByteCodeInstruction::Gt
+            | ByteCodeInstruction::Lt
+            | ByteCodeInstruction::GtEq
+            | ByteCodeInstruction::LtEq
+            | ByteCodeInstruction::Eq
+            | ByteCodeInstruction::Compare
+            | ByteCodeInstruction::CompareList { .. } => Some((2, 1)),
+            ByteCodeInstruction::Dup => Some((1, 2)),
+            ByteCodeInstruction::Over => Some((2, 3)),
+            ByteCodeInstruction::Swap => Some((2, 2)),
+            ByteCodeInstruction::Rot | ByteCodeInstruction::Unrot => Some((3, 3)),
+            ByteCodeInstruction::TwoOver => Some((4, 6)),
+            ByteCodeInstruction::TwoSwap => Some((4, 4)),
+            ByteCodeInstruction::Label(_)
+            | ByteCodeInstruction::Jump { .. }
+            | ByteCodeInstruction::Return => Some((0, 0)),
+            ByteCodeInstruction::NewList
+            | ByteCodeInstruction::CallStatic { .. }
+            | ByteCodeInstruction::CallDynamic => None,
+        }
+    }
+
     fn get_opcode(&self) -> usize {
         match self {
             ByteCodeInstruction::Push(_) => 0x01,
@@ -77,7 +159,7 @@ impl ByteCodeInstruction {
             ByteCodeInstruction::LtEq => 0x16,
             ByteCodeInstruction::Eq => 0x17,
             ByteCodeInstruction::Print => 0x18,
-            ByteCodeInstruction::PrintList => 0x19,
+            ByteCodeInstruction::PrintList { .. } => 0x19,
             ByteCodeInstruction::Label(_) => 0x1A,
             ByteCodeInstruction::CallStatic { .. } => 0x1B,
             ByteCodeInstruction::CallDynamic => 0x1C,
@@ -87,6 +169,13 @@ impl ByteCodeInstruction {
             ByteCodeInstruction::Inc => 0x20,
             ByteCodeInstruction::Dec => 0x21,
             ByteCodeInstruction::PrintBool => 0x22,
+            ByteCodeInstruction::Compare => 0x23,
+            ByteCodeInstruction::CompareList { .. } => 0x24,
+            ByteCodeInstruction::TwoOver => 0x25,
+            ByteCodeInstruction::TwoSwap => 0x26,
+            ByteCodeInstruction::Unrot => 0x27,
+            ByteCodeInstruction::Not => 0x28,
+            ByteCodeInstruction::PrintFunction { .. } => 0x29,
         }
     }
 
@@ -103,7 +192,10 @@ impl ByteCodeInstruction {
             ByteCodeInstruction::Dup => vec![self.get_opcode()],
             ByteCodeInstruction::Over => vec![self.get_opcode()],
             ByteCodeInstruction::Rot => vec![self.get_opcode()],
+            ByteCodeInstruction::Unrot => vec![self.get_opcode()],
             ByteCodeInstruction::Swap => vec![self.get_opcode()],
+            ByteCodeInstruction::TwoOver => vec![self.get_opcode()],
+            ByteCodeInstruction::TwoSwap => vec![self.get_opcode()],
             ByteCodeInstruction::Inc => vec![self.get_opcode()],
             ByteCodeInstruction::Dec => vec![self.get_opcode()],
             ByteCodeInstruction::Add => vec![self.get_opcode()],
@@ -116,8 +208,12 @@ impl ByteCodeInstruction {
             ByteCodeInstruction::GtEq => vec![self.get_opcode()],
             ByteCodeInstruction::LtEq => vec![self.get_opcode()],
             ByteCodeInstruction::Eq => vec![self.get_opcode()],
+            ByteCodeInstruction::Not => vec![self.get_opcode()],
             ByteCodeInstruction::Print => vec![self.get_opcode()],
-            ByteCodeInstruction::PrintList => vec![self.get_opcode()],
+            ByteCodeInstruction::PrintList { depth, element } => {
+                vec![self.get_opcode(), *depth, *element as usize]
+            }
+            ByteCodeInstruction::PrintFunction { index } => vec![self.get_opcode(), *index],
             ByteCodeInstruction::Label(label) => vec![self.get_opcode(), *label],
             ByteCodeInstruction::CallStatic { index } => vec![self.get_opcode(), *index],
             ByteCodeInstruction::CallDynamic => vec![self.get_opcode()],
@@ -125,6 +221,8 @@ impl ByteCodeInstruction {
             ByteCodeInstruction::JumpIfFalse { label } => vec![self.get_opcode(), *label],
             ByteCodeInstruction::Return => vec![self.get_opcode()],
             ByteCodeInstruction::PrintBool => vec![self.get_opcode()],
+            ByteCodeInstruction::Compare => vec![self.get_opcode()],
+            ByteCodeInstruction::CompareList { depth } => vec![self.get_opcode(), *depth],
         }
     }
 
@@ -169,7 +267,18 @@ impl ByteCodeInstruction {
             0x16 => (ByteCodeInstruction::LtEq, 1),
             0x17 => (ByteCodeInstruction::Eq, 1),
             0x18 => (ByteCodeInstruction::Print, 1),
-            0x19 => (ByteCodeInstruction::PrintList, 1),
+            0x19 => (
+                ByteCodeInstruction::PrintList {
+                    depth: arguments[0],
+                    element: match arguments[1] {
+                        0 => ListElement::Int,
+                        1 => ListElement::Bool,
+                        2 => ListElement::Function,
+                        _ => unreachable!(),
+                    },
+                },
+                3,
+            ),
             0x1A => (ByteCodeInstruction::Label(arguments[0]), 2),
             0x1B => (
                 ByteCodeInstruction::CallStatic {
@@ -194,6 +303,23 @@ impl ByteCodeInstruction {
             0x20 => (ByteCodeInstruction::Inc, 1),
             0x21 => (ByteCodeInstruction::Dec, 1),
             0x22 => (ByteCodeInstruction::PrintBool, 1),
+            0x23 => (ByteCodeInstruction::Compare, 1),
+            0x24 => (
+                ByteCodeInstruction::CompareList {
+                    depth: arguments[0],
+                },
+                2,
+            ),
+            0x25 => (ByteCodeInstruction::TwoOver, 1),
+            0x26 => (ByteCodeInstruction::TwoSwap, 1),
+            0x27 => (ByteCodeInstruction::Unrot, 1),
+            0x28 => (ByteCodeInstruction::Not, 1),
+            0x29 => (
+                ByteCodeInstruction::PrintFunction {
+                    index: arguments[0],
+                },
+                2,
+            ),
             _ => todo!("unhandled opcode {}", opcode),
         }
     }
@@ -202,16 +328,31 @@ impl ByteCodeInstruction {
 pub struct Lowerer {
     next_label: usize,
     pub constant_pool: Vec<String>,
-    functions: Vec<(Vec<TypeKind>, Vec<TypeKind>)>,
+    //The signature of each function by name, for working out how calls to them change the stack
+    functions: HashMap<String, (Vec<TypeKind>, Vec<TypeKind>)>,
     bindings: HashMap<String, usize>,
     fns_to_emit: HashMap<String, Vec<ByteCodeInstruction>>,
+    //The constant pool index of each block by its body
+    block_indices: HashMap<Vec<ByteCodeInstruction>, usize>,
     locals_count: usize,
     max_locals: usize,
+    //Locals are shared by every frame, and blocks and functions can run whenever they're called,
+    // so any local one of them uses is never handed out again
+    reserved_locals: usize,
 }
 
 pub struct StackFrame {
     pub instructions: Vec<ByteCodeInstruction>,
     pub max_locals: usize,
+    //The most values the frame has on the stack at once on top of the ones it was called with, or None if that
+    // depends on what it's run with. Worked out before optimizing, so afterwards it's only an upper bound
+    pub max_stack_depth: Option<usize>,
+}
+
+impl Default for Lowerer {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Lowerer {
@@ -219,11 +360,13 @@ impl Lowerer {
         Lowerer {
             next_label: 0,
             constant_pool: Vec::new(),
-            functions: Vec::new(),
+            functions: HashMap::new(),
             bindings: HashMap::new(),
             fns_to_emit: HashMap::new(),
+            block_indices: HashMap::new(),
             locals_count: 0,
             max_locals: 0,
+            reserved_locals: 0,
         }
     }
 
@@ -232,6 +375,7 @@ impl Lowerer {
 
         let bytecode = self.lower_ops(ops);
         let frame = StackFrame {
+            max_stack_depth: self.max_stack_depth(&bytecode),
             instructions: bytecode,
             max_locals: self.max_locals,
         };
@@ -245,6 +389,7 @@ impl Lowerer {
             let frame = StackFrame {
                 instructions: fn_to_emit.clone(),
                 max_locals: self.max_locals,
+                max_stack_depth: self.max_stack_depth(fn_to_emit),
             };
             self.locals_count = 0;
             self.bindings = HashMap::new();
@@ -271,37 +416,64 @@ impl Lowerer {
         match &op.kind {
             TypedOpKind::PushInt(value) => vec![ByteCodeInstruction::Push(*value as usize)],
             TypedOpKind::PushBool(value) => vec![ByteCodeInstruction::Push(*value as usize)],
-            TypedOpKind::PushList(elements) => {
+            TypedOpKind::PushList {
+                ops: elements,
+                length,
+            } => {
                 let mut ops = Vec::new();
-                for element in elements.iter().rev() {
-                    ops.extend(self.lower_op(element));
+                //NewList takes the first element from the top of the stack
+                if elements
+                    .iter()
+                    .all(|element| element.ins.is_empty() && element.outs.len() == 1)
+                {
+                    //Each element is pushed by exactly one op, so they can just be pushed last to first
+                    for element in elements.iter().rev() {
+                        ops.extend(self.lower_op(element));
+                    }
+                } else {
+                    //Otherwise the elements have to be computed in order, which leaves the last one on top,
+                    // so they're put back the other way round through locals
+                    ops.extend(self.lower_ops(elements));
+                    let locals: Vec<usize> = (0..*length).map(|_| self.next_local()).collect();
+                    for local in &locals {
+                        ops.push(ByteCodeInstruction::Store { index: *local });
+                    }
+                    for local in &locals {
+                        ops.push(ByteCodeInstruction::Load { index: *local });
+                    }
                 }
-                ops.push(ByteCodeInstruction::Push(elements.len()));
+                ops.push(ByteCodeInstruction::Push(*length));
                 ops.push(ByteCodeInstruction::NewList);
                 ops
             }
             TypedOpKind::PushBlock(ops) => {
-                let index = self.constant_pool.len();
-
                 let mut bytecode = self.lower_ops(ops);
                 bytecode.push(ByteCodeInstruction::Return);
+                self.reserve_locals();
+
+                //Blocks that lower to the same bytecode do the same thing, so they share one function
+                if let Some(index) = self.block_indices.get(&bytecode) {
+                    return vec![ByteCodeInstruction::PushBlock { index: *index }];
+                }
+
+                //The name is only taken once the body is lowered, so any blocks nested inside it already have theirs
+                let index = self.constant_pool.len();
+                self.constant_pool.push(format!("block_{}", index));
+                self.block_indices.insert(bytecode.clone(), index);
 
                 self.fns_to_emit
                     .insert(format!("block_{}", index), bytecode);
 
-                self.constant_pool.insert(index, format!("block_{}", index));
-
                 vec![ByteCodeInstruction::PushBlock { index }]
             }
-            TypedOpKind::Dup => {
-                if let TypeKind::List(_) = &op.ins[0] {
-                    return self.duplicate_list();
-                }
-                vec![ByteCodeInstruction::Dup]
-            }
+            //Nothing can change a list once it's made, so a list's copy can share its elements
+            TypedOpKind::Dup => vec![ByteCodeInstruction::Dup],
             TypedOpKind::Over => vec![ByteCodeInstruction::Over],
             TypedOpKind::Rot => vec![ByteCodeInstruction::Rot],
+            TypedOpKind::Unrot => vec![ByteCodeInstruction::Unrot],
             TypedOpKind::Swap => vec![ByteCodeInstruction::Swap],
+            TypedOpKind::TwoOver => vec![ByteCodeInstruction::TwoOver],
+            TypedOpKind::TwoSwap => vec![ByteCodeInstruction::TwoSwap],
             TypedOpKind::Pop => vec![ByteCodeInstruction::Pop],
             TypedOpKind::Plus => vec![ByteCodeInstruction::Add],
             TypedOpKind::Minus => vec![ByteCodeInstruction::Sub],
@@ -312,8 +484,24 @@ impl Lowerer {
             TypedOpKind::GreaterThanEquals => vec![ByteCodeInstruction::GtEq],
             TypedOpKind::LessThan => vec![ByteCodeInstruction::Lt],
             TypedOpKind::LessThanEquals => vec![ByteCodeInstruction::LtEq],
-            TypedOpKind::Equals => vec![ByteCodeInstruction::Eq],
-            TypedOpKind::Not => vec![ByteCodeInstruction::Push(0), ByteCodeInstruction::Eq],
+            TypedOpKind::Equals => match &op.ins[0] {
+                //Lists are pointers, so they're equal when comparing their elements finds no difference
+                TypeKind::List(_) => vec![
+                    ByteCodeInstruction::CompareList {
+                        depth: innermost_elements(&op.ins[0]).0,
+                    },
+                    ByteCodeInstruction::Push(0),
+                    ByteCodeInstruction::Eq,
+                ],
+                _ => vec![ByteCodeInstruction::Eq],
+            },
+            TypedOpKind::Compare => match &op.ins[0] {
+                TypeKind::List(_) => vec![ByteCodeInstruction::CompareList {
+                    depth: innermost_elements(&op.ins[0]).0,
+                }],
+                _ => vec![ByteCodeInstruction::Compare],
+            },
+            TypedOpKind::Not => vec![ByteCodeInstruction::Not],
             TypedOpKind::Map => {
                 let func_idx = self.next_local();
                 let list_idx = self.next_local();
@@ -417,6 +605,58 @@ impl Lowerer {
                     ByteCodeInstruction::NewList,
                 ]
             }
+            TypedOpKind::Count => {
+                let func_idx = self.next_local();
+                let list_idx = self.next_local();
+                let index_idx = self.next_local();
+                let count_idx = self.next_local();
+
+                let cond = self.next_label();
+                let end = self.next_label();
+
+                //[list_ptr func_ptr]
+                vec![
+                    ByteCodeInstruction::Store { index: func_idx },
+                    ByteCodeInstruction::Store { index: list_idx },
+                    //init index with len
+                    ByteCodeInstruction::Load { index: list_idx },
+                    ByteCodeInstruction::ListLen,
+                    ByteCodeInstruction::Store { index: index_idx },
+                    //init count with 0
+                    ByteCodeInstruction::Push(0),
+                    ByteCodeInstruction::Store { index: count_idx },
+                    //Prepare loop
+                    ByteCodeInstruction::Label(cond),
+                    ByteCodeInstruction::Load { index: index_idx },
+                    ByteCodeInstruction::Push(0),
+                    //Is index > 0?
+                    ByteCodeInstruction::Gt,
+                    ByteCodeInstruction::JumpIfFalse { label: end },
+                    //Decrement the index before performing the get
+                    ByteCodeInstruction::Load { index: index_idx },
+                    ByteCodeInstruction::Dec,
+                    ByteCodeInstruction::Store { index: index_idx },
+                    //Get list[index]
+                    ByteCodeInstruction::Load { index: list_idx },
+                    ByteCodeInstruction::Load { index: index_idx },
+                    ByteCodeInstruction::ListGet,
+                    //[el]
+                    ByteCodeInstruction::Load { index: func_idx },
+                    //[el func_ptr]
+                    ByteCodeInstruction::CallDynamic,
+                    //[true/false]
+                    //Jump back to cond if predicate failed
+                    ByteCodeInstruction::JumpIfFalse { label: cond },
+                    //Like filter, but only the count is kept rather than the element
+                    ByteCodeInstruction::Load { index: count_idx },
+                    ByteCodeInstruction::Inc,
+                    ByteCodeInstruction::Store { index: count_idx },
+                    //loop
+                    ByteCodeInstruction::Jump { label: cond },
+                    ByteCodeInstruction::Label(end),
+                    ByteCodeInstruction::Load { index: count_idx },
+                ]
+            }
             TypedOpKind::Fold => {
                 let func_idx = self.next_local();
                 let list_idx = self.next_local();
@@ -426,10 +666,57 @@ impl Lowerer {
                 let cond = self.next_label();
                 let end = self.next_label();
 
-                //[list_ptr acc func_ptr]
+                //[list_ptr func_ptr acc]
                 vec![
+                    ByteCodeInstruction::Store { index: acc_idx },
                     ByteCodeInstruction::Store { index: func_idx },
+                    ByteCodeInstruction::Store { index: list_idx },
+                    //init index with 0
+                    ByteCodeInstruction::Push(0),
+                    ByteCodeInstruction::Store { index: index_idx },
+                    //Prepare loop
+                    ByteCodeInstruction::Label(cond),
+                    ByteCodeInstruction::Load { index: index_idx },
+                    ByteCodeInstruction::Load { index: list_idx },
+                    ByteCodeInstruction::ListLen,
+                    //Is index < len?
+                    ByteCodeInstruction::Lt,
+                    ByteCodeInstruction::JumpIfFalse { label: end },
+                    //Get list[index]
+                    ByteCodeInstruction::Load { index: list_idx },
+                    ByteCodeInstruction::Load { index: index_idx },
+                    ByteCodeInstruction::ListGet,
+                    //Get accumulator
+                    ByteCodeInstruction::Load { index: acc_idx },
+                    //[el acc]
+                    ByteCodeInstruction::Load { index: func_idx },
+                    //[el acc func_ptr]
+                    ByteCodeInstruction::CallDynamic,
+                    //['el...]
                     ByteCodeInstruction::Store { index: acc_idx },
+                    //Increment the index
+                    ByteCodeInstruction::Load { index: index_idx },
+                    ByteCodeInstruction::Inc,
+                    ByteCodeInstruction::Store { index: index_idx },
+                    ByteCodeInstruction::Jump { label: cond },
+                    ByteCodeInstruction::Label(end),
+                    ByteCodeInstruction::Load { index: acc_idx },
+                ]
+            }
+            //The same as `fold` but from the last element to the first
+            TypedOpKind::FoldRight => {
+                let func_idx = self.next_local();
+                let list_idx = self.next_local();
+                let index_idx = self.next_local();
+                let acc_idx = self.next_local();
+
+                let cond = self.next_label();
+                let end = self.next_label();
+
+                //[list_ptr func_ptr acc]
+                vec![
+                    ByteCodeInstruction::Store { index: acc_idx },
+                    ByteCodeInstruction::Store { index: func_idx },
                     ByteCodeInstruction::Store { index: list_idx },
                     //init index with len
                     ByteCodeInstruction::Load { index: list_idx },
@@ -464,7 +751,7 @@ impl Lowerer {
                     ByteCodeInstruction::Load { index: acc_idx },
                 ]
             }
-            TypedOpKind::Foreach => {
+            TypedOpKind::Foreach | TypedOpKind::EachWhile => {
                 let func_idx = self.next_local();
                 let list_idx = self.next_local();
                 let index_idx = self.next_local();
@@ -473,7 +760,7 @@ impl Lowerer {
                 let end = self.next_label();
 
                 //[list_ptr func_ptr]
-                vec![
+                let mut bytecode = vec![
                     ByteCodeInstruction::Store { index: func_idx },
                     ByteCodeInstruction::Store { index: list_idx },
                     //init index with 0
@@ -496,6 +783,56 @@ impl Lowerer {
                     ByteCodeInstruction::Load { index: func_idx },
                     //[el func_ptr]
                     ByteCodeInstruction::CallDynamic,
+                ];
+                if let TypedOpKind::EachWhile = op.kind {
+                    //[continue?]
+                    bytecode.push(ByteCodeInstruction::JumpIfFalse { label: end });
+                }
+                bytecode.extend(vec![
+                    //Increment the index
+                    ByteCodeInstruction::Load { index: index_idx },
+                    ByteCodeInstruction::Inc,
+                    ByteCodeInstruction::Store { index: index_idx },
+                    //Jump back to the condition
+                    ByteCodeInstruction::Jump { label: cond },
+                    ByteCodeInstruction::Label(end),
+                ]);
+
+                bytecode
+            }
+            TypedOpKind::EachWithIndex => {
+                let func_idx = self.next_local();
+                let list_idx = self.next_local();
+                let index_idx = self.next_local();
+
+                let cond = self.next_label();
+                let end = self.next_label();
+
+                //[list_ptr func_ptr]
+                vec![
+                    ByteCodeInstruction::Store { index: func_idx },
+                    ByteCodeInstruction::Store { index: list_idx },
+                    //init index with 0
+                    ByteCodeInstruction::Push(0),
+                    ByteCodeInstruction::Store { index: index_idx },
+                    //Prepare loop
+                    ByteCodeInstruction::Label(cond),
+                    ByteCodeInstruction::Load { index: index_idx },
+                    ByteCodeInstruction::Load { index: list_idx },
+                    ByteCodeInstruction::ListLen,
+                    //Is index < len?
+                    ByteCodeInstruction::Lt,
+                    ByteCodeInstruction::JumpIfFalse { label: end },
+                    //The same as foreach, but with the index underneath the element
+                    ByteCodeInstruction::Load { index: index_idx },
+                    //Get list[index]
+                    ByteCodeInstruction::Load { index: list_idx },
+                    ByteCodeInstruction::Load { index: index_idx },
+                    ByteCodeInstruction::ListGet,
+                    //[index el]
+                    ByteCodeInstruction::Load { index: func_idx },
+                    //[index el func_ptr]
+                    ByteCodeInstruction::CallDynamic,
                     //Increment the index
                     ByteCodeInstruction::Load { index: index_idx },
                     ByteCodeInstruction::Inc,
@@ -505,12 +842,268 @@ impl Lowerer {
                     ByteCodeInstruction::Label(end),
                 ]
             }
-            TypedOpKind::Print => match &op.ins[0] {
-                TypeKind::List(_) => vec![ByteCodeInstruction::PrintList],
-                TypeKind::Bool => vec![ByteCodeInstruction::PrintBool],
-                _ => vec![ByteCodeInstruction::Print],
-            },
+            TypedOpKind::Print => self.lower_print(&op.ins[0]),
+            TypedOpKind::PrintStack => {
+                let locals: Vec<usize> = op.ins.iter().map(|_| self.next_local()).collect();
+
+                //The top of the stack goes into the first local, then everything is loaded back from the bottom up,
+                // printing a copy of each value on the way
+                let mut bytecode: Vec<ByteCodeInstruction> = locals
+                    .iter()
+                    .map(|index| ByteCodeInstruction::Store { index: *index })
+                    .collect();
+                for (index, type_kind) in locals.iter().zip(&op.ins).rev() {
+                    bytecode.push(ByteCodeInstruction::Load { index: *index });
+                    bytecode.push(ByteCodeInstruction::Load { index: *index });
+                    bytecode.extend(self.lower_print(type_kind));
+                }
+                bytecode
+            }
+            TypedOpKind::Concat => {
+                let rhs_idx = self.next_local();
+                let lhs_idx = self.next_local();
+
+                //[lhs_ptr rhs_ptr]
+                let mut ops = vec![
+                    ByteCodeInstruction::Store { index: rhs_idx },
+                    ByteCodeInstruction::Store { index: lhs_idx },
+                ];
+                //The first element of the new list has to end up on top, so the right hand side goes first
+                ops.extend(self.push_elements_reversed(rhs_idx));
+                ops.extend(self.push_elements_reversed(lhs_idx));
+                ops.extend(vec![
+                    ByteCodeInstruction::Load { index: lhs_idx },
+                    ByteCodeInstruction::ListLen,
+                    ByteCodeInstruction::Load { index: rhs_idx },
+                    ByteCodeInstruction::ListLen,
+                    ByteCodeInstruction::Add,
+                    ByteCodeInstruction::NewList,
+                ]);
+                ops
+            }
+            TypedOpKind::Push => {
+                let element_idx = self.next_local();
+                let list_idx = self.next_local();
+
+                //[list_ptr el]
+                let mut ops = vec![
+                    ByteCodeInstruction::Store { index: element_idx },
+                    ByteCodeInstruction::Store { index: list_idx },
+                    //The new element goes at the end, so it's pushed before the rest
+                    ByteCodeInstruction::Load { index: element_idx },
+                ];
+                ops.extend(self.push_elements_reversed(list_idx));
+                ops.extend(vec![
+                    ByteCodeInstruction::Load { index: list_idx },
+                    ByteCodeInstruction::ListLen,
+                    ByteCodeInstruction::Inc,
+                    ByteCodeInstruction::NewList,
+                ]);
+                ops
+            }
             TypedOpKind::Len => vec![ByteCodeInstruction::ListLen],
+            TypedOpKind::Digits => {
+                let value_idx = self.next_local();
+                let count_idx = self.next_local();
+
+                let cond = self.next_label();
+                let end = self.next_label();
+
+                //[value]
+                vec![
+                    ByteCodeInstruction::Store { index: value_idx },
+                    //init count with 0
+                    ByteCodeInstruction::Push(0),
+                    ByteCodeInstruction::Store { index: count_idx },
+                    //Always take at least one digit, so 0 gives [0]
+                    ByteCodeInstruction::Label(cond),
+                    //Push the last digit, which keeps the value's sign
+                    ByteCodeInstruction::Load { index: value_idx },
+                    ByteCodeInstruction::Push(10),
+                    ByteCodeInstruction::Mod,
+                    //Increment the count
+                    ByteCodeInstruction::Load { index: count_idx },
+                    ByteCodeInstruction::Inc,
+                    ByteCodeInstruction::Store { index: count_idx },
+                    //Drop the last digit from the value
+                    ByteCodeInstruction::Load { index: value_idx },
+                    ByteCodeInstruction::Push(10),
+                    ByteCodeInstruction::Div,
+                    ByteCodeInstruction::Store { index: value_idx },
+                    //Are there any digits left?
+                    ByteCodeInstruction::Load { index: value_idx },
+                    ByteCodeInstruction::Push(0),
+                    ByteCodeInstruction::Eq,
+                    ByteCodeInstruction::Not,
+                    ByteCodeInstruction::JumpIfFalse { label: end },
+                    ByteCodeInstruction::Jump { label: cond },
+                    ByteCodeInstruction::Label(end),
+                    //The most significant digit was pushed last, so it's the first element
+                    ByteCodeInstruction::Load { index: count_idx },
+                    ByteCodeInstruction::NewList,
+                ]
+            }
+            TypedOpKind::Uncons => {
+                let list_idx = self.next_local();
+                let index_idx = self.next_local();
+
+                let cond = self.next_label();
+                let end = self.next_label();
+
+                //[list_ptr]
+                vec![
+                    ByteCodeInstruction::Store { index: list_idx },
+                    //Push the head, which is an index error if the list is empty
+                    ByteCodeInstruction::Load { index: list_idx },
+                    ByteCodeInstruction::Push(0),
+                    ByteCodeInstruction::ListGet,
+                    //init index with len
+                    ByteCodeInstruction::Load { index: list_idx },
+                    ByteCodeInstruction::ListLen,
+                    ByteCodeInstruction::Store { index: index_idx },
+                    //Prepare loop
+                    ByteCodeInstruction::Label(cond),
+                    ByteCodeInstruction::Load { index: index_idx },
+                    ByteCodeInstruction::Push(1),
+                    //Is index > 1?
+                    ByteCodeInstruction::Gt,
+                    ByteCodeInstruction::JumpIfFalse { label: end },
+                    //Decrement the index before performing the get
+                    ByteCodeInstruction::Load { index: index_idx },
+                    ByteCodeInstruction::Dec,
+                    ByteCodeInstruction::Store { index: index_idx },
+                    //Push list[index], leaving the second element on top for NewList
+                    ByteCodeInstruction::Load { index: list_idx },
+                    ByteCodeInstruction::Load { index: index_idx },
+                    ByteCodeInstruction::ListGet,
+                    ByteCodeInstruction::Jump { label: cond },
+                    ByteCodeInstruction::Label(end),
+                    //The tail is everything but the head
+                    ByteCodeInstruction::Load { index: list_idx },
+                    ByteCodeInstruction::ListLen,
+                    ByteCodeInstruction::Dec,
+                    ByteCodeInstruction::NewList,
+                ]
+            }
+            TypedOpKind::Maximum => self.reduce_list_by(ByteCodeInstruction::Gt),
+            TypedOpKind::Minimum => self.reduce_list_by(ByteCodeInstruction::Lt),
+            TypedOpKind::Clamp => {
+                let value_idx = self.next_local();
+                let low_idx = self.next_local();
+                let high_idx = self.next_local();
+
+                let above_low = self.next_label();
+                let below_high = self.next_label();
+
+                //[value low high]
+                vec![
+                    ByteCodeInstruction::Store { index: high_idx },
+                    ByteCodeInstruction::Store { index: low_idx },
+                    ByteCodeInstruction::Store { index: value_idx },
+                    //Raise the value to low...
+                    ByteCodeInstruction::Load { index: value_idx },
+                    ByteCodeInstruction::Load { index: low_idx },
+                    ByteCodeInstruction::Lt,
+                    ByteCodeInstruction::JumpIfFalse { label: above_low },
+                    ByteCodeInstruction::Load { index: low_idx },
+                    ByteCodeInstruction::Store { index: value_idx },
+                    ByteCodeInstruction::Label(above_low),
+                    //...then lower it to high, so high wins if it's below low
+                    ByteCodeInstruction::Load { index: value_idx },
+                    ByteCodeInstruction::Load { index: high_idx },
+                    ByteCodeInstruction::Gt,
+                    ByteCodeInstruction::JumpIfFalse { label: below_high },
+                    ByteCodeInstruction::Load { index: high_idx },
+                    ByteCodeInstruction::Store { index: value_idx },
+                    ByteCodeInstruction::Label(below_high),
+                    ByteCodeInstruction::Load { index: value_idx },
+                ]
+            }
+            TypedOpKind::Replicate => {
+                let value_idx = self.next_local();
+                let count_idx = self.next_local();
+                let index_idx = self.next_local();
+
+                let cond = self.next_label();
+                let end = self.next_label();
+
+                //[count value]
+                vec![
+                    ByteCodeInstruction::Store { index: value_idx },
+                    ByteCodeInstruction::Store { index: count_idx },
+                    //init index with 0
+                    ByteCodeInstruction::Push(0),
+                    ByteCodeInstruction::Store { index: index_idx },
+                    //Prepare loop
+                    ByteCodeInstruction::Label(cond),
+                    ByteCodeInstruction::Load { index: index_idx },
+                    ByteCodeInstruction::Load { index: count_idx },
+                    //Is index < count?
+                    ByteCodeInstruction::Lt,
+                    ByteCodeInstruction::JumpIfFalse { label: end },
+                    //Push another copy of the value
+                    ByteCodeInstruction::Load { index: value_idx },
+                    //Increment the index
+                    ByteCodeInstruction::Load { index: index_idx },
+                    ByteCodeInstruction::Inc,
+                    ByteCodeInstruction::Store { index: index_idx },
+                    //Jump back to the condition
+                    ByteCodeInstruction::Jump { label: cond },
+                    ByteCodeInstruction::Label(end),
+                    //The index stops at the count, or stays at 0 if the count isn't positive
+                    ByteCodeInstruction::Load { index: index_idx },
+                    ByteCodeInstruction::NewList,
+                ]
+            }
+            TypedOpKind::Rotate => {
+                let list_idx = self.next_local();
+                let count_idx = self.next_local();
+                let length_idx = self.next_local();
+                let index_idx = self.next_local();
+
+                let cond = self.next_label();
+                let end = self.next_label();
+
+                //[count list_ptr]
+                vec![
+                    ByteCodeInstruction::Store { index: list_idx },
+                    ByteCodeInstruction::Store { index: count_idx },
+                    ByteCodeInstruction::Load { index: list_idx },
+                    ByteCodeInstruction::ListLen,
+                    ByteCodeInstruction::Store { index: length_idx },
+                    //init index with len
+                    ByteCodeInstruction::Load { index: length_idx },
+                    ByteCodeInstruction::Store { index: index_idx },
+                    //Prepare loop
+                    ByteCodeInstruction::Label(cond),
+                    ByteCodeInstruction::Load { index: index_idx },
+                    ByteCodeInstruction::Push(0),
+                    //Is index > 0?
+                    ByteCodeInstruction::Gt,
+                    ByteCodeInstruction::JumpIfFalse { label: end },
+                    //Decrement the index before performing the get
+                    ByteCodeInstruction::Load { index: index_idx },
+                    ByteCodeInstruction::Dec,
+                    ByteCodeInstruction::Store { index: index_idx },
+                    //Push list[((index + count % len) + len) % len], the extra len keeps it positive when count is
+                    // negative. Only reached when the list isn't empty, so it never divides by zero
+                    ByteCodeInstruction::Load { index: list_idx },
+                    ByteCodeInstruction::Load { index: index_idx },
+                    ByteCodeInstruction::Load { index: count_idx },
+                    ByteCodeInstruction::Load { index: length_idx },
+                    ByteCodeInstruction::Mod,
+                    ByteCodeInstruction::Add,
+                    ByteCodeInstruction::Load { index: length_idx },
+                    ByteCodeInstruction::Add,
+                    ByteCodeInstruction::Load { index: length_idx },
+                    ByteCodeInstruction::Mod,
+                    ByteCodeInstruction::ListGet,
+                    ByteCodeInstruction::Jump { label: cond },
+                    ByteCodeInstruction::Label(end),
+                    ByteCodeInstruction::Load { index: length_idx },
+                    ByteCodeInstruction::NewList,
+                ]
+            }
             TypedOpKind::DefineFunction { name, block } => {
                 if let TypedOpKind::PushBlock(ops) = &block.kind {
                     let mut bytecode = Vec::new();
@@ -518,7 +1111,9 @@ impl Lowerer {
                         bytecode.extend(self.lower_op(op));
                     }
                     bytecode.push(ByteCodeInstruction::Return);
-                    self.functions.push((block.ins.clone(), block.outs.clone()));
+                    self.reserve_locals();
+                    self.functions
+                        .insert(name.clone(), (block.ins.clone(), block.outs.clone()));
                     self.constant_pool.push(name.clone());
 
                     self.fns_to_emit.insert(name.clone(), bytecode);
@@ -531,30 +1126,94 @@ impl Lowerer {
             TypedOpKind::Do => {
                 vec![ByteCodeInstruction::CallDynamic]
             }
+            TypedOpKind::Curry => {
+                //A function value is a list of its code followed by the values it has captured, which are
+                // pushed above its arguments when it's called. Currying makes a new list with the value
+                // captured ahead of the function's own captures, so it ends up just under them
+                let function_idx = self.next_local();
+                let value_idx = self.next_local();
+                let index_idx = self.next_local();
+
+                let cond = self.next_label();
+                let end = self.next_label();
+
+                //[value function]
+                vec![
+                    ByteCodeInstruction::Store {
+                        index: function_idx,
+                    },
+                    ByteCodeInstruction::Store { index: value_idx },
+                    //init index with len
+                    ByteCodeInstruction::Load {
+                        index: function_idx,
+                    },
+                    ByteCodeInstruction::ListLen,
+                    ByteCodeInstruction::Store { index: index_idx },
+                    //Prepare loop
+                    ByteCodeInstruction::Label(cond),
+                    ByteCodeInstruction::Load { index: index_idx },
+                    ByteCodeInstruction::Push(1),
+                    //Is index > 1?
+                    ByteCodeInstruction::Gt,
+                    ByteCodeInstruction::JumpIfFalse { label: end },
+                    //Decrement the index before performing the get
+                    ByteCodeInstruction::Load { index: index_idx },
+                    ByteCodeInstruction::Dec,
+                    ByteCodeInstruction::Store { index: index_idx },
+                    //Push the captures from the last, leaving the first on top
+                    ByteCodeInstruction::Load {
+                        index: function_idx,
+                    },
+                    ByteCodeInstruction::Load { index: index_idx },
+                    ByteCodeInstruction::ListGet,
+                    ByteCodeInstruction::Jump { label: cond },
+                    ByteCodeInstruction::Label(end),
+                    ByteCodeInstruction::Load { index: value_idx },
+                    //The code, which goes first
+                    ByteCodeInstruction::Load {
+                        index: function_idx,
+                    },
+                    ByteCodeInstruction::Push(0),
+                    ByteCodeInstruction::ListGet,
+                    //One longer than the function it was made from
+                    ByteCodeInstruction::Load {
+                        index: function_idx,
+                    },
+                    ByteCodeInstruction::ListLen,
+                    ByteCodeInstruction::Inc,
+                    ByteCodeInstruction::NewList,
+                ]
+            }
             TypedOpKind::Call(name) => {
                 let index = self.constant_pool.iter().position(|n| n == name).unwrap();
                 vec![ByteCodeInstruction::CallStatic { index }]
             }
             TypedOpKind::Binding { bindings, body } => {
                 let mut bytecode = Vec::new();
+                //Any bindings these shadow, to restore once the body is done with them
+                let mut shadowed = Vec::new();
+                //Combinators in the body take locals of their own without giving them back,
+                // so restore the count as it was rather than unbinding one local at a time
+                let locals_count = self.locals_count;
 
                 for binding in bindings {
                     let local = self.next_local();
                     bytecode.push(ByteCodeInstruction::Store { index: local });
-                    self.bindings.insert(binding.clone(), local);
+                    let previous = self.bindings.insert(binding.clone(), local);
+                    shadowed.push((binding, previous));
                 }
 
                 for op in body {
                     bytecode.extend(self.lower_op(op));
                 }
 
-                //Keep track of the maximum number of locals we use over the binding
-                self.max_locals = max(self.max_locals, self.locals_count);
-
                 //Unbind locals to reuse their space on the stack
-                for binding in bindings {
-                    self.locals_count -= 1;
-                    self.bindings.remove(binding);
+                self.locals_count = max(locals_count, self.reserved_locals);
+                for (binding, previous) in shadowed.into_iter().rev() {
+                    match previous {
+                        Some(local) => self.bindings.insert(binding.clone(), local),
+                        None => self.bindings.remove(binding),
+                    };
                 }
 
                 bytecode
@@ -604,50 +1263,168 @@ impl Lowerer {
                     }
                 }
             }
+            TypedOpKind::Match { arms, default } => {
+                let value_idx = self.next_local();
+                let end = self.next_label();
+
+                //[value]
+                let mut bytecode = vec![ByteCodeInstruction::Store { index: value_idx }];
+                for (value, body) in arms {
+                    let next_arm = self.next_label();
+                    bytecode.extend(vec![
+                        ByteCodeInstruction::Load { index: value_idx },
+                        ByteCodeInstruction::Push(*value as usize),
+                        ByteCodeInstruction::Eq,
+                        ByteCodeInstruction::JumpIfFalse { label: next_arm },
+                    ]);
+                    for op in body {
+                        bytecode.extend(self.lower_op(op));
+                    }
+                    bytecode.push(ByteCodeInstruction::Jump { label: end });
+                    bytecode.push(ByteCodeInstruction::Label(next_arm));
+                }
+                for op in default {
+                    bytecode.extend(self.lower_op(op));
+                }
+                bytecode.push(ByteCodeInstruction::Label(end));
+
+                bytecode
+            }
+            TypedOpKind::When | TypedOpKind::Unless => {
+                let func_idx = self.next_local();
+
+                let end = self.next_label();
+
+                //[cond func_ptr]
+                let mut bytecode = vec![ByteCodeInstruction::Store { index: func_idx }];
+                if let TypedOpKind::Unless = op.kind {
+                    //Negate the condition
+                    bytecode.push(ByteCodeInstruction::Push(0));
+                    bytecode.push(ByteCodeInstruction::Eq);
+                }
+                bytecode.extend(vec![
+                    //[cond]
+                    ByteCodeInstruction::JumpIfFalse { label: end },
+                    ByteCodeInstruction::Load { index: func_idx },
+                    //[func_ptr]
+                    ByteCodeInstruction::CallDynamic,
+                    ByteCodeInstruction::Label(end),
+                ]);
+                bytecode
+            }
             _ => todo!("lowering {:?} is not yet implemented", op.kind),
         }
     }
 
-    //Helper method for the code to duplicate a list on the stack
-    fn duplicate_list(&mut self) -> Vec<ByteCodeInstruction> {
-        //[list_ptr func_ptr]
+    //The instructions that pop a value of the type and print it
+    fn lower_print(&mut self, type_kind: &TypeKind) -> Vec<ByteCodeInstruction> {
+        match type_kind {
+            TypeKind::List(_) => {
+                let (depth, element_type) = innermost_elements(type_kind);
+                vec![ByteCodeInstruction::PrintList {
+                    depth,
+                    element: match element_type {
+                        TypeKind::Bool => ListElement::Bool,
+                        TypeKind::Block { .. } => ListElement::Function,
+                        _ => ListElement::Int,
+                    },
+                }]
+            }
+            TypeKind::Bool => vec![ByteCodeInstruction::PrintBool],
+            TypeKind::Block { .. } => {
+                let index = self.constant_pool.len();
+                self.constant_pool.push(type_kind.to_string());
+                vec![ByteCodeInstruction::PrintFunction { index }]
+            }
+            _ => vec![ByteCodeInstruction::Print],
+        }
+    }
+
+    //Helper method for the code to push every element of the list in the given local from last to first,
+    // leaving the first element on top ready for NewList
+    fn push_elements_reversed(&mut self, list_idx: usize) -> Vec<ByteCodeInstruction> {
+        let index_idx = self.next_local();
+
         let cond = self.next_label();
         let end = self.next_label();
 
-        let list_idx = self.next_local();
-        let counter_idx = self.next_local();
-
         vec![
-            //[list_ptr]
-            ByteCodeInstruction::Store { index: list_idx },
+            //init index with len
             ByteCodeInstruction::Load { index: list_idx },
-            //Create counter starting at len to count down
             ByteCodeInstruction::ListLen,
-            ByteCodeInstruction::Store { index: counter_idx },
+            ByteCodeInstruction::Store { index: index_idx },
             //Prepare loop
             ByteCodeInstruction::Label(cond),
+            ByteCodeInstruction::Load { index: index_idx },
             ByteCodeInstruction::Push(0),
-            ByteCodeInstruction::Load { index: counter_idx },
-            //Is counter > 0?
+            //Is index > 0?
             ByteCodeInstruction::Gt,
             ByteCodeInstruction::JumpIfFalse { label: end },
-            //Decrement the counter before performing the get
-            ByteCodeInstruction::Load { index: counter_idx },
+            //Decrement the index before performing the get
+            ByteCodeInstruction::Load { index: index_idx },
             ByteCodeInstruction::Dec,
-            ByteCodeInstruction::Store { index: counter_idx },
-            //Push list[len - counter] onto the stack
+            ByteCodeInstruction::Store { index: index_idx },
+            //Push list[index]
             ByteCodeInstruction::Load { index: list_idx },
-            ByteCodeInstruction::Load { index: counter_idx },
+            ByteCodeInstruction::Load { index: index_idx },
             ByteCodeInstruction::ListGet,
             ByteCodeInstruction::Jump { label: cond },
             ByteCodeInstruction::Label(end),
-            //Create new list of the same size from the elements now on the stack
+        ]
+    }
+
+    //Helper method for the code to find the element of a list that `keep` prefers over all the others,
+    // where `keep` compares an element against the best so far
+    fn reduce_list_by(&mut self, keep: ByteCodeInstruction) -> Vec<ByteCodeInstruction> {
+        let list_idx = self.next_local();
+        let index_idx = self.next_local();
+        let element_idx = self.next_local();
+        let best_idx = self.next_local();
+
+        let cond = self.next_label();
+        let next = self.next_label();
+        let end = self.next_label();
+
+        //[list_ptr]
+        vec![
+            ByteCodeInstruction::Store { index: list_idx },
+            //Start from the first element, which is an index error if the list is empty
+            ByteCodeInstruction::Load { index: list_idx },
+            ByteCodeInstruction::Push(0),
+            ByteCodeInstruction::ListGet,
+            ByteCodeInstruction::Store { index: best_idx },
+            //init index with 1
+            ByteCodeInstruction::Push(1),
+            ByteCodeInstruction::Store { index: index_idx },
+            //Prepare loop
+            ByteCodeInstruction::Label(cond),
+            ByteCodeInstruction::Load { index: index_idx },
             ByteCodeInstruction::Load { index: list_idx },
             ByteCodeInstruction::ListLen,
-            ByteCodeInstruction::NewList,
-            //Restore the stack to be [orig new]
+            //Is index < len?
+            ByteCodeInstruction::Lt,
+            ByteCodeInstruction::JumpIfFalse { label: end },
+            //Get list[index]
             ByteCodeInstruction::Load { index: list_idx },
-            ByteCodeInstruction::Swap,
+            ByteCodeInstruction::Load { index: index_idx },
+            ByteCodeInstruction::ListGet,
+            ByteCodeInstruction::Store { index: element_idx },
+            //Keep the element if it beats the best so far
+            ByteCodeInstruction::Load { index: element_idx },
+            ByteCodeInstruction::Load { index: best_idx },
+            keep,
+            ByteCodeInstruction::JumpIfFalse { label: next },
+            ByteCodeInstruction::Load { index: element_idx },
+            ByteCodeInstruction::Store { index: best_idx },
+            ByteCodeInstruction::Label(next),
+            //Increment the index
+            ByteCodeInstruction::Load { index: index_idx },
+            ByteCodeInstruction::Inc,
+            ByteCodeInstruction::Store { index: index_idx },
+            //Jump back to the condition
+            ByteCodeInstruction::Jump { label: cond },
+            ByteCodeInstruction::Label(end),
+            ByteCodeInstruction::Load { index: best_idx },
         ]
     }
 
@@ -657,12 +1434,80 @@ impl Lowerer {
         label
     }
 
+    fn reserve_locals(&mut self) {
+        self.reserved_locals = self.max_locals;
+        self.locals_count = max(self.locals_count, self.reserved_locals);
+    }
+
     fn next_local(&mut self) -> usize {
         let local = self.locals_count;
         self.locals_count += 1;
+        //Keep track of the maximum number of locals in use at once, so the frame has room for all of them
+        self.max_locals = max(self.max_locals, self.locals_count);
         local
     }
 
+    //Walks the instructions keeping count of how many values are on the stack, carrying the count over to each
+    // label from the jumps to it. Gives up when the count depends on values only known at runtime: dynamic calls,
+    // lists built from a computed number of elements and loops that leave more on the stack each time round
+    fn max_stack_depth(&self, instructions: &[ByteCodeInstruction]) -> Option<usize> {
+        let mut label_depths: HashMap<usize, i64> = HashMap::new();
+        //None once a jump or return means the next instruction can only be reached by jumping to it
+        let mut depth: Option<i64> = Some(0);
+        let mut max_depth: i64 = 0;
+
+        for (i, instruction) in instructions.iter().enumerate() {
+            if let ByteCodeInstruction::Label(label) = instruction {
+                depth = match (depth, label_depths.get(label)) {
+                    (Some(depth), Some(jumped)) if depth != *jumped => return None,
+                    (Some(depth), _) => Some(depth),
+                    (None, Some(jumped)) => Some(*jumped),
+                    (None, None) => return None,
+                };
+                label_depths.insert(*label, depth.unwrap());
+                continue;
+            }
+            let current = depth?;
+
+            let (pops, pushes) = match (instruction.stack_effect(), instruction) {
+                (Some(effect), _) => effect,
+                (None, ByteCodeInstruction::NewList) => {
+                    match i.checked_sub(1).map(|i| &instructions[i]) {
+                        Some(ByteCodeInstruction::Push(length)) => (*length + 1, 1),
+                        _ => return None,
+                    }
+                }
+                (None, ByteCodeInstruction::CallStatic { index }) => {
+                    let (ins, outs) = self.functions.get(&self.constant_pool[*index])?;
+                    (ins.len(), outs.len())
+                }
+                (None, _) => return None,
+            };
+            let next = current - pops as i64 + pushes as i64;
+            max_depth = max(max_depth, next);
+            depth = Some(next);
+
+            match instruction {
+                ByteCodeInstruction::Jump { label }
+                | ByteCodeInstruction::JumpIfFalse { label } => {
+                    match label_depths.get(label) {
+                        Some(jumped) if *jumped != next => return None,
+                        _ => {
+                            label_depths.insert(*label, next);
+                        }
+                    }
+                    if let ByteCodeInstruction::Jump { .. } = instruction {
+                        depth = None;
+                    }
+                }
+                ByteCodeInstruction::Return => depth = None,
+                _ => {}
+            }
+        }
+
+        Some(max_depth as usize)
+    }
+
     //TODO: this will enable String literals in future but we don't need it now
     // fn next_const(&mut self, name: String) -> usize {
     //     let index = self.constant_pool.len();
@@ -670,3 +1515,69 @@ impl Lowerer {
     //     index
     // }
 }
+
+//How many lists deep the innermost elements of a list type are, and what they are, e.g. 2 and `int` for `[[int]]`
+fn innermost_elements(list_type: &TypeKind) -> (usize, &TypeKind) {
+    let mut depth = 0;
+    let mut element_type = list_type;
+    while let TypeKind::List(inner) = element_type {
+        depth += 1;
+        element_type = inner;
+    }
+    (depth, element_type)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn main_frame(program: &crate::Program) -> &StackFrame {
+        &program
+            .bytecode
+            .iter()
+            .find(|(name, _)| name == "main")
+            .unwrap()
+            .1
+    }
+
+    #[test]
+    fn max_stack_depth_counts_values_waiting_to_be_used() {
+        let lowerer = Lowerer::new();
+        let instructions = [
+            ByteCodeInstruction::Push(1),
+            ByteCodeInstruction::Push(2),
+            ByteCodeInstruction::Push(3),
+            ByteCodeInstruction::Add,
+            ByteCodeInstruction::Add,
+        ];
+        assert_eq!(lowerer.max_stack_depth(&instructions), Some(3));
+
+        let program = crate::compile("1 2 3 + + print").unwrap();
+        assert_eq!(main_frame(&program).max_stack_depth, Some(3));
+    }
+
+    #[test]
+    fn identical_blocks_share_one_function() {
+        let program = crate::compile("(1) do (1) do + print").unwrap();
+        assert_eq!(program.constants, ["block_0"]);
+        let names: Vec<&str> = program
+            .bytecode
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect();
+        assert_eq!(names, ["block_0", "main"]);
+
+        let pushes: Vec<&ByteCodeInstruction> = main_frame(&program)
+            .instructions
+            .iter()
+            .filter(|instruction| matches!(instruction, ByteCodeInstruction::PushBlock { .. }))
+            .collect();
+        assert_eq!(
+            pushes,
+            [
+                &ByteCodeInstruction::PushBlock { index: 0 },
+                &ByteCodeInstruction::PushBlock { index: 0 }
+            ]
+        );
+    }
+}
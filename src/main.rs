@@ -1,27 +1,130 @@
-use crate::emitter::FasmEmitter;
-use crate::typechecker::TypedOp;
 use anyhow::{Context, Error, Result};
-use bytecode_interpreter::BytecodeInterpreter;
-use lexer::{Lexer, Token};
-use lowerer::Lowerer;
-use parser::Parser;
+use dolang::bytecode_interpreter::BytecodeInterpreter;
+use dolang::diagnostic::{Diagnostic, SourceMap};
+use dolang::emitter::FasmEmitter;
+use dolang::lexer::{IntWidth, Lexer};
+use dolang::lowerer::OverflowMode;
+use dolang::parser::Parser;
+use dolang::timings::Timings;
+use dolang::{CompileError, CompileOptions, Program, color, formatter};
 use std::fs::File;
 use std::path::Path;
-use std::process::Command;
-use std::{env, fs};
-use typechecker::TypeChecker;
-
-mod bytecode_interpreter;
-mod diagnostic;
-mod emitter;
-mod lexer;
-mod lowerer;
-mod parser;
+use std::process::{Command, ExitCode};
+use std::{env, fs, io};
+
 mod repl;
-mod typechecker;
 
-fn main() -> Result<()> {
-    let mut args = env::args().skip(1).peekable();
+const USAGE: &str = "\
+Usage: do [flags] [mode] [file]
+
+Modes:
+    do                             Start the REPL
+    do -i file.do                  Interpret a file
+    do file.do                     Compile a file to an executable with fasm
+    do -r file.do                  Compile a file and run it
+    do -b file.dob                 Interpret a bytecode file (not supported yet)
+    do --check file.do             Type check a file without running or compiling it
+    do --fmt file.do               Print a file laid out the standard way
+
+Flags:
+    --optimize                     Optimize the bytecode before running or compiling it
+    --overflow=wrapping|checked    Wrap on integer overflow (the default) or stop with a runtime error
+    --int-width=64|32              Reject int literals too large for 64 bits (the default) or 32 bits
+    --time                         Print how long each phase took
+    --trace                        Print each instruction the interpreter runs and the stack it leaves
+    --no-color                     Turn off coloured output, as does setting NO_COLOR
+    --help                         Print this message
+    --version                      Print the version of Do";
+
+struct Options {
+    optimize: bool,
+    overflow_mode: OverflowMode,
+    int_width: IntWidth,
+    time: bool,
+    trace: bool,
+    check: bool,
+    fmt: bool,
+    color: bool,
+}
+
+impl Options {
+    fn compile_options(&self) -> CompileOptions {
+        CompileOptions {
+            optimize: self.optimize,
+            int_width: self.int_width,
+        }
+    }
+}
+
+//Any error, including diagnostics from the front end, exits with a non-zero status so scripts and CI can rely on it.
+// The diagnostics have already been printed by then, so only the summary is added without a backtrace
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("Error: {:#}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run() -> Result<()> {
+    let (flags, args): (Vec<String>, Vec<String>) =
+        env::args().skip(1).partition(|arg| arg.starts_with("--"));
+
+    let mut options = Options {
+        optimize: false,
+        overflow_mode: OverflowMode::Wrapping,
+        int_width: IntWidth::I64,
+        time: false,
+        trace: false,
+        check: false,
+        fmt: false,
+        color: !color::no_color_requested(),
+    };
+    for flag in &flags {
+        match flag.as_str() {
+            "--optimize" => options.optimize = true,
+            "--time" => options.time = true,
+            "--trace" => options.trace = true,
+            "--check" => options.check = true,
+            "--fmt" => options.fmt = true,
+            "--no-color" => options.color = false,
+            "--overflow=wrapping" => options.overflow_mode = OverflowMode::Wrapping,
+            "--overflow=checked" => options.overflow_mode = OverflowMode::Checked,
+            "--int-width=64" => options.int_width = IntWidth::I64,
+            "--int-width=32" => options.int_width = IntWidth::I32,
+            "--help" => {
+                println!("{}", USAGE);
+                return Ok(());
+            }
+            "--version" => {
+                println!("do {}", env!("CARGO_PKG_VERSION"));
+                return Ok(());
+            }
+            _ => return Err(anyhow::anyhow!("Unknown flag `{}`", flag)),
+        }
+    }
+
+    color::set_enabled(options.color);
+
+    let mut args = args.into_iter().peekable();
+
+    if options.check {
+        return match args.next() {
+            Some(path) if path.ends_with(".do") => check_file(&path, &options),
+            Some(_) => Err(anyhow::anyhow!("Expected .do file path")),
+            None => Err(anyhow::anyhow!("Unknown arguments")),
+        };
+    }
+
+    if options.fmt {
+        return match args.next() {
+            Some(path) if path.ends_with(".do") => format_file(&path),
+            Some(_) => Err(anyhow::anyhow!("Expected .do file path")),
+            None => Err(anyhow::anyhow!("Unknown arguments")),
+        };
+    }
 
     // //TODO: this is a stupid way to do args, use a lib to parse properly with usage
     match args.peek().map(|s| s.as_str()) {
@@ -30,7 +133,7 @@ fn main() -> Result<()> {
             match args.peek().map(|s| s.as_str()) {
                 Some(path) if path.ends_with(".do") => {
                     let input_path = args.next().unwrap();
-                    compile_file(&input_path, true, &[])
+                    compile_file(&input_path, true, &[], &options)
                 }
                 Some(_) => Err(anyhow::anyhow!("Expected .do file path")),
                 None => Err(anyhow::anyhow!("Unknown arguments")),
@@ -41,7 +144,7 @@ fn main() -> Result<()> {
             match args.peek().map(|s| s.as_str()) {
                 Some(path) if path.ends_with(".do") => {
                     let input_path = args.next().unwrap();
-                    interpret_file(&input_path)
+                    interpret_file(&input_path, &options)
                 }
                 Some(_) => Err(anyhow::anyhow!("Expected .do file path")),
                 None => Err(anyhow::anyhow!("Unknown arguments")),
@@ -59,18 +162,104 @@ fn main() -> Result<()> {
         }
         Some(path) if path.ends_with(".do") => {
             let input_path = args.next().unwrap();
-            compile_file(&input_path, false, &[])
+            compile_file(&input_path, false, &[], &options)
         }
         None => repl::repl_mode(),
         _ => Err(anyhow::anyhow!("Unknown arguments")),
     }
 }
 
-fn interpret_file(input_path: &String) -> Result<(), Error> {
-    let typed_ops = check_program(input_path)?;
+//Compiles the file along with its imports, displaying whatever was reported against them
+fn front_end(
+    input_path: &String,
+    options: &Options,
+    timings: &mut Timings,
+) -> Result<Program, Error> {
+    let mut sources = SourceMap::new();
+    let result = dolang::compile_file(
+        Path::new(input_path),
+        &options.compile_options(),
+        &mut sources,
+        timings,
+    );
+    match result {
+        Ok(program) => {
+            //Warnings are shown but don't stop the program
+            display_diagnostics(&program.warnings, &sources);
+            Ok(program)
+        }
+        Err(error) => Err(display_error(error, &sources)),
+    }
+}
+
+//The diagnostics are printed here, so only the summary is left for main to print
+fn display_error(error: CompileError, sources: &SourceMap) -> Error {
+    if let CompileError::Diagnostics { diagnostics, .. } = &error {
+        display_diagnostics(diagnostics, sources);
+    }
+    error.into()
+}
+
+fn display_diagnostics(diagnostics: &[Diagnostic], sources: &SourceMap) {
+    for diagnostic in diagnostics {
+        diagnostic.display_in(sources);
+    }
+}
+
+//Stops after type checking, so errors can be found without running or compiling anything
+fn check_file(input_path: &String, options: &Options) -> Result<(), Error> {
+    let mut timings = Timings::new();
+    let mut sources = SourceMap::new();
+    let result = dolang::check_file(
+        Path::new(input_path),
+        &options.compile_options(),
+        &mut sources,
+        &mut timings,
+    );
+    match result {
+        Ok(warnings) => display_diagnostics(&warnings, &sources),
+        Err(error) => return Err(display_error(error, &sources)),
+    }
+
+    if options.time {
+        timings.report();
+    }
+
+    Ok(())
+}
+
+//Only the file itself is formatted, so its imports aren't loaded and nothing is type checked
+fn format_file(input_path: &String) -> Result<(), Error> {
+    let input = fs::read_to_string(input_path)
+        .with_context(|| format!("Failed to read input file `{}`", input_path))?;
+    let mut sources = SourceMap::new();
+    sources.add(input_path, &input);
+
+    let mut lexer = Lexer::with_comments();
+    let tokens = lexer.lex(&input);
+    if !lexer.diagnostics.is_empty() {
+        for diagnostic in &lexer.diagnostics {
+            diagnostic.display_in(&sources);
+        }
+        return Err(anyhow::anyhow!("Lexing failed"));
+    }
+
+    let mut parser = Parser::new();
+    let ops = parser.parse(&tokens);
+    if !parser.diagnostics.is_empty() {
+        for diagnostic in &parser.diagnostics {
+            diagnostic.display_in(&sources);
+        }
+        return Err(anyhow::anyhow!("Parsing failed"));
+    }
+
+    print!("{}", formatter::format(&input, &tokens, &ops));
+    Ok(())
+}
 
-    let mut lowerer = Lowerer::new();
-    let bytecode = lowerer.lower(&typed_ops);
+fn interpret_file(input_path: &String, options: &Options) -> Result<(), Error> {
+    let mut timings = Timings::new();
+    let program = front_end(input_path, options, &mut timings)?;
 
     //TODO: allow saving and interpreting straight from dob files
     // // Derive output file names from input path
@@ -97,18 +286,32 @@ fn interpret_file(input_path: &String) -> Result<(), Error> {
     //     }
     // }
 
-    let mut bytecode_interpreter = BytecodeInterpreter::new();
+    let mut bytecode_interpreter = BytecodeInterpreter::new(options.overflow_mode);
+    bytecode_interpreter.trace = options.trace;
+
+    timings.time("interpreting", || {
+        bytecode_interpreter.interpret(
+            &program.bytecode,
+            &program.constants,
+            &mut io::stdout().lock(),
+        )
+    })?;
 
-    bytecode_interpreter.interpret(&bytecode, &lowerer.constant_pool);
+    if options.time {
+        timings.report();
+    }
 
     Ok(())
 }
 
-fn compile_file(input_path: &String, run: bool, args: &[String]) -> Result<(), Error> {
-    let typed_ops = check_program(input_path)?;
-
-    let mut lowerer = Lowerer::new();
-    let bytecode = lowerer.lower(&typed_ops);
+fn compile_file(
+    input_path: &String,
+    run: bool,
+    args: &[String],
+    options: &Options,
+) -> Result<(), Error> {
+    let mut timings = Timings::new();
+    let program = front_end(input_path, options, &mut timings)?;
 
     // Derive output file names from input path
     let input_stem = Path::new(input_path)
@@ -122,65 +325,35 @@ fn compile_file(input_path: &String, run: bool, args: &[String]) -> Result<(), E
     //perform emitting in a block to close the asm file
     {
         let file = File::create(&asm_file)?;
-        let mut emitter = FasmEmitter::new(file);
+        let mut emitter = FasmEmitter::new(file, options.overflow_mode);
 
-        emitter.emit(&bytecode, &lowerer.constant_pool)?;
+        timings.time("emitting", || {
+            emitter.emit(&program.bytecode, &program.constants)
+        })?;
     }
 
     {
-        let output = Command::new("fasm")
-            .arg(&asm_file)
-            .output()
-            .expect("failed to execute fasm");
+        let output = timings
+            .time("assembling", || {
+                Command::new("fasm").arg(&asm_file).output()
+            })
+            .context("Failed to run fasm, is it installed and on the PATH?")?;
         // print!("{}", String::from_utf8(output.stdout)?);
         eprint!("{}", String::from_utf8(output.stderr)?);
     }
 
+    if options.time {
+        timings.report();
+    }
+
     if run {
         let output = Command::new(format!("./{}", exe_file))
             .args(args)
             .output()
-            .expect("failed to execute compiled program");
+            .with_context(|| format!("Failed to run compiled program `{}`", exe_file))?;
 
         print!("{}", String::from_utf8(output.stdout)?);
     }
 
     Ok(())
 }
-
-fn check_program(input_path: &String) -> Result<Vec<TypedOp>, Error> {
-    let input = fs::read_to_string(input_path)
-        .with_context(|| format!("Failed to read input file `{}`", input_path))?;
-
-    let mut lexer = Lexer::new();
-
-    let tokens: Vec<Token> = lexer.lex(&input);
-
-    if !lexer.diagnostics.is_empty() {
-        for diagnostic in lexer.diagnostics {
-            diagnostic.display_diagnostic(input_path, &input);
-        }
-        return Err(anyhow::anyhow!("Lexing failed"));
-    }
-
-    let mut parser = Parser::new();
-    let ops = parser.parse(&tokens);
-
-    if !parser.diagnostics.is_empty() {
-        for diagnostic in parser.diagnostics {
-            diagnostic.display_diagnostic(input_path, &input);
-        }
-        return Err(anyhow::anyhow!("Parsing failed"));
-    }
-
-    let mut type_checker = TypeChecker::new(true);
-    let typed_ops = type_checker.type_check(&ops);
-
-    if !type_checker.diagnostics.is_empty() {
-        for diagnostic in type_checker.diagnostics {
-            diagnostic.display_diagnostic(input_path, &input);
-        }
-        return Err(anyhow::anyhow!("Type checking failed"));
-    }
-    Ok(typed_ops)
-}
@@ -0,0 +1,254 @@
+use crate::lowerer::{ByteCodeInstruction, StackFrame};
+use std::collections::{HashMap, HashSet};
+
+pub fn optimize(program: &mut Vec<(String, StackFrame)>, constants: &[String]) {
+    eliminate_dead_functions(program, constants);
+
+    for (_, frame) in program.iter_mut() {
+        frame.instructions = fold_constants(&frame.instructions);
+    }
+
+    //Locals are shared between frames, so a store in one function may be loaded in another
+    let mut loads: HashMap<usize, usize> = HashMap::new();
+    for (_, frame) in program.iter() {
+        for instruction in &frame.instructions {
+            if let ByteCodeInstruction::Load { index } = instruction {
+                *loads.entry(*index).or_insert(0) += 1;
+            }
+        }
+    }
+
+    for (_, frame) in program.iter_mut() {
+        frame.instructions = remove_redundancies(&frame.instructions, &loads);
+    }
+}
+
+//Folds arithmetic and comparisons over two literals into a single push.
+// Only adjacent instructions are considered, so a literal can never be folded across a label
+pub fn fold_constants(instructions: &[ByteCodeInstruction]) -> Vec<ByteCodeInstruction> {
+    let mut folded: Vec<ByteCodeInstruction> = Vec::new();
+
+    for instruction in instructions {
+        if let [
+            ..,
+            ByteCodeInstruction::Push(b),
+            ByteCodeInstruction::Push(a),
+        ] = folded.as_slice()
+            && let Some(value) = fold_binary(instruction, *b as i64, *a as i64)
+        {
+            folded.truncate(folded.len() - 2);
+            folded.push(ByteCodeInstruction::Push(value as usize));
+            continue;
+        }
+        folded.push(instruction.clone());
+    }
+
+    folded
+}
+
+//Returns None if the instruction can't be folded, or if folding would hide a runtime error
+fn fold_binary(instruction: &ByteCodeInstruction, b: i64, a: i64) -> Option<i64> {
+    match instruction {
+        ByteCodeInstruction::Add => b.checked_add(a),
+        ByteCodeInstruction::Sub => b.checked_sub(a),
+        ByteCodeInstruction::Mul => b.checked_mul(a),
+        //checked_div and checked_rem return None on division by zero, leaving it to fail at runtime
+        ByteCodeInstruction::Div => b.checked_div(a),
+        ByteCodeInstruction::Mod => b.checked_rem(a),
+        ByteCodeInstruction::Gt => Some((b > a) as i64),
+        ByteCodeInstruction::Lt => Some((b < a) as i64),
+        ByteCodeInstruction::GtEq => Some((b >= a) as i64),
+        ByteCodeInstruction::LtEq => Some((b <= a) as i64),
+        ByteCodeInstruction::Eq => Some((b == a) as i64),
+        ByteCodeInstruction::Compare => Some(b.cmp(&a) as i64),
+        _ => None,
+    }
+}
+
+//Removes instruction pairs that have no overall effect on the program.
+// Every instruction that pushes a value is currently free of side effects, so popping
+// the value straight away is always safe to remove. If that stops being true, revisit this.
+pub fn remove_redundancies(
+    instructions: &[ByteCodeInstruction],
+    loads: &HashMap<usize, usize>,
+) -> Vec<ByteCodeInstruction> {
+    let mut optimized: Vec<ByteCodeInstruction> = Vec::new();
+
+    for instruction in instructions {
+        let redundant = match (optimized.last(), instruction) {
+            (Some(ByteCodeInstruction::Swap), ByteCodeInstruction::Swap) => true,
+            (Some(ByteCodeInstruction::Push(_)), ByteCodeInstruction::Pop) => true,
+            (Some(ByteCodeInstruction::Dup), ByteCodeInstruction::Pop) => true,
+            //The store can only go if this is the sole load of the local, otherwise later loads would see a stale value
+            (
+                Some(ByteCodeInstruction::Store { index: store }),
+                ByteCodeInstruction::Load { index: load },
+            ) => store == load && loads.get(load) == Some(&1),
+            _ => false,
+        };
+
+        if redundant {
+            optimized.pop();
+        } else {
+            optimized.push(instruction.clone());
+        }
+    }
+
+    optimized
+}
+
+//Removes every function that can't be reached from main through static calls or block pointers
+pub fn eliminate_dead_functions(program: &mut Vec<(String, StackFrame)>, constants: &[String]) {
+    let functions: HashMap<&String, &StackFrame> =
+        program.iter().map(|(name, frame)| (name, frame)).collect();
+
+    let main = "main".to_string();
+    let mut reachable: HashSet<String> = HashSet::new();
+    let mut to_visit = vec![&main];
+
+    while let Some(name) = to_visit.pop() {
+        if !reachable.insert(name.clone()) {
+            continue;
+        }
+
+        if let Some(frame) = functions.get(name) {
+            for instruction in &frame.instructions {
+                match instruction {
+                    ByteCodeInstruction::CallStatic { index }
+                    | ByteCodeInstruction::PushBlock { index } => {
+                        to_visit.push(&constants[*index]);
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    program.retain(|(name, _)| reachable.contains(name));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lowerer::ByteCodeInstruction::*;
+
+    //Counts the loads itself, the way `optimize` does for a whole program
+    fn peephole(instructions: &[ByteCodeInstruction]) -> Vec<ByteCodeInstruction> {
+        let mut loads = HashMap::new();
+        for instruction in instructions {
+            if let Load { index } = instruction {
+                *loads.entry(*index).or_insert(0) += 1;
+            }
+        }
+        remove_redundancies(instructions, &loads)
+    }
+
+    #[test]
+    fn removes_swap_swap() {
+        assert_eq!(
+            peephole(&[Push(1), Push(2), Swap, Swap, Add]),
+            [Push(1), Push(2), Add]
+        );
+    }
+
+    #[test]
+    fn removes_push_pop() {
+        assert_eq!(peephole(&[Push(1), Push(2), Pop, Print]), [Push(1), Print]);
+    }
+
+    #[test]
+    fn removes_dup_pop() {
+        assert_eq!(peephole(&[Push(1), Dup, Pop, Print]), [Push(1), Print]);
+    }
+
+    #[test]
+    fn removes_store_then_only_load() {
+        assert_eq!(
+            peephole(&[Push(1), Store { index: 0 }, Load { index: 0 }, Print]),
+            [Push(1), Print]
+        );
+    }
+
+    #[test]
+    fn keeps_store_loaded_again_later() {
+        let instructions = [
+            Push(1),
+            Store { index: 0 },
+            Load { index: 0 },
+            Load { index: 0 },
+            Add,
+        ];
+        assert_eq!(peephole(&instructions), instructions);
+    }
+
+    #[test]
+    fn keeps_store_then_load_of_another_local() {
+        let instructions = [Store { index: 0 }, Load { index: 1 }];
+        assert_eq!(peephole(&instructions), instructions);
+    }
+
+    #[test]
+    fn removes_pairs_exposed_by_an_earlier_removal() {
+        assert_eq!(peephole(&[Push(1), Dup, Swap, Swap, Pop]), [Push(1)]);
+    }
+
+    //The instructions `main` is lowered to once its constants are folded
+    fn folded_main(source: &str) -> Vec<ByteCodeInstruction> {
+        let program = crate::compile(source).unwrap();
+        let (_, main) = program
+            .bytecode
+            .iter()
+            .find(|(name, _)| name == "main")
+            .unwrap();
+        fold_constants(&main.instructions)
+    }
+
+    #[test]
+    fn folds_addition_of_literals() {
+        assert_eq!(folded_main("2 3 + print"), [Push(5), Print]);
+    }
+
+    #[test]
+    fn folds_nested_arithmetic() {
+        assert_eq!(folded_main("2 3 4 * + print"), [Push(14), Print]);
+    }
+
+    #[test]
+    fn leaves_division_by_zero_to_fail_at_runtime() {
+        assert_eq!(folded_main("1 0 / print"), [Push(1), Push(0), Div, Print]);
+        assert_eq!(folded_main("1 0 % print"), [Push(1), Push(0), Mod, Print]);
+    }
+
+    #[test]
+    fn leaves_overflowing_arithmetic_unfolded() {
+        let instructions = [Push(i64::MAX as usize), Push(1), Add];
+        assert_eq!(fold_constants(&instructions), instructions);
+    }
+
+    fn function_names(source: &str) -> Vec<String> {
+        let mut program = crate::compile(source).unwrap();
+        eliminate_dead_functions(&mut program.bytecode, &program.constants);
+        program.bytecode.into_iter().map(|(name, _)| name).collect()
+    }
+
+    #[test]
+    fn removes_unused_function() {
+        let names = function_names("fn unused (1 +)\nfn used (2 *)\n3 used print");
+        assert!(!names.contains(&"unused".to_string()));
+        assert!(names.contains(&"used".to_string()));
+        assert!(names.contains(&"main".to_string()));
+    }
+
+    #[test]
+    fn keeps_function_only_called_by_a_used_one() {
+        let names = function_names("fn inner (1 +)\nfn outer (inner)\n3 outer print");
+        assert!(names.contains(&"inner".to_string()));
+        assert!(names.contains(&"outer".to_string()));
+    }
+
+    #[test]
+    fn keeps_function_pushed_as_a_block() {
+        let names = function_names("[1 2] (1 +) map print");
+        assert_eq!(names.len(), 2);
+    }
+}
@@ -1,5 +1,6 @@
-use crate::diagnostic::Diagnostic;
+use crate::diagnostic::{Diagnostic, DiagnosticCode};
 use crate::lexer::{Span, Token, TokenKind};
+use crate::typechecker::TypeKind;
 use std::fmt::Display;
 
 #[derive(Debug, Clone)]
@@ -18,6 +19,7 @@ pub enum OpKind {
     GreaterThan,
     GreaterThanEquals,
     Equals,
+    Compare,
     Not,
     And,
     Or,
@@ -25,20 +27,36 @@ pub enum OpKind {
     Over,
     Pop,
     Rot,
+    Unrot,
     Swap,
+    TwoOver,
+    TwoSwap,
     Dup,
     Print,
     Concat,
     Head,
     Tail,
+    Uncons,
     Push,
+    Replicate,
+    Rotate,
     Do,
+    Curry,
     Filter,
+    Count,
     Fold,
+    FoldRight,
     Foreach,
+    EachWithIndex,
+    EachWhile,
     Len,
+    Digits,
+    Maximum,
+    Minimum,
+    Clamp,
     Map,
     DumpStack,
+    PrintStack,
     DefineFunction {
         identifier: Token,
         body: Box<Op>,
@@ -48,10 +66,21 @@ pub enum OpKind {
         body: Vec<Op>,
         else_body: Option<Vec<Op>>,
     },
+    //Runs the arm for the int on top of the stack, or the `else` arm if none of them match it
+    Match {
+        arms: Vec<(i64, Vec<Op>)>,
+        default: Vec<Op>,
+    },
+    When,
+    Unless,
     Binding {
-        bindings: Vec<Token>,
+        //Each name can be annotated with the type it expects, e.g. `let a: int { ... }`
+        bindings: Vec<(Token, Option<(TypeKind, Span)>)>,
         body: Box<Op>,
     },
+    Import(Token),
+    //The types expected on the stack from bottom to top
+    StackEffect(Vec<TypeKind>),
 }
 
 #[derive(Debug, Clone)]
@@ -95,27 +124,44 @@ impl Display for Op {
             OpKind::GreaterThan => write!(f, ">"),
             OpKind::GreaterThanEquals => write!(f, ">="),
             OpKind::Equals => write!(f, "="),
-            OpKind::Not => write!(f, "not"),
+            OpKind::Compare => write!(f, "compare"),
+            OpKind::Not => write!(f, "!"),
             OpKind::And => write!(f, "and"),
             OpKind::Or => write!(f, "or"),
             OpKind::Identity => write!(f, "."),
             OpKind::Over => write!(f, "over"),
             OpKind::Pop => write!(f, "pop"),
             OpKind::Rot => write!(f, "rot"),
+            OpKind::Unrot => write!(f, "-rot"),
             OpKind::Swap => write!(f, "swap"),
+            OpKind::TwoOver => write!(f, "2over"),
+            OpKind::TwoSwap => write!(f, "2swap"),
             OpKind::Dup => write!(f, "dup"),
             OpKind::Print => write!(f, "print"),
             OpKind::Concat => write!(f, "concat"),
             OpKind::Head => write!(f, "head"),
             OpKind::Tail => write!(f, "tail"),
+            OpKind::Uncons => write!(f, "uncons"),
             OpKind::Push => write!(f, "push"),
+            OpKind::Replicate => write!(f, "replicate"),
+            OpKind::Rotate => write!(f, "rotate"),
             OpKind::Do => write!(f, "do"),
+            OpKind::Curry => write!(f, "curry"),
             OpKind::Filter => write!(f, "filter"),
+            OpKind::Count => write!(f, "count"),
             OpKind::Fold => write!(f, "fold"),
+            OpKind::FoldRight => write!(f, "foldr"),
             OpKind::Foreach => write!(f, "foreach"),
+            OpKind::EachWithIndex => write!(f, "eachWithIndex"),
+            OpKind::EachWhile => write!(f, "eachWhile"),
             OpKind::Len => write!(f, "len"),
+            OpKind::Digits => write!(f, "digits"),
+            OpKind::Maximum => write!(f, "maximum"),
+            OpKind::Minimum => write!(f, "minimum"),
+            OpKind::Clamp => write!(f, "clamp"),
             OpKind::Map => write!(f, "map"),
             OpKind::DumpStack => write!(f, "???"),
+            OpKind::PrintStack => write!(f, ".s"),
             OpKind::DefineFunction { identifier, body } => {
                 if let TokenKind::Identifier(name) = &identifier.kind {
                     write!(f, "fn {} {}", name, body)
@@ -125,7 +171,24 @@ impl Display for Op {
             }
             OpKind::Identifier(name) => write!(f, "{}", name),
             OpKind::If { .. } => todo!(),
+            OpKind::Match { .. } => write!(f, "match"),
+            OpKind::When => write!(f, "when"),
+            OpKind::Unless => write!(f, "unless"),
             OpKind::Binding { .. } => write!(f, ""),
+            OpKind::Import(identifier) => {
+                if let TokenKind::Identifier(name) = &identifier.kind {
+                    write!(f, "import {}", name)
+                } else {
+                    unreachable!()
+                }
+            }
+            OpKind::StackEffect(types) => {
+                write!(f, "// effect:")?;
+                for type_kind in types {
+                    write!(f, " {}", type_kind)?;
+                }
+                Ok(())
+            }
         }
     }
 }
@@ -135,6 +198,12 @@ pub struct Parser {
     pub diagnostics: Vec<Diagnostic>,
 }
 
+impl Default for Parser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Parser {
     pub fn new() -> Parser {
         Parser {
@@ -143,10 +212,21 @@ impl Parser {
         }
     }
 
+    //Comments are only in the tokens for tools like the formatter, so they're dropped here and can appear anywhere
     pub fn parse(&mut self, tokens: &[Token]) -> Vec<Op> {
+        let tokens: Vec<Token> = tokens
+            .iter()
+            .filter(|token| !matches!(token.kind, TokenKind::Comment(_)))
+            .cloned()
+            .collect();
+
         let mut ops: Vec<Op> = vec![];
-        while let Some(op) = self.parse_op(tokens) {
-            ops.push(op);
+        while self.cursor < tokens.len() {
+            //parse_op always consumes at least one token, so on an error we can simply carry on
+            // from the next token and report any further errors in the same pass
+            if let Some(op) = self.parse_op(&tokens) {
+                ops.push(op);
+            }
         }
         ops
     }
@@ -205,6 +285,10 @@ impl Parser {
                 kind: OpKind::Equals,
                 span: token.span,
             }),
+            TokenKind::CompareKeyword => Some(Op {
+                kind: OpKind::Compare,
+                span: token.span,
+            }),
             TokenKind::Bang => Some(Op {
                 kind: OpKind::Not,
                 span: token.span,
@@ -226,6 +310,7 @@ impl Parser {
             }
             TokenKind::CloseParenthesis => {
                 self.diagnostics.push(Diagnostic::report_error(
+                    DiagnosticCode::UnexpectedToken,
                     "unexpected token ')'".to_string(),
                     token.span,
                 ));
@@ -237,16 +322,17 @@ impl Parser {
                 while self.cursor < tokens.len()
                     && tokens[self.cursor].kind != TokenKind::CloseSquare
                 {
-                    elements.push(self.parse_op(tokens)?);
+                    if let Some(element) = self.parse_op(tokens) {
+                        elements.push(element);
+                    }
                 }
 
                 if self.cursor >= tokens.len() {
-                    self.diagnostics.push(Diagnostic::report_error(
+                    self.diagnostics.push(Diagnostic::report_error_with_hint(
+                        DiagnosticCode::UnclosedDelimiter,
                         "List missing closing ']'".to_string(),
-                        Span::from_to(
-                            token.span,
-                            elements.last().map(|op| op.span).unwrap_or(token.span),
-                        ),
+                        self.current_span(tokens),
+                        ("list opened here".to_string(), token.span),
                     ));
                     return None;
                 }
@@ -261,6 +347,7 @@ impl Parser {
             }
             TokenKind::CloseSquare => {
                 self.diagnostics.push(Diagnostic::report_error(
+                    DiagnosticCode::UnexpectedToken,
                     "unexpected token ']'".to_string(),
                     token.span,
                 ));
@@ -273,10 +360,21 @@ impl Parser {
                 {
                     let identifier = self.expect_identifier(tokens, tokens[self.cursor].span)?;
 
-                    bindings.push(identifier);
+                    let annotation = match tokens.get(self.cursor) {
+                        Some(Token {
+                            kind: TokenKind::Colon,
+                            ..
+                        }) => {
+                            self.cursor += 1; //skip colon
+                            Some(self.parse_type(tokens)?)
+                        }
+                        _ => None,
+                    };
+
+                    bindings.push((identifier, annotation));
                 }
                 let open_curly =
-                    self.expect_token(&TokenKind::OpenCurly, tokens, tokens[self.cursor].span)?;
+                    self.expect_token(&TokenKind::OpenCurly, tokens, self.current_span(tokens))?;
                 let block = self.parse_block(&open_curly, tokens, TokenKind::CloseCurly)?;
                 let span = Span::from_to(token.span, block.span);
 
@@ -290,6 +388,7 @@ impl Parser {
             }
             TokenKind::OpenCurly => {
                 self.diagnostics.push(Diagnostic::report_error(
+                    DiagnosticCode::UnexpectedToken,
                     "unexpected token '{'".to_string(),
                     token.span,
                 ));
@@ -297,11 +396,38 @@ impl Parser {
             }
             TokenKind::CloseCurly => {
                 self.diagnostics.push(Diagnostic::report_error(
+                    DiagnosticCode::UnexpectedToken,
                     "unexpected token '}'".to_string(),
                     token.span,
                 ));
                 None
             }
+            TokenKind::StackEffect(tokens) => {
+                let mut parser = Parser::new();
+                let mut types = Vec::new();
+                while parser.cursor < tokens.len() {
+                    match parser.parse_type(&tokens) {
+                        Some((type_kind, _)) => types.push(type_kind),
+                        None => break,
+                    }
+                }
+                if !parser.diagnostics.is_empty() {
+                    self.diagnostics.extend(parser.diagnostics);
+                    return None;
+                }
+                Some(Op {
+                    kind: OpKind::StackEffect(types),
+                    span: token.span,
+                })
+            }
+            TokenKind::Colon => {
+                self.diagnostics.push(Diagnostic::report_error(
+                    DiagnosticCode::UnexpectedToken,
+                    "unexpected token ':'".to_string(),
+                    token.span,
+                ));
+                None
+            }
             TokenKind::DupKeyword => Some(Op {
                 kind: OpKind::Dup,
                 span: token.span,
@@ -322,6 +448,18 @@ impl Parser {
                 kind: OpKind::Rot,
                 span: token.span,
             }),
+            TokenKind::UnrotKeyword => Some(Op {
+                kind: OpKind::Unrot,
+                span: token.span,
+            }),
+            TokenKind::TwoOverKeyword => Some(Op {
+                kind: OpKind::TwoOver,
+                span: token.span,
+            }),
+            TokenKind::TwoSwapKeyword => Some(Op {
+                kind: OpKind::TwoSwap,
+                span: token.span,
+            }),
             TokenKind::PrintKeyword => Some(Op {
                 kind: OpKind::Print,
                 span: token.span,
@@ -334,6 +472,14 @@ impl Parser {
                 kind: OpKind::Push,
                 span: token.span,
             }),
+            TokenKind::ReplicateKeyword => Some(Op {
+                kind: OpKind::Replicate,
+                span: token.span,
+            }),
+            TokenKind::RotateKeyword => Some(Op {
+                kind: OpKind::Rotate,
+                span: token.span,
+            }),
             TokenKind::HeadKeyword => Some(Op {
                 kind: OpKind::Head,
                 span: token.span,
@@ -342,26 +488,66 @@ impl Parser {
                 kind: OpKind::Tail,
                 span: token.span,
             }),
+            TokenKind::UnconsKeyword => Some(Op {
+                kind: OpKind::Uncons,
+                span: token.span,
+            }),
             TokenKind::DoKeyword => Some(Op {
                 kind: OpKind::Do,
                 span: token.span,
             }),
+            TokenKind::CurryKeyword => Some(Op {
+                kind: OpKind::Curry,
+                span: token.span,
+            }),
             TokenKind::FilterKeyword => Some(Op {
                 kind: OpKind::Filter,
                 span: token.span,
             }),
+            TokenKind::CountKeyword => Some(Op {
+                kind: OpKind::Count,
+                span: token.span,
+            }),
             TokenKind::FoldKeyword => Some(Op {
                 kind: OpKind::Fold,
                 span: token.span,
             }),
+            TokenKind::FoldRightKeyword => Some(Op {
+                kind: OpKind::FoldRight,
+                span: token.span,
+            }),
             TokenKind::ForeachKeyword => Some(Op {
                 kind: OpKind::Foreach,
                 span: token.span,
             }),
+            TokenKind::EachWithIndexKeyword => Some(Op {
+                kind: OpKind::EachWithIndex,
+                span: token.span,
+            }),
+            TokenKind::EachWhileKeyword => Some(Op {
+                kind: OpKind::EachWhile,
+                span: token.span,
+            }),
             TokenKind::LenKeyword => Some(Op {
                 kind: OpKind::Len,
                 span: token.span,
             }),
+            TokenKind::DigitsKeyword => Some(Op {
+                kind: OpKind::Digits,
+                span: token.span,
+            }),
+            TokenKind::MaximumKeyword => Some(Op {
+                kind: OpKind::Maximum,
+                span: token.span,
+            }),
+            TokenKind::MinimumKeyword => Some(Op {
+                kind: OpKind::Minimum,
+                span: token.span,
+            }),
+            TokenKind::ClampKeyword => Some(Op {
+                kind: OpKind::Clamp,
+                span: token.span,
+            }),
             TokenKind::MapKeyword => Some(Op {
                 kind: OpKind::Map,
                 span: token.span,
@@ -370,6 +556,19 @@ impl Parser {
                 kind: OpKind::DumpStack,
                 span: token.span,
             }),
+            TokenKind::DotS => Some(Op {
+                kind: OpKind::PrintStack,
+                span: token.span,
+            }),
+            TokenKind::ImportKeyword => {
+                let identifier = self.expect_identifier(tokens, token.span)?;
+                let span = Span::from_to(token.span, identifier.span);
+
+                Some(Op {
+                    kind: OpKind::Import(identifier),
+                    span,
+                })
+            }
             TokenKind::FnKeyword => {
                 let identifier = self.expect_identifier(tokens, token.span)?;
                 let open_parenthesis =
@@ -393,7 +592,7 @@ impl Parser {
             }),
             TokenKind::IfKeyword => {
                 let open_curly =
-                    self.expect_token(&TokenKind::OpenCurly, tokens, tokens[self.cursor].span)?;
+                    self.expect_token(&TokenKind::OpenCurly, tokens, self.current_span(tokens))?;
                 let block = self.parse_block(&open_curly, tokens, TokenKind::CloseCurly)?;
 
                 let span = Span::from_to(token.span, block.span);
@@ -403,18 +602,17 @@ impl Parser {
                     if self.cursor < tokens.len()
                         && tokens[self.cursor].kind == TokenKind::ElseKeyword
                     {
-                        let else_keyword =
-                            self.expect_token(&TokenKind::ElseKeyword, tokens, token.span)?;
+                        self.expect_token(&TokenKind::ElseKeyword, tokens, token.span)?;
 
                         let open_curly = self.expect_token(
                             &TokenKind::OpenCurly,
                             tokens,
-                            tokens[self.cursor].span,
+                            self.current_span(tokens),
                         )?;
                         let else_block =
                             self.parse_block(&open_curly, tokens, TokenKind::CloseCurly)?;
 
-                        let span = Span::from_to(else_keyword.span, else_block.span);
+                        let span = Span::from_to(token.span, else_block.span);
                         if let OpKind::PushFunction(else_ops) = else_block.kind {
                             Some(Op {
                                 kind: OpKind::If {
@@ -439,13 +637,109 @@ impl Parser {
                     unreachable!()
                 }
             }
+            TokenKind::MatchKeyword => {
+                let open_parenthesis = self.expect_token(
+                    &TokenKind::OpenParenthesis,
+                    tokens,
+                    self.current_span(tokens),
+                )?;
+
+                let mut arms: Vec<(i64, Vec<Op>)> = Vec::new();
+                loop {
+                    let arm_span = self.current_span(tokens);
+                    match tokens.get(self.cursor).map(|token| &token.kind) {
+                        Some(TokenKind::IntLiteral(value)) => {
+                            let value = *value;
+                            self.cursor += 1;
+                            self.expect_token(
+                                &TokenKind::Arrow,
+                                tokens,
+                                self.current_span(tokens),
+                            )?;
+                            let body = self.parse_match_arm(tokens)?;
+                            if arms.iter().any(|(arm, _)| *arm == value) {
+                                self.diagnostics.push(Diagnostic::report_error(
+                                    DiagnosticCode::InvalidMatch,
+                                    format!("`match` already has an arm for `{}`", value),
+                                    arm_span,
+                                ));
+                            }
+                            arms.push((value, body));
+                        }
+                        Some(TokenKind::ElseKeyword) => {
+                            self.cursor += 1;
+                            let default = self.parse_match_arm(tokens)?;
+                            let close_parenthesis = self.expect_token(
+                                &TokenKind::CloseParenthesis,
+                                tokens,
+                                self.current_span(tokens),
+                            )?;
+
+                            break Some(Op {
+                                kind: OpKind::Match { arms, default },
+                                span: Span::from_to(token.span, close_parenthesis.span),
+                            });
+                        }
+                        Some(TokenKind::CloseParenthesis) | None => {
+                            self.cursor += 1;
+                            self.diagnostics.push(Diagnostic::report_error_with_hint(
+                                DiagnosticCode::InvalidMatch,
+                                "`match` missing an `else` arm".to_string(),
+                                arm_span,
+                                ("`match` opened here".to_string(), open_parenthesis.span),
+                            ));
+                            return None;
+                        }
+                        Some(kind) => {
+                            self.diagnostics.push(Diagnostic::report_error(
+                                DiagnosticCode::InvalidMatch,
+                                format!(
+                                    "Expected an int or `else` in `match` but got `{:?}`",
+                                    kind
+                                ),
+                                arm_span,
+                            ));
+                            //Skip the rest of the `match` so its arms aren't reported as well
+                            let mut depth = 1;
+                            while depth > 0 && self.cursor < tokens.len() {
+                                match tokens[self.cursor].kind {
+                                    TokenKind::OpenParenthesis => depth += 1,
+                                    TokenKind::CloseParenthesis => depth -= 1,
+                                    _ => {}
+                                }
+                                self.cursor += 1;
+                            }
+                            return None;
+                        }
+                    }
+                }
+            }
+            TokenKind::Arrow => {
+                self.diagnostics.push(Diagnostic::report_error(
+                    DiagnosticCode::UnexpectedToken,
+                    "`->` encountered outside of a `match`".to_string(),
+                    token.span,
+                ));
+                None
+            }
+            TokenKind::WhenKeyword => Some(Op {
+                kind: OpKind::When,
+                span: token.span,
+            }),
+            TokenKind::UnlessKeyword => Some(Op {
+                kind: OpKind::Unless,
+                span: token.span,
+            }),
             TokenKind::ElseKeyword => {
                 self.diagnostics.push(Diagnostic::report_error(
+                    DiagnosticCode::UnexpectedToken,
                     "`else` encountered without corresponding `if`".to_string(),
                     token.span,
                 ));
                 None
             }
+            //Filtered out by `parse`
+            TokenKind::Comment(_) => None,
             TokenKind::Error(_) => None,
         }
     }
@@ -459,16 +753,21 @@ impl Parser {
         let mut ops = Vec::new();
 
         while self.cursor < tokens.len() && tokens[self.cursor].kind != terminal {
-            ops.push(self.parse_op(tokens)?);
+            if let Some(op) = self.parse_op(tokens) {
+                ops.push(op);
+            }
         }
 
         if self.cursor >= tokens.len() {
-            self.diagnostics.push(Diagnostic::report_error(
-                "Block missing closing ')'".to_string(),
-                Span::from_to(
-                    open_paren.span,
-                    ops.last().map(|op| op.span).unwrap_or(open_paren.span),
-                ),
+            let closing = match terminal {
+                TokenKind::CloseCurly => '}',
+                _ => ')',
+            };
+            self.diagnostics.push(Diagnostic::report_error_with_hint(
+                DiagnosticCode::UnclosedDelimiter,
+                format!("Block missing closing '{}'", closing),
+                self.current_span(tokens),
+                ("block opened here".to_string(), open_paren.span),
             ));
             return None;
         }
@@ -482,6 +781,109 @@ impl Parser {
         })
     }
 
+    //Each arm of a `match` is a block in parentheses, e.g. the `(1 +)` in `0 -> (1 +)`
+    fn parse_match_arm(&mut self, tokens: &[Token]) -> Option<Vec<Op>> {
+        let open_parenthesis = self.expect_token(
+            &TokenKind::OpenParenthesis,
+            tokens,
+            self.current_span(tokens),
+        )?;
+        match self
+            .parse_block(&open_parenthesis, tokens, TokenKind::CloseParenthesis)?
+            .kind
+        {
+            OpKind::PushFunction(ops) => Some(ops),
+            _ => unreachable!(),
+        }
+    }
+
+    //At the end of the input there's no token under the cursor, so point at the last one instead
+    fn current_span(&self, tokens: &[Token]) -> Span {
+        tokens
+            .get(self.cursor)
+            .or(tokens.last())
+            .map(|token| token.span)
+            .unwrap_or(Span {
+                offset: 0,
+                length: 0,
+            })
+    }
+
+    //Types are written the way they're printed: `int`, `bool`, `[int]` for a list or `fn [int -- bool]` for a function.
+    // The tokens the type is made of are put back together as written and parsed by `TypeKind` itself, so a type
+    // reads the same in the source as it does in a diagnostic
+    fn parse_type(&mut self, tokens: &[Token]) -> Option<(TypeKind, Span)> {
+        let start = self.cursor;
+        //A single word, or everything up to the `]` that closes a list or function
+        let mut depth = 0;
+        while let Some(token) = tokens.get(self.cursor) {
+            match &token.kind {
+                TokenKind::Identifier(_) | TokenKind::Minus => {}
+                //The `[` after `fn` is part of the same type
+                TokenKind::FnKeyword => {
+                    self.cursor += 1;
+                    continue;
+                }
+                TokenKind::OpenSquare => depth += 1,
+                TokenKind::CloseSquare if depth > 0 => depth -= 1,
+                _ => break,
+            }
+            self.cursor += 1;
+            if depth == 0 {
+                break;
+            }
+        }
+
+        if self.cursor == start {
+            let span = self.current_span(tokens);
+            let message = match tokens.get(self.cursor) {
+                Some(token) => format!("Expected type but got `{:?}`", token.kind),
+                None => "Expected type but got nothing".to_string(),
+            };
+            self.diagnostics.push(Diagnostic::report_error(
+                DiagnosticCode::UnexpectedToken,
+                message,
+                span,
+            ));
+            self.cursor += 1;
+            return None;
+        }
+
+        let type_tokens = &tokens[start..self.cursor];
+        let span = Span::from_to(type_tokens[0].span, type_tokens[type_tokens.len() - 1].span);
+
+        //Only tokens that are next to each other in the source are joined, so `--` isn't read as two minuses
+        let mut text = String::new();
+        for (i, token) in type_tokens.iter().enumerate() {
+            if i > 0 {
+                let previous = type_tokens[i - 1].span;
+                if token.span.offset > previous.offset + previous.length {
+                    text.push(' ');
+                }
+            }
+            match &token.kind {
+                TokenKind::Identifier(name) => text.push_str(name),
+                TokenKind::FnKeyword => text.push_str("fn"),
+                TokenKind::OpenSquare => text.push('['),
+                TokenKind::CloseSquare => text.push(']'),
+                TokenKind::Minus => text.push('-'),
+                _ => unreachable!(),
+            }
+        }
+
+        match text.parse::<TypeKind>() {
+            Ok(type_kind) => Some((type_kind, span)),
+            Err(message) => {
+                self.diagnostics.push(Diagnostic::report_error(
+                    DiagnosticCode::UnexpectedToken,
+                    message,
+                    span,
+                ));
+                None
+            }
+        }
+    }
+
     fn expect_identifier(&mut self, tokens: &[Token], span: Span) -> Option<Token> {
         match tokens.get(self.cursor) {
             Some(token) => match &token.kind {
@@ -492,6 +894,7 @@ impl Parser {
                 _ => {
                     self.cursor += 1;
                     self.diagnostics.push(Diagnostic::report_error(
+                        DiagnosticCode::UnexpectedToken,
                         //TODO: implement display for tokenkind
                         format!("Expected identifier but got `{:?}`", token.kind),
                         span,
@@ -501,6 +904,7 @@ impl Parser {
             },
             None => {
                 self.diagnostics.push(Diagnostic::report_error(
+                    DiagnosticCode::UnexpectedToken,
                     "Expected identifier but got nothing".to_string(),
                     span,
                 ));
@@ -523,6 +927,7 @@ impl Parser {
                 _ => {
                     self.cursor += 1;
                     self.diagnostics.push(Diagnostic::report_error(
+                        DiagnosticCode::UnexpectedToken,
                         //TODO: implement display for tokenkind
                         format!("Expected '{:?}' but got `{:?}`", expected, token.kind),
                         span,
@@ -532,6 +937,7 @@ impl Parser {
             },
             None => {
                 self.diagnostics.push(Diagnostic::report_error(
+                    DiagnosticCode::UnexpectedToken,
                     //TODO: implement display for tokenkind
                     format!("Expected '{:?}' but got nothing", expected),
                     span,
@@ -541,3 +947,48 @@ impl Parser {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+
+    fn annotation(source: &str) -> Option<TypeKind> {
+        let tokens = Lexer::new().lex(source);
+        let mut parser = Parser::new();
+        let ops = parser.parse(&tokens);
+        match &ops.last()?.kind {
+            OpKind::Binding { bindings, .. } => {
+                bindings[0].1.clone().map(|(type_kind, _)| type_kind)
+            }
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn annotations_are_parsed_as_printed() {
+        let types = [
+            "int",
+            "[[bool]]",
+            "fn [ -- int]",
+            "fn [[int] fn [int -- bool] -- [bool]]",
+        ];
+        for text in types {
+            let source = format!("1 let a: {} {{ }}", text);
+            assert_eq!(
+                annotation(&source),
+                Some(text.parse().unwrap()),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn malformed_annotation_is_an_error() {
+        let tokens = Lexer::new().lex("1 let a: [int { }");
+        let mut parser = Parser::new();
+        parser.parse(&tokens);
+        assert!(!parser.diagnostics.is_empty());
+    }
+}
@@ -1,91 +1,28 @@
-use crate::bytecode_interpreter::BytecodeInterpreter;
-use crate::lexer::Lexer;
-use crate::lowerer::Lowerer;
-use crate::parser::Parser;
-use crate::typechecker::{TypeChecker, TypeKind};
+use dolang::bytecode_interpreter::BytecodeInterpreter;
+use dolang::color::Color;
+use dolang::diagnostic::Diagnostic;
+use dolang::lexer::Lexer;
+use dolang::lowerer::{Lowerer, OverflowMode};
+use dolang::parser::Parser;
+use dolang::typechecker::{TypeChecker, TypeKind};
 use std::io;
 use std::io::Write;
 use std::iter::zip;
 
-const GREEN: &str = "\x1b[32m";
-const GREY: &str = "\x1b[2m";
-const RESET: &str = "\x1b[0m";
-
 pub fn repl_mode() -> anyhow::Result<()> {
     let stdin = io::stdin();
 
-    let mut lexer = Lexer::new();
-    let mut parser = Parser::new();
-    let mut type_checker = TypeChecker::new(false);
-    let mut lowerer = Lowerer::new();
-    let mut interpreter = BytecodeInterpreter::new();
+    let mut repl = Repl::new();
 
     print_input_symbol()?;
     for line in stdin.lines() {
         //TODO: store all the lines so that the diagnostics are accurate
         if let Ok(line) = line {
-            match line.as_str() {
-                "" => {
-                    print_input_symbol()?;
-                    continue;
-                }
-                "quit" => return Ok(()),
-                _ => {
-                    let tokens = lexer.lex(&line);
-                    if !lexer.diagnostics.is_empty() {
-                        for diagnostic in &lexer.diagnostics {
-                            diagnostic.display_diagnostic("", &line);
-                        }
-                        lexer = Lexer::new();
-                        print_input_symbol()?;
-                        continue;
-                    }
-                    lexer = Lexer::new();
-
-                    let ops = parser.parse(&tokens);
-
-                    if !parser.diagnostics.is_empty() {
-                        for diagnostic in &parser.diagnostics {
-                            diagnostic.display_diagnostic("", &line);
-                        }
-                        parser = Parser::new();
-                        print_input_symbol()?;
-                        continue;
-                    }
-                    parser = Parser::new();
-
-                    //save the state of the stack before type checking, so we can rewind if there is an error
-                    let type_checker_checkpoint = type_checker.clone();
-                    let typed_ops = type_checker.type_check(&ops);
-
-                    if !&type_checker.diagnostics.is_empty() {
-                        for diagnostic in &type_checker.diagnostics {
-                            diagnostic.display_diagnostic("", &line);
-                        }
-                        //rewind
-                        type_checker = type_checker_checkpoint;
-                        print_input_symbol()?;
-                        continue;
-                    }
-
-                    let stack_frames = lowerer.lower(&typed_ops);
-
-                    interpreter.interpret(&stack_frames, &lowerer.constant_pool);
-
-                    if !&interpreter.stack.is_empty() {
-                        print!("{}", GREY);
-                        for (value, (type_kind, _)) in
-                            zip(&interpreter.stack, &type_checker.type_stack)
-                        {
-                            print_value(*value, type_kind, &interpreter, &type_checker);
-                            print!(" ")
-                        }
-                        println!("{}", RESET);
-                    }
-
-                    print_input_symbol()?;
-                }
+            if line == "quit" {
+                return Ok(());
             }
+            repl.run_line(&line, &mut io::stdout())?;
+            print_input_symbol()?;
         } else {
             panic!()
         }
@@ -93,41 +30,178 @@ pub fn repl_mode() -> anyhow::Result<()> {
     Ok(())
 }
 
-fn print_value(
-    value: usize,
-    type_kind: &TypeKind,
-    interpreter: &BytecodeInterpreter,
-    type_checker: &TypeChecker,
-) {
-    match type_kind {
-        TypeKind::Bool => print!("{} ", if value > 0 { "true" } else { "false" }),
-        TypeKind::Int => print!("{}", value),
-        TypeKind::List(el_type) => {
-            print!("[");
-            let length = interpreter.heap[value];
-            for i in 1..length + 1 {
-                print_value(
-                    interpreter.heap[value + i],
-                    el_type,
-                    interpreter,
-                    type_checker,
-                );
-                if i < length {
-                    print!(" ")
+//Everything that's kept from one line to the next
+struct Repl {
+    type_checker: TypeChecker,
+    lowerer: Lowerer,
+    interpreter: BytecodeInterpreter,
+}
+
+impl Repl {
+    fn new() -> Repl {
+        Repl {
+            type_checker: TypeChecker::new(false),
+            lowerer: Lowerer::new(),
+            interpreter: BytecodeInterpreter::new(OverflowMode::Wrapping),
+        }
+    }
+
+    //Anything the line prints and the stack it leaves are written to `out`, diagnostics go to stderr
+    fn run_line(&mut self, line: &str, out: &mut dyn Write) -> io::Result<()> {
+        match line {
+            "" => Ok(()),
+            ":stack" => {
+                if self.interpreter.stack.is_empty() {
+                    writeln!(out, "{}empty{}", Color::Grey, Color::Reset)
+                } else {
+                    writeln!(out, "{}", self.render_stack())
                 }
             }
-            print!("]");
+            _ => self.evaluate(line, out),
+        }
+    }
+
+    fn evaluate(&mut self, line: &str, out: &mut dyn Write) -> io::Result<()> {
+        let mut lexer = Lexer::new();
+        let tokens = lexer.lex(line);
+        if !lexer.diagnostics.is_empty() {
+            for diagnostic in &lexer.diagnostics {
+                diagnostic.display_diagnostic("", line);
+            }
+            return Ok(());
+        }
+
+        let mut parser = Parser::new();
+        let ops = parser.parse(&tokens);
+        if !parser.diagnostics.is_empty() {
+            for diagnostic in &parser.diagnostics {
+                diagnostic.display_diagnostic("", line);
+            }
+            return Ok(());
+        }
+
+        //save the state of the stack before type checking, so we can rewind if there is an error
+        let type_checker_checkpoint = self.type_checker.clone();
+        let typed_ops = self.type_checker.type_check(&ops);
+
+        for diagnostic in &self.type_checker.diagnostics {
+            diagnostic.display_diagnostic("", line);
+        }
+        if self
+            .type_checker
+            .diagnostics
+            .iter()
+            .any(Diagnostic::is_error)
+        {
+            //rewind
+            self.type_checker = type_checker_checkpoint;
+            return Ok(());
+        }
+        //Warnings have been shown and don't stop the line from running
+        self.type_checker.diagnostics.clear();
+
+        //The typed ops are lowered with their types already erased, so nothing refers to the old generics anymore
+        self.type_checker.compact_generics();
+        self.type_checker.forget_function_spans();
+
+        let stack_frames = self.lowerer.lower(&typed_ops);
+
+        //A runtime error stops the line part way through, so the stack goes back to how it was before it
+        let stack_checkpoint = self.interpreter.stack.clone();
+        if let Err(error) =
+            self.interpreter
+                .interpret(&stack_frames, &self.lowerer.constant_pool, out)
+        {
+            eprintln!("{}", error);
+            self.interpreter.stack = stack_checkpoint;
+            self.type_checker = type_checker_checkpoint;
+            return Ok(());
+        }
+
+        if !self.interpreter.stack.is_empty() {
+            writeln!(out, "{}", self.render_stack())?;
+        }
+        Ok(())
+    }
+
+    //Each value is followed by its type, e.g. `5: int [1 2]: [int]`
+    fn render_stack(&self) -> String {
+        let mut rendered = Color::Grey.to_string();
+        for (value, (type_kind, _)) in zip(&self.interpreter.stack, &self.type_checker.type_stack) {
+            rendered += &self.render_value(*value, type_kind);
+            match self.type_checker.erase(type_kind) {
+                Some(type_kind) => rendered += &format!(": {}", type_kind),
+                None => rendered += &format!(": {}", type_kind),
+            }
+            rendered += " ";
+        }
+        rendered + &Color::Reset.to_string()
+    }
+
+    fn render_value(&self, value: usize, type_kind: &TypeKind) -> String {
+        match type_kind {
+            TypeKind::Bool => (if value > 0 { "true" } else { "false" }).to_string(),
+            TypeKind::Int => (value as i64).to_string(),
+            TypeKind::List(el_type) => {
+                let length = self.interpreter.heap[value];
+                let elements: Vec<String> = (1..length + 1)
+                    .map(|i| self.render_value(self.interpreter.heap[value + i], el_type))
+                    .collect();
+                format!("[{}]", elements.join(" "))
+            }
+            TypeKind::Block { .. } => "fn".to_string(),
+            TypeKind::Generic(_) => match self.type_checker.erase(type_kind) {
+                None => "<?>".to_string(),
+                Some(type_kind) => self.render_value(value, &type_kind),
+            },
         }
-        TypeKind::Block { .. } => print!("fn"),
-        TypeKind::Generic(_) => match type_checker.erase(type_kind) {
-            None => print!("<?>"),
-            Some(type_kind) => print_value(value, &type_kind, interpreter, type_checker),
-        },
     }
 }
 
 fn print_input_symbol() -> anyhow::Result<()> {
-    print!("{}(≡) {}", GREEN, RESET);
+    print!("{}(≡) {}", Color::Green, Color::Reset);
     io::stdout().flush()?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dolang::color;
+
+    //What the REPL shows after each line
+    fn shown(lines: &[&str]) -> Vec<String> {
+        color::set_enabled(false);
+        let mut repl = Repl::new();
+        lines
+            .iter()
+            .map(|line| {
+                let mut out = Vec::new();
+                repl.run_line(line, &mut out).unwrap();
+                String::from_utf8(out).unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn stack_command_shows_the_stack_without_changing_it() {
+        assert_eq!(
+            shown(&[":stack", "1 2", ":stack", ":stack"]),
+            [
+                "empty\n",
+                "1: int 2: int \n",
+                "1: int 2: int \n",
+                "1: int 2: int \n"
+            ]
+        );
+    }
+
+    #[test]
+    fn each_value_left_by_a_line_is_shown_with_its_type() {
+        assert_eq!(shown(&["2 3 +"]), ["5: int \n"]);
+        assert_eq!(
+            shown(&["[1 2] true (len)"]),
+            ["[1 2]: [int] true: bool fn: fn [[<a>] -- int] \n"]
+        );
+    }
+}
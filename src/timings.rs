@@ -0,0 +1,39 @@
+use std::time::{Duration, Instant};
+
+//How long each phase of the compiler took, reported with --time
+pub struct Timings {
+    phases: Vec<(&'static str, Duration)>,
+}
+
+impl Default for Timings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Timings {
+    pub fn new() -> Timings {
+        Timings { phases: Vec::new() }
+    }
+
+    //Phases that run more than once, such as lexing each imported file, are added up
+    pub fn time<T>(&mut self, phase: &'static str, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        let elapsed = start.elapsed();
+        match self.phases.iter_mut().find(|(name, _)| *name == phase) {
+            Some((_, duration)) => *duration += elapsed,
+            None => self.phases.push((phase, elapsed)),
+        }
+        result
+    }
+
+    //Printed to stderr so the program's own output is unaffected
+    pub fn report(&self) {
+        for (phase, duration) in &self.phases {
+            eprintln!("{:<14}{:>12.3?}", phase, duration);
+        }
+        let total: Duration = self.phases.iter().map(|(_, duration)| *duration).sum();
+        eprintln!("{:<14}{:>12.3?}", "total", total);
+    }
+}
@@ -1,10 +1,11 @@
-use crate::diagnostic::Diagnostic;
-use crate::lexer::{Span, TokenKind};
+use crate::diagnostic::{Diagnostic, DiagnosticCode};
+use crate::lexer::{Span, Token, TokenKind};
 use crate::parser::{Op, OpKind};
 use std::cmp::PartialEq;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt::{Display, Formatter};
 use std::iter::zip;
+use std::str::FromStr;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum TypeKind {
@@ -22,7 +23,11 @@ pub enum TypeKind {
 pub enum TypedOpKind {
     PushBool(bool),
     PushInt(i64),
-    PushList(Vec<TypedOp>),
+    //`length` is how many values the ops leave on the stack, which isn't always one per op
+    PushList {
+        ops: Vec<TypedOp>,
+        length: usize,
+    },
     PushBlock(Vec<TypedOp>),
     Plus,
     Minus,
@@ -34,6 +39,7 @@ pub enum TypedOpKind {
     GreaterThan,
     GreaterThanEquals,
     Equals,
+    Compare,
     Not,
     And,
     Or,
@@ -41,20 +47,36 @@ pub enum TypedOpKind {
     Over,
     Pop,
     Rot,
+    Unrot,
     Swap,
+    TwoOver,
+    TwoSwap,
     Dup,
     Print,
     Concat,
     Head,
     Tail,
+    Uncons,
     Push,
+    Replicate,
+    Rotate,
     Do,
+    Curry,
     Filter,
+    Count,
     Fold,
+    FoldRight,
     Foreach,
+    EachWithIndex,
+    EachWhile,
     Len,
+    Digits,
+    Maximum,
+    Minimum,
+    Clamp,
     Map,
     DumpStack,
+    PrintStack,
     DefineFunction {
         name: String,
         block: Box<TypedOp>,
@@ -69,31 +91,195 @@ pub enum TypedOpKind {
         body: Vec<TypedOp>,
         else_body: Option<Vec<TypedOp>>,
     },
+    Match {
+        arms: Vec<(i64, Vec<TypedOp>)>,
+        default: Vec<TypedOp>,
+    },
+    When,
+    Unless,
 }
 
 impl Display for TypeKind {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.fmt_with_generics(f, &mut Vec::new())
+    }
+}
+
+impl TypeKind {
+    //Generics are named by order of appearance rather than by their internal index,
+    // so that `[<12> -- <12> <12>]` reads as `[<a> -- <a> <a>]`
+    fn fmt_with_generics(
+        &self,
+        f: &mut Formatter<'_>,
+        generics: &mut Vec<usize>,
+    ) -> std::fmt::Result {
         match self {
             TypeKind::Bool => write!(f, "bool"),
             TypeKind::Int => write!(f, "int"),
-            TypeKind::List(el_type) => write!(f, "[{}]", el_type),
-            TypeKind::Block { ins, outs } => write!(
-                f,
-                "fn [{} -- {}]",
-                ins.iter()
-                    .map(|el| el.to_string())
-                    .collect::<Vec<_>>()
-                    .join(" "),
-                outs.iter()
-                    .map(|el| el.to_string())
-                    .collect::<Vec<_>>()
-                    .join(" "),
-            ),
-            TypeKind::Generic(index) => write!(f, "<{}>", index),
+            TypeKind::List(el_type) => {
+                write!(f, "[")?;
+                el_type.fmt_with_generics(f, generics)?;
+                write!(f, "]")
+            }
+            TypeKind::Block { ins, outs } => {
+                write!(f, "fn ")?;
+                Self::fmt_signature(f, ins, outs, generics)
+            }
+            TypeKind::Generic(index) => {
+                let position = match generics.iter().position(|generic| generic == index) {
+                    Some(position) => position,
+                    None => {
+                        generics.push(*index);
+                        generics.len() - 1
+                    }
+                };
+                let letter = (b'a' + (position % 26) as u8) as char;
+                match position / 26 {
+                    0 => write!(f, "<{}>", letter),
+                    n => write!(f, "<{}{}>", letter, n),
+                }
+            }
+        }
+    }
+
+    fn fmt_signature(
+        f: &mut Formatter<'_>,
+        ins: &[TypeKind],
+        outs: &[TypeKind],
+        generics: &mut Vec<usize>,
+    ) -> std::fmt::Result {
+        write!(f, "[")?;
+        for (i, type_kind) in ins.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            type_kind.fmt_with_generics(f, generics)?;
+        }
+        write!(f, " -- ")?;
+        for (i, type_kind) in outs.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            type_kind.fmt_with_generics(f, generics)?;
+        }
+        write!(f, "]")
+    }
+}
+
+//Reads a type back from the way it's displayed, e.g. `fn [[<a>] -- int]`. Generics are numbered by their letter,
+// so `<a>` is the first one and `<a1>` the 27th, the same way they're named when displayed
+impl FromStr for TypeKind {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let spaced = text.replace('[', " [ ").replace(']', " ] ");
+        let words: Vec<&str> = spaced.split_whitespace().collect();
+
+        let mut cursor = 0;
+        let type_kind = TypeKind::parse_words(&words, &mut cursor)?;
+        match words.get(cursor) {
+            None => Ok(type_kind),
+            Some(word) => Err(format!("unexpected `{}` after `{}`", word, type_kind)),
+        }
+    }
+}
+
+impl TypeKind {
+    fn parse_words(words: &[&str], cursor: &mut usize) -> Result<TypeKind, String> {
+        let word = *words
+            .get(*cursor)
+            .ok_or("expected a type but got nothing")?;
+        *cursor += 1;
+
+        match word {
+            "int" => Ok(TypeKind::Int),
+            "bool" => Ok(TypeKind::Bool),
+            "[" => {
+                let element = Self::parse_words(words, cursor)?;
+                Self::expect_word("]", words, cursor)?;
+                Ok(TypeKind::List(Box::new(element)))
+            }
+            "fn" => {
+                Self::expect_word("[", words, cursor)?;
+                let mut ins = Vec::new();
+                while words.get(*cursor) != Some(&"--") {
+                    if words.get(*cursor) == Some(&"]") {
+                        return Err("expected `--` but got `]`".to_string());
+                    }
+                    ins.push(Self::parse_words(words, cursor)?);
+                }
+                *cursor += 1;
+                let mut outs = Vec::new();
+                while words.get(*cursor) != Some(&"]") {
+                    outs.push(Self::parse_words(words, cursor)?);
+                }
+                *cursor += 1;
+                Ok(TypeKind::Block { ins, outs })
+            }
+            generic if generic.starts_with('<') && generic.ends_with('>') && generic.len() > 2 => {
+                let name = &generic[1..generic.len() - 1];
+                let letter = name.chars().next().unwrap();
+                let number = match &name[letter.len_utf8()..] {
+                    "" => Some(0),
+                    number => number.parse::<usize>().ok(),
+                };
+                match (letter, number) {
+                    ('a'..='z', Some(number)) => Ok(TypeKind::Generic(
+                        number * 26 + (letter as usize - 'a' as usize),
+                    )),
+                    _ => Err(format!("`{}` is not a generic", generic)),
+                }
+            }
+            _ => Err(format!("unknown type `{}`", word)),
+        }
+    }
+
+    fn expect_word(expected: &str, words: &[&str], cursor: &mut usize) -> Result<(), String> {
+        match words.get(*cursor) {
+            Some(word) if *word == expected => {
+                *cursor += 1;
+                Ok(())
+            }
+            Some(word) => Err(format!("expected `{}` but got `{}`", expected, word)),
+            None => Err(format!("expected `{}` but got nothing", expected)),
         }
     }
 }
 
+//Renders a stack effect such as `[<a> -- <a> <a>]`, naming generics consistently across ins and outs
+pub fn display_signature(ins: &[TypeKind], outs: &[TypeKind]) -> String {
+    struct Signature<'a>(&'a [TypeKind], &'a [TypeKind]);
+
+    impl Display for Signature<'_> {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            TypeKind::fmt_signature(f, self.0, self.1, &mut Vec::new())
+        }
+    }
+
+    Signature(ins, outs).to_string()
+}
+
+//Takes everything on the stack, given from the bottom up, and puts it back, so the lowerer knows how to print each value
+fn print_stack(types: Vec<TypeKind>) -> TypedOp {
+    TypedOp {
+        kind: TypedOpKind::PrintStack,
+        ins: types.iter().rev().cloned().collect(),
+        outs: types,
+    }
+}
+
+fn display_stack(types: &[TypeKind]) -> String {
+    if types.is_empty() {
+        "empty".to_string()
+    } else {
+        let types: Vec<String> = types
+            .iter()
+            .map(|type_kind| type_kind.to_string())
+            .collect();
+        format!("`{}`", types.join(" "))
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct TypedOp {
     pub kind: TypedOpKind,
@@ -109,8 +295,17 @@ pub struct TypeChecker {
     erasures: Vec<Option<TypeKind>>,
     next_generic_index: usize,
     functions: HashMap<String, (Vec<TypeKind>, Vec<TypeKind>)>,
+    //Where each function was defined, to point at the original when it's defined again
+    function_spans: HashMap<String, Span>,
+    //Functions that have been called, to warn about the ones that never are
+    used_functions: HashSet<String>,
     bindings: HashMap<String, TypeKind>,
+    //Bindings in scope that have been referenced, to warn about the ones that never are
+    used_bindings: HashSet<String>,
     in_block: bool,
+    recovering: bool,
+    //What the block literal about to be checked will be run on, when it's passed straight to a combinator
+    block_input: Option<TypeKind>,
 }
 
 impl TypeChecker {
@@ -122,39 +317,46 @@ impl TypeChecker {
             erasures: Vec::new(),
             next_generic_index: 0,
             functions: HashMap::new(),
+            function_spans: HashMap::new(),
+            used_functions: HashSet::new(),
             bindings: HashMap::new(),
+            used_bindings: HashSet::new(),
             in_block: false,
+            recovering: false,
+            block_input: None,
         }
     }
 
-    pub fn type_check(&mut self, ops: &Vec<Op>) -> Vec<TypedOp> {
+    pub fn type_check(&mut self, ops: &[Op]) -> Vec<TypedOp> {
         let mut typed_ops = Vec::new();
-        for op in ops {
+        for (i, op) in ops.iter().enumerate() {
             // println!("op: {:?}", op.kind);
-            let typed_op = self.type_check_op(&op.kind, op.span);
+            let top = self
+                .type_stack
+                .last()
+                .map(|(type_kind, _)| type_kind.clone());
+            self.block_input = self.combinator_input(op, ops.get(i + 1), top);
+            let typed_op = self.type_check_and_resolve(op);
 
-            // println!("  ins: {:?}, outs: {:?}", ins, outs);
-            self.resolve_type_stack(op, &typed_op);
-
-            typed_ops.push(TypedOp {
-                kind: typed_op.kind,
-                ins: typed_op
-                    .ins
-                    .iter()
-                    .map(|t| self.erase(t).unwrap_or(t.clone()))
-                    .collect(),
-                outs: typed_op
-                    .outs
-                    .iter()
-                    .map(|t| self.erase(t).unwrap_or(t.clone()))
-                    .collect(),
-            });
+            typed_ops.push(typed_op);
         }
+        //Generics can be resolved by ops after the one that introduced them, so substitute once everything is checked
+        let typed_ops = typed_ops
+            .into_iter()
+            .map(|typed_op| self.substitute_op(typed_op))
+            .collect();
 
         if self.fail_on_non_empty_stack {
-            for (type_kind, span) in &self.type_stack {
+            //Whatever is left after an error was most likely pushed by the op that failed, which is already reported
+            let leftover: &[(TypeKind, Span)] = if self.recovering {
+                &[]
+            } else {
+                &self.type_stack
+            };
+            for (type_kind, span) in leftover {
                 let erased = self.erase(type_kind).unwrap_or(type_kind.clone());
                 self.diagnostics.push(Diagnostic::report_error(
+                    DiagnosticCode::NonEmptyStackAtEnd,
                     format!(
                         "type stack must be empty at the end of the program, but got {}",
                         erased
@@ -162,6 +364,20 @@ impl TypeChecker {
                     *span,
                 ))
             }
+
+            let mut unused: Vec<(&String, &Span)> = self
+                .function_spans
+                .iter()
+                .filter(|(name, _)| !self.used_functions.contains(*name))
+                .collect();
+            unused.sort_by_key(|(_, span)| span.offset);
+            for (name, span) in unused {
+                self.diagnostics.push(Diagnostic::report_warning(
+                    DiagnosticCode::Unused,
+                    format!("function `{}` is never used", name),
+                    *span,
+                ));
+            }
         }
         typed_ops
     }
@@ -193,6 +409,151 @@ impl TypeChecker {
         }
     }
 
+    //Spans only point into the input they were parsed from, so the REPL forgets them between lines,
+    // which also lets a later line redefine a function
+    pub fn forget_function_spans(&mut self) {
+        self.function_spans.clear();
+    }
+
+    //How many generics are being tracked, resolved or not
+    pub fn generic_count(&self) -> usize {
+        self.erasures.len()
+    }
+
+    //Substitutes every resolved generic and renumbers the unresolved ones from zero, discarding all other erasures.
+    // Only safe between top-level ops, when no typed op in flight still refers to the old generic indices
+    pub fn compact_generics(&mut self) {
+        let mut type_stack = std::mem::take(&mut self.type_stack);
+        for (type_kind, _) in type_stack.iter_mut() {
+            *type_kind = self.substitute(type_kind);
+        }
+
+        let mut functions = std::mem::take(&mut self.functions);
+        for (ins, outs) in functions.values_mut() {
+            *ins = ins.iter().map(|t| self.substitute(t)).collect();
+            *outs = outs.iter().map(|t| self.substitute(t)).collect();
+        }
+
+        let mut bindings = std::mem::take(&mut self.bindings);
+        for type_kind in bindings.values_mut() {
+            *type_kind = self.substitute(type_kind);
+        }
+
+        let mut renumbered = HashMap::new();
+        for (type_kind, _) in type_stack.iter_mut() {
+            Self::renumber(type_kind, &mut renumbered);
+        }
+        for (ins, outs) in functions.values_mut() {
+            for type_kind in ins.iter_mut().chain(outs.iter_mut()) {
+                Self::renumber(type_kind, &mut renumbered);
+            }
+        }
+        for type_kind in bindings.values_mut() {
+            Self::renumber(type_kind, &mut renumbered);
+        }
+
+        self.type_stack = type_stack;
+        self.functions = functions;
+        self.bindings = bindings;
+        self.erasures = vec![None; renumbered.len()];
+        self.next_generic_index = renumbered.len();
+    }
+
+    //Substitutes the types of an op and every op nested inside it, so the lowerer sees e.g. the list a `print` in a
+    // binding's body is given rather than the generic it was checked against
+    fn substitute_op(&self, typed_op: TypedOp) -> TypedOp {
+        let substitute_all = |ops: Vec<TypedOp>| -> Vec<TypedOp> {
+            ops.into_iter().map(|op| self.substitute_op(op)).collect()
+        };
+        let kind = match typed_op.kind {
+            TypedOpKind::PushList { ops, length } => TypedOpKind::PushList {
+                ops: substitute_all(ops),
+                length,
+            },
+            TypedOpKind::PushBlock(ops) => TypedOpKind::PushBlock(substitute_all(ops)),
+            TypedOpKind::DefineFunction { name, block } => TypedOpKind::DefineFunction {
+                name,
+                block: Box::new(self.substitute_op(*block)),
+            },
+            TypedOpKind::Binding { bindings, body } => TypedOpKind::Binding {
+                bindings,
+                body: substitute_all(body),
+            },
+            TypedOpKind::If { body, else_body } => TypedOpKind::If {
+                body: substitute_all(body),
+                else_body: else_body.map(substitute_all),
+            },
+            TypedOpKind::Match { arms, default } => TypedOpKind::Match {
+                arms: arms
+                    .into_iter()
+                    .map(|(value, body)| (value, substitute_all(body)))
+                    .collect(),
+                default: substitute_all(default),
+            },
+            kind => kind,
+        };
+        TypedOp {
+            kind,
+            ins: typed_op.ins.iter().map(|t| self.substitute(t)).collect(),
+            outs: typed_op.outs.iter().map(|t| self.substitute(t)).collect(),
+        }
+    }
+
+    //Like erase, but leaves unresolved generics in place rather than giving up on the whole type
+    fn substitute(&self, type_kind: &TypeKind) -> TypeKind {
+        match type_kind {
+            TypeKind::Generic(index) => match &self.erasures[*index] {
+                Some(erasure) => self.substitute(erasure),
+                None => type_kind.clone(),
+            },
+            TypeKind::List(element_type) => TypeKind::List(Box::new(self.substitute(element_type))),
+            TypeKind::Block { ins, outs } => TypeKind::Block {
+                ins: ins.iter().map(|t| self.substitute(t)).collect(),
+                outs: outs.iter().map(|t| self.substitute(t)).collect(),
+            },
+            _ => type_kind.clone(),
+        }
+    }
+
+    fn renumber(type_kind: &mut TypeKind, renumbered: &mut HashMap<usize, usize>) {
+        match type_kind {
+            TypeKind::Generic(index) => {
+                let next = renumbered.len();
+                *index = *renumbered.entry(*index).or_insert(next);
+            }
+            TypeKind::List(element_type) => Self::renumber(element_type, renumbered),
+            TypeKind::Block { ins, outs } => {
+                for type_kind in ins.iter_mut().chain(outs.iter_mut()) {
+                    Self::renumber(type_kind, renumbered);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    //Whichever arm of a `match` runs, it has to leave the stack the same way. Unlike the branches of an `if` the
+    // number of ins and outs has to match as well
+    fn expect_same_effect(
+        &mut self,
+        actual: &TypedOp,
+        expected: &TypedOp,
+        expected_span: Span,
+        actual_span: Span,
+    ) {
+        self.expect_type(
+            &TypeKind::Block {
+                ins: actual.ins.clone(),
+                outs: actual.outs.clone(),
+            },
+            &TypeKind::Block {
+                ins: expected.ins.clone(),
+                outs: expected.outs.clone(),
+            },
+            expected_span,
+            actual_span,
+        );
+    }
+
     fn expect_type(
         &mut self,
         actual: &TypeKind,
@@ -233,11 +594,15 @@ impl TypeChecker {
                 if actual_ins.len() != expected_ins.len()
                     || actual_outs.len() != expected_outs.len()
                 {
-                    let erased_actual = self.erase(original_actual).unwrap();
+                    let erased_actual = self
+                        .erase(original_actual)
+                        .unwrap_or(original_actual.clone());
                     self.diagnostics.push(Diagnostic::report_error_with_hint(
+                        DiagnosticCode::TypeMismatch,
                         format!(
                             "expected {} but got {}",
-                            self.erase(original_expected).unwrap(),
+                            self.erase(original_expected)
+                                .unwrap_or(original_expected.clone()),
                             erased_actual
                         ),
                         expected_span,
@@ -265,34 +630,6 @@ impl TypeChecker {
                     );
                 }
             }
-            //These generic/list pairings feel like they're patching over a mistake somewhere else...
-            (TypeKind::Generic(index), TypeKind::List(rhs)) => {
-                match self.erasures.get(*index).unwrap().clone() {
-                    None => self.erase_generic(index, expected),
-                    Some(type_kind) => self.expect_type_inner(
-                        &type_kind,
-                        rhs,
-                        original_actual,
-                        original_expected,
-                        expected_span,
-                        actual_span,
-                    ),
-                }
-            }
-            //These generic/list pairings feel like they're patching over a mistake somewhere else...
-            (TypeKind::List(lhs), TypeKind::Generic(index)) => {
-                match self.erasures.get(*index).unwrap().clone() {
-                    None => self.erase_generic(index, actual),
-                    Some(type_kind) => self.expect_type_inner(
-                        lhs,
-                        &type_kind,
-                        original_actual,
-                        original_expected,
-                        expected_span,
-                        actual_span,
-                    ),
-                }
-            }
             (TypeKind::Generic(index), expected) => {
                 match self.erasures.get(*index).unwrap().clone() {
                     None => self.erase_generic(index, expected),
@@ -331,11 +668,15 @@ impl TypeChecker {
             }
             _ => {
                 if self.erase(expected) != self.erase(actual) {
-                    let erased_actual = self.erase(original_actual).unwrap();
+                    let erased_actual = self
+                        .erase(original_actual)
+                        .unwrap_or(original_actual.clone());
                     self.diagnostics.push(Diagnostic::report_error_with_hint(
+                        DiagnosticCode::TypeMismatch,
                         format!(
                             "expected {} but got {}",
-                            self.erase(original_expected).unwrap(),
+                            self.erase(original_expected)
+                                .unwrap_or(original_expected.clone()),
                             erased_actual
                         ),
                         expected_span,
@@ -359,9 +700,25 @@ impl TypeChecker {
     }
 
     fn erase_generic(&mut self, index: &usize, erasure: &TypeKind) {
-        let erased = self.erase(erasure);
+        //Keep partially known types such as `[<a>]` rather than forgetting everything about them,
+        // unless that would make the generic refer to itself
+        let erased = self.substitute(erasure);
         // println!("generic: {:?} erased to {:?}", erasure, erased);
-        self.erasures[*index] = erased;
+        if !Self::contains_generic(&erased, *index) {
+            self.erasures[*index] = Some(erased);
+        }
+    }
+
+    fn contains_generic(type_kind: &TypeKind, index: usize) -> bool {
+        match type_kind {
+            TypeKind::Generic(other) => *other == index,
+            TypeKind::List(element_type) => Self::contains_generic(element_type, index),
+            TypeKind::Block { ins, outs } => ins
+                .iter()
+                .chain(outs.iter())
+                .any(|type_kind| Self::contains_generic(type_kind, index)),
+            _ => false,
+        }
     }
 
     fn type_check_op(&mut self, op_kind: &OpKind, span: Span) -> TypedOp {
@@ -377,70 +734,63 @@ impl TypeChecker {
                 outs: vec![TypeKind::Int],
             },
             OpKind::PushList(ops) => {
+                //The elements are whatever the ops leave behind, so they're checked like a block that takes nothing
+                let mut ins = Vec::new();
+                let mut outs = Vec::new();
                 let mut element_type: Option<TypeKind> = None;
 
-                let mut elements = Vec::new();
+                let was_in_block = self.in_block;
+                self.in_block = true;
+
+                let mut typed_ops = Vec::new();
                 for op in ops {
-                    let typed_op = self.type_check_op(&op.kind, span);
-                    if !typed_op.ins.is_empty() || typed_op.outs.len() != 1 {
+                    let before = outs.clone();
+                    let typed_op = self.type_check_block_ops(
+                        std::slice::from_ref(op),
+                        span,
+                        None,
+                        &mut ins,
+                        &mut outs,
+                    );
+                    if !ins.is_empty() {
                         self.diagnostics.push(Diagnostic::report_error(
+                            DiagnosticCode::TypeMismatch,
                             format!(
-                                "List elements must have the signature [ -- <a>], got [{} -- {}]",
-                                typed_op
-                                    .ins
-                                    .iter()
-                                    .map(|x| x.to_string())
-                                    .collect::<Vec<String>>()
-                                    .join(" "),
-                                typed_op
-                                    .outs
-                                    .iter()
-                                    .map(|x| x.to_string())
-                                    .collect::<Vec<String>>()
-                                    .join(" ")
+                                "List elements can only take values from earlier in the list, got {} after {}",
+                                display_signature(&typed_op[0].ins, &typed_op[0].outs),
+                                display_stack(&before)
                             ),
                             op.span,
                         ));
-                        //Return what we know just to keep parsing
-                        return match element_type {
-                            None => {
-                                let index = self.create_generic();
-                                TypedOp {
-                                    kind: TypedOpKind::PushList(vec![]),
-                                    ins: vec![],
-                                    outs: vec![TypeKind::List(Box::new(TypeKind::Generic(index)))],
+                        //Carry on as if it took nothing, so later elements are still checked
+                        ins.clear();
+                        outs = before;
+                    } else {
+                        //Check whatever this op pushed here so a mismatch points at the op that caused it
+                        let kept = before.len().saturating_sub(typed_op[0].ins.len());
+                        for out in &outs[kept.min(outs.len())..] {
+                            match &element_type {
+                                Some(type_kind) => {
+                                    self.expect_type(out, type_kind, op.span, op.span)
                                 }
+                                None => element_type = Some(out.clone()),
                             }
-                            Some(type_kind) => TypedOp {
-                                kind: TypedOpKind::PushList(vec![]),
-                                ins: vec![],
-                                outs: vec![TypeKind::List(Box::new(type_kind))],
-                            },
-                        };
+                        }
                     }
+                    typed_ops.extend(typed_op);
+                }
 
-                    let out = typed_op.outs.first().unwrap();
-                    match &element_type {
-                        Some(type_kind) => self.expect_type(out, type_kind, op.span, op.span),
-                        None => element_type = Some(out.clone()),
-                    }
+                self.in_block = was_in_block;
 
-                    elements.push(typed_op);
-                }
-                match element_type {
-                    None => {
-                        let index = self.create_generic();
-                        TypedOp {
-                            kind: TypedOpKind::PushList(elements),
-                            ins: vec![],
-                            outs: vec![TypeKind::List(Box::new(TypeKind::Generic(index)))],
-                        }
-                    }
-                    Some(type_kind) => TypedOp {
-                        kind: TypedOpKind::PushList(elements),
-                        ins: vec![],
-                        outs: vec![TypeKind::List(Box::new(type_kind))],
+                let element_type =
+                    element_type.unwrap_or_else(|| TypeKind::Generic(self.create_generic()));
+                TypedOp {
+                    kind: TypedOpKind::PushList {
+                        ops: typed_ops,
+                        length: outs.len(),
                     },
+                    ins: vec![],
+                    outs: vec![TypeKind::List(Box::new(element_type))],
                 }
             }
             OpKind::PushFunction(ops) => {
@@ -491,27 +841,44 @@ impl TypeChecker {
                     outs: vec![TypeKind::Bool],
                 }
             }
+            OpKind::Compare => {
+                let index = self.create_generic();
+                TypedOp {
+                    kind: TypedOpKind::Compare,
+                    ins: vec![TypeKind::Generic(index), TypeKind::Generic(index)],
+                    outs: vec![TypeKind::Int],
+                }
+            }
             OpKind::Not => TypedOp {
                 kind: TypedOpKind::Not,
                 ins: vec![TypeKind::Bool],
                 outs: vec![TypeKind::Bool],
             },
             OpKind::Identity => {
-                let index = self.create_generic();
+                //Outside a block `.` passes on whatever is already on top of the stack,
+                // only a block's signature needs a generic to stand in for its input
+                let type_kinds = if self.in_block {
+                    vec![TypeKind::Generic(self.create_generic())]
+                } else {
+                    self.peek_type(span)
+                        .map(|(type_kind, _)| type_kind)
+                        .into_iter()
+                        .collect()
+                };
                 TypedOp {
                     kind: TypedOpKind::Identity,
-                    ins: vec![TypeKind::Generic(index)],
-                    outs: vec![TypeKind::Generic(index)],
+                    ins: type_kinds.clone(),
+                    outs: type_kinds,
                 }
             }
             OpKind::And => TypedOp {
                 kind: TypedOpKind::And,
-                ins: vec![TypeKind::Bool],
+                ins: vec![TypeKind::Bool, TypeKind::Bool],
                 outs: vec![TypeKind::Bool],
             },
             OpKind::Or => TypedOp {
                 kind: TypedOpKind::Or,
-                ins: vec![TypeKind::Bool],
+                ins: vec![TypeKind::Bool, TypeKind::Bool],
                 outs: vec![TypeKind::Bool],
             },
             OpKind::Dup => {
@@ -526,17 +893,36 @@ impl TypeChecker {
                 let index = self.create_generic();
                 TypedOp {
                     kind: TypedOpKind::Len,
-                    ins: vec![TypeKind::Generic(index)],
+                    ins: vec![TypeKind::List(Box::new(TypeKind::Generic(index)))],
                     outs: vec![TypeKind::Int],
                 }
             }
+            OpKind::Digits => TypedOp {
+                kind: TypedOpKind::Digits,
+                ins: vec![TypeKind::Int],
+                outs: vec![TypeKind::List(Box::new(TypeKind::Int))],
+            },
+            OpKind::Maximum | OpKind::Minimum => TypedOp {
+                kind: match op_kind {
+                    OpKind::Maximum => TypedOpKind::Maximum,
+                    OpKind::Minimum => TypedOpKind::Minimum,
+                    _ => unreachable!(),
+                },
+                ins: vec![TypeKind::List(Box::new(TypeKind::Int))],
+                outs: vec![TypeKind::Int],
+            },
+            OpKind::Clamp => TypedOp {
+                kind: TypedOpKind::Clamp,
+                ins: vec![TypeKind::Int, TypeKind::Int, TypeKind::Int],
+                outs: vec![TypeKind::Int],
+            },
             OpKind::Over => {
                 let a = self.create_generic();
                 let b = self.create_generic();
 
                 TypedOp {
                     kind: TypedOpKind::Over,
-                    ins: vec![TypeKind::Generic(a), TypeKind::Generic(b)],
+                    ins: vec![TypeKind::Generic(b), TypeKind::Generic(a)],
                     outs: vec![
                         TypeKind::Generic(a),
                         TypeKind::Generic(b),
@@ -553,6 +939,8 @@ impl TypeChecker {
                     outs: vec![],
                 }
             }
+            //ins are listed from the top of the stack down while outs are pushed in order, so this reads
+            //`c b a -- b a c` which is the usual `a b c -- b c a`
             OpKind::Rot => {
                 let a = self.create_generic();
                 let b = self.create_generic();
@@ -571,6 +959,24 @@ impl TypeChecker {
                     ],
                 }
             }
+            OpKind::Unrot => {
+                let a = self.create_generic();
+                let b = self.create_generic();
+                let c = self.create_generic();
+                TypedOp {
+                    kind: TypedOpKind::Unrot,
+                    ins: vec![
+                        TypeKind::Generic(a),
+                        TypeKind::Generic(b),
+                        TypeKind::Generic(c),
+                    ],
+                    outs: vec![
+                        TypeKind::Generic(a),
+                        TypeKind::Generic(c),
+                        TypeKind::Generic(b),
+                    ],
+                }
+            }
             OpKind::Swap => {
                 let a = self.create_generic();
                 let b = self.create_generic();
@@ -580,6 +986,50 @@ impl TypeChecker {
                     outs: vec![TypeKind::Generic(a), TypeKind::Generic(b)],
                 }
             }
+            OpKind::TwoOver => {
+                let a = self.create_generic();
+                let b = self.create_generic();
+                let c = self.create_generic();
+                let d = self.create_generic();
+                TypedOp {
+                    kind: TypedOpKind::TwoOver,
+                    ins: vec![
+                        TypeKind::Generic(d),
+                        TypeKind::Generic(c),
+                        TypeKind::Generic(b),
+                        TypeKind::Generic(a),
+                    ],
+                    outs: vec![
+                        TypeKind::Generic(a),
+                        TypeKind::Generic(b),
+                        TypeKind::Generic(c),
+                        TypeKind::Generic(d),
+                        TypeKind::Generic(a),
+                        TypeKind::Generic(b),
+                    ],
+                }
+            }
+            OpKind::TwoSwap => {
+                let a = self.create_generic();
+                let b = self.create_generic();
+                let c = self.create_generic();
+                let d = self.create_generic();
+                TypedOp {
+                    kind: TypedOpKind::TwoSwap,
+                    ins: vec![
+                        TypeKind::Generic(d),
+                        TypeKind::Generic(c),
+                        TypeKind::Generic(b),
+                        TypeKind::Generic(a),
+                    ],
+                    outs: vec![
+                        TypeKind::Generic(c),
+                        TypeKind::Generic(d),
+                        TypeKind::Generic(a),
+                        TypeKind::Generic(b),
+                    ],
+                }
+            }
             OpKind::Print => {
                 let index = self.create_generic();
 
@@ -612,6 +1062,27 @@ impl TypeChecker {
                     outs: vec![TypeKind::List(Box::new(TypeKind::Generic(index)))],
                 }
             }
+            OpKind::Replicate => {
+                let index = self.create_generic();
+
+                TypedOp {
+                    kind: TypedOpKind::Replicate,
+                    ins: vec![TypeKind::Generic(index), TypeKind::Int],
+                    outs: vec![TypeKind::List(Box::new(TypeKind::Generic(index)))],
+                }
+            }
+            OpKind::Rotate => {
+                let index = self.create_generic();
+
+                TypedOp {
+                    kind: TypedOpKind::Rotate,
+                    ins: vec![
+                        TypeKind::List(Box::new(TypeKind::Generic(index))),
+                        TypeKind::Int,
+                    ],
+                    outs: vec![TypeKind::List(Box::new(TypeKind::Generic(index)))],
+                }
+            }
             OpKind::Head => {
                 let index = self.create_generic();
 
@@ -630,48 +1101,25 @@ impl TypeChecker {
                     outs: vec![TypeKind::List(Box::new(TypeKind::Generic(index)))],
                 }
             }
+            OpKind::Uncons => {
+                let index = self.create_generic();
+
+                TypedOp {
+                    kind: TypedOpKind::Uncons,
+                    ins: vec![TypeKind::List(Box::new(TypeKind::Generic(index)))],
+                    outs: vec![
+                        TypeKind::Generic(index),
+                        TypeKind::List(Box::new(TypeKind::Generic(index))),
+                    ],
+                }
+            }
             OpKind::Do => {
-                match self.peek_type(span) {
-                    Some((type_kind, type_span)) => {
-                        match &type_kind {
-                            TypeKind::Block { ins, outs } => {
-                                let mut do_ins = Vec::new();
-                                do_ins.push(type_kind.clone());
-                                do_ins.extend(ins.clone());
-                                TypedOp {
-                                    kind: TypedOpKind::Do,
-                                    ins: do_ins,
-                                    outs: outs.clone(),
-                                }
-                            }
-                            TypeKind::Generic(_) => todo!("`do` in lambdas"),
-                            _ => {
-                                self.diagnostics.push(Diagnostic::report_error_with_hint(
-                                    format!("expected a function but got {}", type_kind),
-                                    span,
-                                    (format!("{} introduced at", type_kind), type_span),
-                                ));
-                                //Return bogus with expected to continue checking normally
-                                TypedOp {
-                                    kind: TypedOpKind::Do,
-                                    ins: vec![type_kind],
-                                    outs: vec![],
-                                }
-                            }
-                        }
-                    }
-                    None => {
-                        self.diagnostics.push(Diagnostic::report_error(
-                            "expected a function but stack was empty".to_string(),
-                            span,
-                        ));
-                        TypedOp {
-                            kind: TypedOpKind::Do,
-                            ins: vec![],
-                            outs: vec![],
-                        }
-                    }
-                }
+                let top = self.peek_type(span);
+                self.type_check_do(top, span)
+            }
+            OpKind::Curry => {
+                let top = self.peek_type(span);
+                self.type_check_curry(top, span)
             }
             OpKind::Filter => {
                 let a = self.create_generic();
@@ -687,15 +1135,33 @@ impl TypeChecker {
                     outs: vec![TypeKind::List(Box::new(TypeKind::Generic(a)))],
                 }
             }
-            OpKind::Fold => {
+            OpKind::Count => {
+                let a = self.create_generic();
+                TypedOp {
+                    kind: TypedOpKind::Count,
+                    ins: vec![
+                        TypeKind::Block {
+                            ins: vec![TypeKind::Generic(a)],
+                            outs: vec![TypeKind::Bool],
+                        },
+                        TypeKind::List(Box::new(TypeKind::Generic(a))),
+                    ],
+                    outs: vec![TypeKind::Int],
+                }
+            }
+            OpKind::Fold | OpKind::FoldRight => {
                 let a = self.create_generic();
                 let b = self.create_generic();
                 TypedOp {
-                    kind: TypedOpKind::Fold,
+                    kind: match op_kind {
+                        OpKind::Fold => TypedOpKind::Fold,
+                        _ => TypedOpKind::FoldRight,
+                    },
                     ins: vec![
                         TypeKind::Generic(b),
+                        //The accumulator is on top of the element
                         TypeKind::Block {
-                            ins: vec![TypeKind::Generic(a), TypeKind::Generic(b)],
+                            ins: vec![TypeKind::Generic(b), TypeKind::Generic(a)],
                             outs: vec![TypeKind::Generic(b)],
                         },
                         TypeKind::List(Box::new(TypeKind::Generic(a))),
@@ -717,6 +1183,35 @@ impl TypeChecker {
                     outs: vec![],
                 }
             }
+            OpKind::EachWhile => {
+                let a = self.create_generic();
+                TypedOp {
+                    kind: TypedOpKind::EachWhile,
+                    ins: vec![
+                        TypeKind::Block {
+                            ins: vec![TypeKind::Generic(a)],
+                            outs: vec![TypeKind::Bool],
+                        },
+                        TypeKind::List(Box::new(TypeKind::Generic(a))),
+                    ],
+                    outs: vec![],
+                }
+            }
+            OpKind::EachWithIndex => {
+                let a = self.create_generic();
+                TypedOp {
+                    kind: TypedOpKind::EachWithIndex,
+                    ins: vec![
+                        //The element is on top of its index
+                        TypeKind::Block {
+                            ins: vec![TypeKind::Generic(a), TypeKind::Int],
+                            outs: vec![],
+                        },
+                        TypeKind::List(Box::new(TypeKind::Generic(a))),
+                    ],
+                    outs: vec![],
+                }
+            }
             OpKind::Map => {
                 let a = self.create_generic();
                 let b = self.create_generic();
@@ -747,13 +1242,29 @@ impl TypeChecker {
                     outs: vec![],
                 }
             }
+            OpKind::PrintStack => {
+                let types: Vec<TypeKind> = self
+                    .type_stack
+                    .iter()
+                    .map(|(type_kind, _)| type_kind.clone())
+                    .collect();
+                print_stack(types)
+            }
             OpKind::DefineFunction { identifier, body } => {
                 if let TokenKind::Identifier(name) = &identifier.kind {
+                    if let Some(previous_span) = self.function_spans.get(name) {
+                        self.diagnostics.push(Diagnostic::report_error_with_hint(
+                            DiagnosticCode::DuplicateDefinition,
+                            format!("function `{}` is already defined", name),
+                            identifier.span,
+                            ("previously defined here".to_string(), *previous_span),
+                        ));
+                    } else {
+                        self.function_spans.insert(name.clone(), identifier.span);
+                    }
                     if let OpKind::PushFunction(ops) = &body.kind {
-                        
-                        //TODO: prime the subchecker's stack with the function ins
-                        let mut sub_checker = self.clone();
-                        let block = sub_checker.type_check_block(ops, span);
+                        //Checked like any other block, so errors in the body are reported and its generics are ours
+                        let block = self.type_check_block(ops, span);
 
                         self.functions
                             .insert(name.clone(), (block.ins.clone(), block.outs.clone()));
@@ -774,21 +1285,73 @@ impl TypeChecker {
                     unreachable!()
                 }
             }
+            OpKind::StackEffect(expected) => {
+                //Only the top level's stack is tracked as a whole, a block only knows what it has pushed itself
+                if self.in_block {
+                    self.diagnostics.push(Diagnostic::report_error(
+                        DiagnosticCode::InvalidStackEffect,
+                        "stack effects can only be checked outside of blocks".to_string(),
+                        span,
+                    ));
+                } else {
+                    let actual: Vec<TypeKind> = self
+                        .type_stack
+                        .iter()
+                        .map(|(type_kind, _)| self.substitute(type_kind))
+                        .collect();
+                    if actual != *expected {
+                        self.diagnostics.push(Diagnostic::report_error(
+                            DiagnosticCode::InvalidStackEffect,
+                            format!(
+                                "expected the stack to be {} but it was {}",
+                                display_stack(expected),
+                                display_stack(&actual)
+                            ),
+                            span,
+                        ));
+                    }
+                }
+                TypedOp {
+                    kind: TypedOpKind::Identity,
+                    ins: vec![],
+                    outs: vec![],
+                }
+            }
+            OpKind::Import(_) => {
+                //Imports at the top of a file are resolved before type checking, so any left over are misplaced
+                self.diagnostics.push(Diagnostic::report_error(
+                    DiagnosticCode::InvalidImport,
+                    "imports are only allowed at the top level of a file".to_string(),
+                    span,
+                ));
+                TypedOp {
+                    kind: TypedOpKind::Identity,
+                    ins: vec![],
+                    outs: vec![],
+                }
+            }
             OpKind::Identifier(name) => {
                 match self.bindings.get(name) {
-                    Some(type_kind) => TypedOp {
-                        kind: TypedOpKind::Value(name.clone()),
-                        ins: vec![],
-                        outs: vec![type_kind.clone()],
-                    },
+                    Some(type_kind) => {
+                        self.used_bindings.insert(name.clone());
+                        TypedOp {
+                            kind: TypedOpKind::Value(name.clone()),
+                            ins: vec![],
+                            outs: vec![type_kind.clone()],
+                        }
+                    }
                     None => match self.functions.get(name) {
-                        Some((ins, outs)) => TypedOp {
-                            kind: TypedOpKind::Call(name.clone()),
-                            ins: ins.clone(),
-                            outs: outs.clone(),
-                        },
+                        Some((ins, outs)) => {
+                            self.used_functions.insert(name.clone());
+                            TypedOp {
+                                kind: TypedOpKind::Call(name.clone()),
+                                ins: ins.clone(),
+                                outs: outs.clone(),
+                            }
+                        }
                         None => {
                             self.diagnostics.push(Diagnostic::report_error(
+                                DiagnosticCode::UnknownIdentifier,
                                 format!("no such identifier `{}` in scope", name),
                                 span,
                             ));
@@ -805,14 +1368,6 @@ impl TypeChecker {
             OpKind::If { body, else_body } => match else_body {
                 Some(else_body) => {
                     let top = self.pop_type(span);
-                    let mut typed_ops = Vec::new();
-                    for op in body {
-                        let typed_op = self.type_check_op(&op.kind, op.span);
-
-                        self.resolve_type_stack(op, &typed_op);
-
-                        typed_ops.push(typed_op);
-                    }
                     let body_span = if body.is_empty() {
                         span
                     } else {
@@ -862,14 +1417,6 @@ impl TypeChecker {
                 }
                 None => {
                     let top = self.pop_type(span);
-                    let mut typed_ops = Vec::new();
-                    for op in body {
-                        let typed_op = self.type_check_op(&op.kind, op.span);
-
-                        self.resolve_type_stack(op, &typed_op);
-
-                        typed_ops.push(typed_op);
-                    }
                     let body_span = if body.is_empty() {
                         span
                     } else {
@@ -898,13 +1445,73 @@ impl TypeChecker {
                     }
                 }
             },
+            OpKind::Match { arms, default } => {
+                let arm_span = |ops: &[Op]| match (ops.first(), ops.last()) {
+                    (Some(first), Some(last)) => Span::from_to(first.span, last.span),
+                    _ => span,
+                };
+                let checked_default = self.type_check_block(default, arm_span(default));
+
+                let mut typed_arms = Vec::new();
+                for (value, body) in arms {
+                    let checked_body = self.type_check_block(body, arm_span(body));
+                    self.expect_same_effect(&checked_body, &checked_default, span, arm_span(body));
+                    if let TypedOpKind::PushBlock(typed_ops) = checked_body.kind {
+                        typed_arms.push((*value, typed_ops));
+                    } else {
+                        unreachable!()
+                    }
+                }
+
+                if let TypedOpKind::PushBlock(typed_default) = checked_default.kind {
+                    let mut ins = vec![TypeKind::Int];
+                    ins.extend(checked_default.ins);
+                    TypedOp {
+                        ins,
+                        outs: checked_default.outs,
+                        kind: TypedOpKind::Match {
+                            arms: typed_arms,
+                            default: typed_default,
+                        },
+                    }
+                } else {
+                    unreachable!()
+                }
+            }
+            OpKind::When | OpKind::Unless => TypedOp {
+                kind: match op_kind {
+                    OpKind::When => TypedOpKind::When,
+                    OpKind::Unless => TypedOpKind::Unless,
+                    _ => unreachable!(),
+                },
+                ins: vec![
+                    TypeKind::Block {
+                        ins: vec![],
+                        outs: vec![],
+                    },
+                    TypeKind::Bool,
+                ],
+                outs: vec![],
+            },
             OpKind::Binding { bindings, body } => {
                 let mut binding_identifiers = Vec::new();
-                for identifier in bindings.iter().rev() {
+                //Any bindings these shadow, to restore once the body is done with them
+                let mut shadowed = Vec::new();
+                for (identifier, annotation) in bindings.iter().rev() {
                     if let TokenKind::Identifier(name) = &identifier.kind {
                         match self.pop_type(identifier.span) {
-                            Some((type_kind, _)) => {
-                                self.bindings.insert(name.clone(), type_kind);
+                            Some((type_kind, type_span)) => {
+                                if let Some((expected, expected_span)) = annotation {
+                                    self.expect_type(
+                                        &type_kind,
+                                        expected,
+                                        *expected_span,
+                                        type_span,
+                                    );
+                                }
+                                let previous = self.bindings.insert(name.clone(), type_kind);
+                                let was_used = self.used_bindings.remove(name);
+                                shadowed.push((identifier.clone(), previous, was_used));
                                 binding_identifiers.push(name.clone());
                             }
                             None => break,
@@ -918,13 +1525,13 @@ impl TypeChecker {
                     let mut typed_ops = Vec::new();
 
                     for op in ops {
-                        let typed_op = self.type_check_op(&op.kind, op.span);
-
-                        self.resolve_type_stack(op, &typed_op);
+                        let typed_op = self.type_check_and_resolve(op);
 
                         typed_ops.push(typed_op);
                     }
 
+                    self.unbind(shadowed);
+
                     TypedOp {
                         ins: vec![],
                         outs: vec![],
@@ -940,21 +1547,154 @@ impl TypeChecker {
         }
     }
 
+    //Restores whatever a binding's names shadowed once its body is done with them, warning about any names the
+    // body never used. Names starting with `_` are expected to go unused
+    fn unbind(&mut self, shadowed: Vec<(Token, Option<TypeKind>, bool)>) {
+        for (identifier, previous, was_used) in shadowed.into_iter().rev() {
+            if let TokenKind::Identifier(name) = &identifier.kind {
+                if !self.used_bindings.remove(name) && !name.starts_with('_') {
+                    self.diagnostics.push(Diagnostic::report_warning(
+                        DiagnosticCode::Unused,
+                        format!("binding `{}` is never used", name),
+                        identifier.span,
+                    ));
+                }
+                match previous {
+                    Some(type_kind) => self.bindings.insert(name.clone(), type_kind),
+                    None => self.bindings.remove(name),
+                };
+                if was_used {
+                    self.used_bindings.insert(name.clone());
+                }
+            } else {
+                unreachable!()
+            }
+        }
+    }
+
+    //`curry` fills in the top input of the function on top of the stack with the value below it,
+    // so its signature is the function's with that input removed
+    fn type_check_curry(&mut self, top: Option<(TypeKind, Span)>, span: Span) -> TypedOp {
+        let bogus = |ins| TypedOp {
+            kind: TypedOpKind::Curry,
+            ins,
+            outs: vec![],
+        };
+        let Some((type_kind, type_span)) = top else {
+            self.diagnostics.push(Diagnostic::report_error(
+                DiagnosticCode::EmptyStack,
+                "expected a function but stack was empty".to_string(),
+                span,
+            ));
+            return bogus(vec![]);
+        };
+        let type_kind = self.erase(&type_kind).unwrap_or(type_kind);
+        match &type_kind {
+            TypeKind::Block { ins, outs } if !ins.is_empty() => TypedOp {
+                kind: TypedOpKind::Curry,
+                ins: vec![type_kind.clone(), ins[0].clone()],
+                outs: vec![TypeKind::Block {
+                    ins: ins[1..].to_vec(),
+                    outs: outs.clone(),
+                }],
+            },
+            TypeKind::Block { .. } => {
+                self.diagnostics.push(Diagnostic::report_error_with_hint(
+                    DiagnosticCode::TypeMismatch,
+                    format!("cannot curry {}, it takes no inputs", type_kind),
+                    span,
+                    ("function introduced at".to_string(), type_span),
+                ));
+                bogus(vec![type_kind])
+            }
+            TypeKind::Generic(_) => {
+                self.diagnostics.push(Diagnostic::report_error_with_hint(
+                    DiagnosticCode::UninferableFunction,
+                    "cannot infer the signature of the function passed to `curry`".to_string(),
+                    span,
+                    ("function introduced at".to_string(), type_span),
+                ));
+                bogus(vec![type_kind])
+            }
+            _ => {
+                self.diagnostics.push(Diagnostic::report_error_with_hint(
+                    DiagnosticCode::TypeMismatch,
+                    format!("expected a function but got {}", type_kind),
+                    span,
+                    (format!("{} introduced at", type_kind), type_span),
+                ));
+                bogus(vec![type_kind])
+            }
+        }
+    }
+
+    //The arity of `do` is taken from the signature of the function on top of the stack
+    fn type_check_do(&mut self, top: Option<(TypeKind, Span)>, span: Span) -> TypedOp {
+        match top {
+            Some((type_kind, type_span)) => {
+                let type_kind = self.erase(&type_kind).unwrap_or(type_kind);
+                match &type_kind {
+                    TypeKind::Block { ins, outs } => {
+                        let mut do_ins = Vec::new();
+                        do_ins.push(type_kind.clone());
+                        do_ins.extend(ins.clone());
+                        TypedOp {
+                            kind: TypedOpKind::Do,
+                            ins: do_ins,
+                            outs: outs.clone(),
+                        }
+                    }
+                    TypeKind::Generic(_) => {
+                        self.diagnostics.push(Diagnostic::report_error_with_hint(
+                            DiagnosticCode::UninferableFunction,
+                            "cannot infer the signature of the function passed to `do`".to_string(),
+                            span,
+                            ("function introduced at".to_string(), type_span),
+                        ));
+                        TypedOp {
+                            kind: TypedOpKind::Do,
+                            ins: vec![type_kind],
+                            outs: vec![],
+                        }
+                    }
+                    _ => {
+                        self.diagnostics.push(Diagnostic::report_error_with_hint(
+                            DiagnosticCode::TypeMismatch,
+                            format!("expected a function but got {}", type_kind),
+                            span,
+                            (format!("{} introduced at", type_kind), type_span),
+                        ));
+                        //Return bogus with expected to continue checking normally
+                        TypedOp {
+                            kind: TypedOpKind::Do,
+                            ins: vec![type_kind],
+                            outs: vec![],
+                        }
+                    }
+                }
+            }
+            None => {
+                self.diagnostics.push(Diagnostic::report_error(
+                    DiagnosticCode::EmptyStack,
+                    "expected a function but stack was empty".to_string(),
+                    span,
+                ));
+                TypedOp {
+                    kind: TypedOpKind::Do,
+                    ins: vec![],
+                    outs: vec![],
+                }
+            }
+        }
+    }
+
     fn check_op_symmetrical(&mut self, span: Span, op: &TypedOp) {
         if op.ins.len() != op.outs.len() {
             self.diagnostics.push(Diagnostic::report_error(
+                DiagnosticCode::TypeMismatch,
                 format!(
-                    "expected symmetrical function, but got [{} -- {}]",
-                    op.ins
-                        .iter()
-                        .map(|t| t.to_string())
-                        .collect::<Vec<String>>()
-                        .join(" "),
-                    op.outs
-                        .iter()
-                        .map(|t| t.to_string())
-                        .collect::<Vec<String>>()
-                        .join(" ")
+                    "expected symmetrical function, but got {}",
+                    display_signature(&op.ins, &op.outs)
                 ),
                 span,
             ))
@@ -963,18 +1703,10 @@ impl TypeChecker {
         for (block_in, block_out) in zip(&op.ins, &op.outs) {
             if block_in != block_out {
                 self.diagnostics.push(Diagnostic::report_error(
+                    DiagnosticCode::TypeMismatch,
                     format!(
-                        "expected symmetrical function, but got [{} -- {}]",
-                        op.ins
-                            .iter()
-                            .map(|t| t.to_string())
-                            .collect::<Vec<String>>()
-                            .join(" "),
-                        op.outs
-                            .iter()
-                            .map(|t| t.to_string())
-                            .collect::<Vec<String>>()
-                            .join(" ")
+                        "expected symmetrical function, but got {}",
+                        display_signature(&op.ins, &op.outs)
                     ),
                     span,
                 ))
@@ -982,11 +1714,122 @@ impl TypeChecker {
         }
     }
 
+    fn type_check_and_resolve(&mut self, op: &Op) -> TypedOp {
+        let error_count = self.error_count();
+        let typed_op = self.type_check_op(&op.kind, op.span);
+        self.resolve_type_stack(op, &typed_op);
+        self.check_comparable(&typed_op, op.span);
+
+        //An op that failed to check may not have pushed what the program expected it to, so any values missing
+        // from the stack are assumed rather than reported again, but only by the next op that takes any
+        if self.error_count() > error_count {
+            self.recovering = true;
+        } else if !typed_op.ins.is_empty() || self.type_stack.is_empty() {
+            self.recovering = false;
+        }
+        typed_op
+    }
+
+    //Passing a function that takes or leaves the wrong number of values to a combinator gets its own error, as
+    // the one from unifying the two signatures doesn't say which part is wrong. Returns whether it reported one
+    fn check_function_shape(
+        &mut self,
+        op: &Op,
+        actual: &TypeKind,
+        expected: &TypeKind,
+        actual_span: Span,
+    ) -> bool {
+        if !matches!(
+            op.kind,
+            OpKind::Map
+                | OpKind::Filter
+                | OpKind::Count
+                | OpKind::Fold
+                | OpKind::FoldRight
+                | OpKind::Foreach
+                | OpKind::EachWithIndex
+                | OpKind::EachWhile
+        ) {
+            return false;
+        }
+        let actual = self.erase(actual).unwrap_or(actual.clone());
+        match (&actual, expected) {
+            (
+                TypeKind::Block { ins, outs },
+                TypeKind::Block {
+                    ins: expected_ins,
+                    outs: expected_outs,
+                },
+            ) if ins.len() != expected_ins.len() || outs.len() != expected_outs.len() => {
+                let message = format!(
+                    "`{}` expects a function {} but got {}",
+                    op,
+                    display_signature(expected_ins, expected_outs),
+                    display_signature(ins, outs)
+                );
+                //Inside a block there's no span for the function, only for the op
+                if actual_span == op.span {
+                    self.diagnostics.push(Diagnostic::report_error(
+                        DiagnosticCode::TypeMismatch,
+                        message,
+                        actual_span,
+                    ));
+                } else {
+                    self.diagnostics.push(Diagnostic::report_error_with_hint(
+                        DiagnosticCode::TypeMismatch,
+                        message,
+                        actual_span,
+                        (format!("`{}` used here", op), op.span),
+                    ));
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    //Warnings don't mean anything went wrong with the types, so they don't count
+    fn error_count(&self) -> usize {
+        self.diagnostics
+            .iter()
+            .filter(|diagnostic| diagnostic.is_error())
+            .count()
+    }
+
+    //Functions are only known by their pointer at runtime, so comparing them would be meaningless
+    fn check_comparable(&mut self, typed_op: &TypedOp, span: Span) {
+        if let TypedOpKind::Equals | TypedOpKind::Compare = typed_op.kind
+            && let Some(type_kind) = typed_op.ins.first()
+            && self.contains_block(type_kind)
+        {
+            let erased = self.erase(type_kind).unwrap_or(type_kind.clone());
+            self.diagnostics.push(Diagnostic::report_error(
+                DiagnosticCode::TypeMismatch,
+                format!("functions are not comparable, but got {}", erased),
+                span,
+            ));
+        }
+    }
+
+    fn contains_block(&self, type_kind: &TypeKind) -> bool {
+        match self.erase(type_kind).unwrap_or(type_kind.clone()) {
+            TypeKind::Block { .. } => true,
+            TypeKind::List(element_type) => self.contains_block(&element_type),
+            _ => false,
+        }
+    }
+
     fn resolve_type_stack(&mut self, op: &Op, typed_op: &TypedOp) {
         for input in typed_op.ins.clone() {
             match self.type_stack.pop() {
-                Some((type_kind, span)) => self.expect_type(&type_kind, &input, op.span, span),
+                Some((type_kind, span)) => {
+                    if !self.check_function_shape(op, &type_kind, &input, span) {
+                        self.expect_type(&type_kind, &input, op.span, span)
+                    }
+                }
+                None if self.recovering => {}
                 None => self.diagnostics.push(Diagnostic::report_error(
+                    DiagnosticCode::EmptyStack,
                     format!("expected {} but stack was empty", input),
                     op.span,
                 )),
@@ -1002,11 +1845,12 @@ impl TypeChecker {
         match self.type_stack.pop() {
             Some((type_kind, span)) => Some((type_kind, span)),
             None => {
-                if self.in_block {
+                if self.in_block || self.recovering {
                     let generic = self.create_generic();
                     Some((TypeKind::Generic(generic), span))
                 } else {
                     self.diagnostics.push(Diagnostic::report_error(
+                        DiagnosticCode::EmptyStack,
                         "expected value but stack was empty".to_string(),
                         span,
                     ));
@@ -1020,11 +1864,12 @@ impl TypeChecker {
         match self.type_stack.last() {
             Some((type_kind, span)) => Some((type_kind.clone(), *span)),
             None => {
-                if self.in_block {
+                if self.in_block || self.recovering {
                     let generic = self.create_generic();
                     Some((TypeKind::Generic(generic), span))
                 } else {
                     self.diagnostics.push(Diagnostic::report_error(
+                        DiagnosticCode::EmptyStack,
                         "expected value but stack was empty".to_string(),
                         span,
                     ));
@@ -1034,31 +1879,15 @@ impl TypeChecker {
         }
     }
 
-    fn type_check_block(&mut self, ops: &Vec<Op>, span: Span) -> TypedOp {
-        let mut typed_ops = Vec::new();
+    fn type_check_block(&mut self, ops: &[Op], span: Span) -> TypedOp {
         let mut ins: Vec<TypeKind> = Vec::new();
         let mut outs: Vec<TypeKind> = Vec::new();
 
         let was_in_block = self.in_block;
         self.in_block = true;
 
-        for op in ops {
-            let typed_op = self.type_check_op(&op.kind, span);
-
-            // println!("  op: {:?}, op_ins: {:?}, op_outs: {:?}", op, op_ins, op_outs);
-
-            for op_in in &typed_op.ins {
-                match outs.pop() {
-                    Some(out) => self.expect_type(&out, op_in, op.span, span),
-                    None => ins.push(op_in.clone()),
-                }
-            }
-
-            for op_out in &typed_op.outs {
-                outs.push(op_out.clone());
-            }
-            typed_ops.push(typed_op);
-        }
+        let input = self.block_input.take();
+        let typed_ops = self.type_check_block_ops(ops, span, input, &mut ins, &mut outs);
 
         self.in_block = was_in_block;
 
@@ -1109,4 +1938,203 @@ impl TypeChecker {
             outs: erased_outs,
         }
     }
+
+    //A block literal followed by `map` or the like is run on each element of the list below it
+    fn combinator_input(
+        &self,
+        op: &Op,
+        next: Option<&Op>,
+        top: Option<TypeKind>,
+    ) -> Option<TypeKind> {
+        if !matches!(op.kind, OpKind::PushFunction(_)) {
+            return None;
+        }
+        if !matches!(
+            next?.kind,
+            OpKind::Map | OpKind::Filter | OpKind::Count | OpKind::Foreach | OpKind::EachWhile
+        ) {
+            return None;
+        }
+        let top = top?;
+        match self.erase(&top).unwrap_or(top) {
+            TypeKind::List(element_type) => Some(*element_type),
+            _ => None,
+        }
+    }
+
+    //Checks ops against the block's own stack, where anything taken from below the bottom of it becomes one of the block's ins.
+    // `input` is the first value the block will be given, if that's known
+    fn type_check_block_ops(
+        &mut self,
+        ops: &[Op],
+        span: Span,
+        input: Option<TypeKind>,
+        ins: &mut Vec<TypeKind>,
+        outs: &mut Vec<TypeKind>,
+    ) -> Vec<TypedOp> {
+        let mut typed_ops = Vec::new();
+
+        for (i, op) in ops.iter().enumerate() {
+            self.block_input = self.combinator_input(op, ops.get(i + 1), outs.last().cloned());
+            let typed_op = match (&op.kind, outs.last()) {
+                //`do` needs to see the function on top of the block's own stack, not the enclosing one
+                (OpKind::Do, Some(top)) => self.type_check_do(Some((top.clone(), span)), op.span),
+                (OpKind::Curry, Some(top)) => {
+                    self.type_check_curry(Some((top.clone(), span)), op.span)
+                }
+                //With nothing on the block's own stack the function comes from outside the block, so its signature
+                // isn't known unless it's the first value the block is given and a combinator says what that is.
+                // Otherwise annotating it with a binding such as `let f: fn [ -- int] { f do }` gets round that
+                (OpKind::Do | OpKind::Curry, None) => {
+                    let top = match &input {
+                        Some(input) if ins.is_empty() => input.clone(),
+                        _ => TypeKind::Generic(self.create_generic()),
+                    };
+                    let top = Some((top, span));
+                    match op.kind {
+                        OpKind::Do => self.type_check_do(top, op.span),
+                        _ => self.type_check_curry(top, op.span),
+                    }
+                }
+                //`.s` can only print what the block has pushed itself
+                (OpKind::PrintStack, _) => print_stack(outs.clone()),
+                //So do bindings, which also check their body against it
+                (OpKind::Binding { bindings, body }, _) => {
+                    self.type_check_block_binding(bindings, body, span, ins, outs)
+                }
+                _ => self.type_check_op(&op.kind, op.span),
+            };
+
+            // println!("  op: {:?}, op_ins: {:?}, op_outs: {:?}", op, op_ins, op_outs);
+
+            for op_in in &typed_op.ins {
+                match outs.pop() {
+                    Some(out) => {
+                        if !self.check_function_shape(op, &out, op_in, op.span) {
+                            self.expect_type(&out, op_in, op.span, span)
+                        }
+                    }
+                    None => ins.push(op_in.clone()),
+                }
+            }
+
+            self.check_comparable(&typed_op, op.span);
+
+            for op_out in &typed_op.outs {
+                outs.push(op_out.clone());
+            }
+            typed_ops.push(typed_op);
+        }
+
+        typed_ops
+    }
+
+    fn type_check_block_binding(
+        &mut self,
+        bindings: &[(Token, Option<(TypeKind, Span)>)],
+        body: &Op,
+        span: Span,
+        ins: &mut Vec<TypeKind>,
+        outs: &mut Vec<TypeKind>,
+    ) -> TypedOp {
+        let mut binding_identifiers = Vec::new();
+        let mut shadowed = Vec::new();
+        for (identifier, annotation) in bindings.iter().rev() {
+            if let TokenKind::Identifier(name) = &identifier.kind {
+                let type_kind = outs.pop().unwrap_or_else(|| {
+                    let generic = TypeKind::Generic(self.create_generic());
+                    ins.push(generic.clone());
+                    generic
+                });
+                if let Some((expected, expected_span)) = annotation {
+                    self.expect_type(&type_kind, expected, *expected_span, identifier.span);
+                }
+                let previous = self.bindings.insert(name.clone(), type_kind);
+                let was_used = self.used_bindings.remove(name);
+                shadowed.push((identifier.clone(), previous, was_used));
+                binding_identifiers.push(name.clone());
+            } else {
+                unreachable!()
+            }
+        }
+
+        let typed_ops = if let OpKind::PushFunction(ops) = &body.kind {
+            self.type_check_block_ops(ops, span, None, ins, outs)
+        } else {
+            unreachable!()
+        };
+
+        self.unbind(shadowed);
+
+        TypedOp {
+            ins: vec![],
+            outs: vec![],
+            kind: TypedOpKind::Binding {
+                bindings: binding_identifiers,
+                body: typed_ops,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Generics are numbered in order of appearance, which is how they're named when printed
+    fn assert_round_trips(type_kind: TypeKind) {
+        let text = type_kind.to_string();
+        assert_eq!(
+            text.parse::<TypeKind>(),
+            Ok(type_kind),
+            "parsing `{}`",
+            text
+        );
+    }
+
+    #[test]
+    fn simple_types_round_trip() {
+        assert_round_trips(TypeKind::Int);
+        assert_round_trips(TypeKind::Bool);
+        assert_round_trips(TypeKind::Generic(0));
+    }
+
+    #[test]
+    fn lists_round_trip() {
+        assert_round_trips(TypeKind::List(Box::new(TypeKind::Int)));
+        assert_round_trips(TypeKind::List(Box::new(TypeKind::List(Box::new(
+            TypeKind::Bool,
+        )))));
+    }
+
+    #[test]
+    fn functions_round_trip() {
+        assert_round_trips(TypeKind::Block {
+            ins: vec![],
+            outs: vec![],
+        });
+        assert_round_trips(TypeKind::Block {
+            ins: vec![TypeKind::Generic(0), TypeKind::Generic(1)],
+            outs: vec![TypeKind::Generic(1), TypeKind::Generic(0)],
+        });
+        assert_round_trips(TypeKind::Block {
+            ins: vec![
+                TypeKind::List(Box::new(TypeKind::Int)),
+                TypeKind::Block {
+                    ins: vec![TypeKind::Int],
+                    outs: vec![TypeKind::Bool],
+                },
+            ],
+            outs: vec![TypeKind::List(Box::new(TypeKind::Bool))],
+        });
+    }
+
+    #[test]
+    fn rejects_malformed_types() {
+        assert!("foo".parse::<TypeKind>().is_err());
+        assert!("[int".parse::<TypeKind>().is_err());
+        assert!("int bool".parse::<TypeKind>().is_err());
+        assert!("fn [int]".parse::<TypeKind>().is_err());
+        assert!("".parse::<TypeKind>().is_err());
+    }
 }
@@ -0,0 +1,237 @@
+use crate::lowerer::{ByteCodeInstruction, StackFrame};
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+
+//Bytecode the lowerer got wrong, caught before it's run or compiled rather than as a crash partway through
+#[derive(Debug)]
+pub struct VerifyError {
+    pub frame: String,
+    pub message: String,
+}
+
+impl Display for VerifyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "malformed bytecode in `{}`: {}",
+            self.frame, self.message
+        )
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+//Checks what can be checked without running anything: that jumps go to labels in the same frame, that calls and
+// blocks refer to functions in the program, that printed signatures are in the constant pool, and that the stack is
+// the same depth however a label is reached.
+// Loops can leave more on the stack each time round, so the jumps back to the top of one aren't compared
+pub fn verify(program: &[(String, StackFrame)], constants: &[String]) -> Result<(), VerifyError> {
+    let functions: HashSet<&String> = program.iter().map(|(name, _)| name).collect();
+
+    for (name, frame) in program {
+        verify_frame(name, frame, constants, &functions).map_err(|message| VerifyError {
+            frame: name.clone(),
+            message,
+        })?;
+    }
+
+    Ok(())
+}
+
+fn verify_frame(
+    name: &str,
+    frame: &StackFrame,
+    constants: &[String],
+    functions: &HashSet<&String>,
+) -> Result<(), String> {
+    let instructions = &frame.instructions;
+
+    let mut labels = HashSet::new();
+    for instruction in instructions {
+        if let ByteCodeInstruction::Label(label) = instruction
+            && !labels.insert(*label)
+        {
+            return Err(format!("label {} is defined more than once", label));
+        }
+    }
+
+    //The depth each label is jumped to with, and the labels already passed, which can only be jumped back to
+    let mut label_depths: HashMap<usize, i64> = HashMap::new();
+    let mut passed = HashSet::new();
+    //None once the depth depends on something only known at runtime, or nothing falls through to the next instruction
+    let mut depth: Option<i64> = Some(0);
+
+    for (i, instruction) in instructions.iter().enumerate() {
+        match instruction {
+            ByteCodeInstruction::Jump { label } | ByteCodeInstruction::JumpIfFalse { label }
+                if !labels.contains(label) =>
+            {
+                return Err(format!("jump to undefined label {}", label));
+            }
+            ByteCodeInstruction::CallStatic { index }
+            | ByteCodeInstruction::PushBlock { index } => match constants.get(*index) {
+                Some(function) if functions.contains(function) => {}
+                Some(function) => return Err(format!("`{}` is not a function", function)),
+                None => return Err(format!("no constant at index {}", index)),
+            },
+            ByteCodeInstruction::PrintFunction { index } if constants.get(*index).is_none() => {
+                return Err(format!("no constant at index {}", index));
+            }
+            _ => {}
+        }
+
+        if let ByteCodeInstruction::Label(label) = instruction {
+            passed.insert(*label);
+            depth = match (depth, label_depths.get(label)) {
+                (Some(depth), Some(jumped)) if depth != *jumped => {
+                    return Err(format!(
+                        "the stack is {} deep falling through to label {} but {} deep jumping to it",
+                        depth, label, jumped
+                    ));
+                }
+                (Some(depth), _) => Some(depth),
+                (None, jumped) => jumped.copied(),
+            };
+            continue;
+        }
+
+        let effect = match (instruction.stack_effect(), instruction) {
+            (Some(effect), _) => Some(effect),
+            (None, ByteCodeInstruction::NewList) => {
+                match i.checked_sub(1).map(|i| &instructions[i]) {
+                    Some(ByteCodeInstruction::Push(length)) => Some((*length + 1, 1)),
+                    _ => None,
+                }
+            }
+            (None, _) => None,
+        };
+        depth = match (depth, effect) {
+            (Some(depth), Some((pops, pushes))) => {
+                //Functions are called with their arguments already on the stack, so only `main` starts out empty
+                if name == "main" && depth < pops as i64 {
+                    return Err(format!(
+                        "`{:?}` pops {} values but the stack only has {}",
+                        instruction, pops, depth
+                    ));
+                }
+                Some(depth - pops as i64 + pushes as i64)
+            }
+            _ => None,
+        };
+
+        match instruction {
+            ByteCodeInstruction::Jump { label } | ByteCodeInstruction::JumpIfFalse { label }
+                if !passed.contains(label) =>
+            {
+                if let Some(depth) = depth {
+                    match label_depths.get(label) {
+                        Some(jumped) if *jumped != depth => {
+                            return Err(format!(
+                                "label {} is jumped to with the stack both {} and {} deep",
+                                label, jumped, depth
+                            ));
+                        }
+                        _ => {
+                            label_depths.insert(*label, depth);
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+        if let ByteCodeInstruction::Jump { .. } | ByteCodeInstruction::Return = instruction {
+            depth = None;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ByteCodeInstruction::*;
+
+    //A program with just a `main` made of the given instructions
+    fn verify_main(
+        instructions: Vec<ByteCodeInstruction>,
+        constants: &[&str],
+    ) -> Result<(), String> {
+        let program = vec![(
+            "main".to_string(),
+            StackFrame {
+                instructions,
+                max_locals: 0,
+                max_stack_depth: None,
+            },
+        )];
+        let constants: Vec<String> = constants.iter().map(|name| name.to_string()).collect();
+        verify(&program, &constants).map_err(|error| error.message)
+    }
+
+    #[test]
+    fn accepts_well_formed_bytecode() {
+        let instructions = vec![
+            Push(1),
+            JumpIfFalse { label: 0 },
+            Push(2),
+            Print,
+            Label(0),
+            Push(3),
+            Print,
+        ];
+        assert_eq!(verify_main(instructions, &[]), Ok(()));
+    }
+
+    #[test]
+    fn rejects_jump_to_undefined_label() {
+        let result = verify_main(vec![Jump { label: 3 }], &[]);
+        assert_eq!(result, Err("jump to undefined label 3".to_string()));
+    }
+
+    #[test]
+    fn rejects_label_defined_twice() {
+        let result = verify_main(vec![Label(0), Label(0)], &[]);
+        assert_eq!(result, Err("label 0 is defined more than once".to_string()));
+    }
+
+    #[test]
+    fn rejects_call_to_a_constant_that_is_not_a_function() {
+        let result = verify_main(vec![CallStatic { index: 0 }], &["missing"]);
+        assert_eq!(result, Err("`missing` is not a function".to_string()));
+    }
+
+    #[test]
+    fn rejects_block_outside_the_constant_pool() {
+        let result = verify_main(vec![PushBlock { index: 2 }, Pop], &["main"]);
+        assert_eq!(result, Err("no constant at index 2".to_string()));
+    }
+
+    #[test]
+    fn rejects_printed_signature_outside_the_constant_pool() {
+        let result = verify_main(vec![Push(0), PrintFunction { index: 0 }], &[]);
+        assert_eq!(result, Err("no constant at index 0".to_string()));
+    }
+
+    #[test]
+    fn rejects_main_popping_an_empty_stack() {
+        let result = verify_main(vec![Push(1), Add], &[]);
+        assert!(
+            result
+                .unwrap_err()
+                .contains("pops 2 values but the stack only has 1")
+        );
+    }
+
+    #[test]
+    fn rejects_label_reached_with_different_depths() {
+        let instructions = vec![Push(1), Push(1), JumpIfFalse { label: 0 }, Pop, Label(0)];
+        assert_eq!(
+            verify_main(instructions, &[]),
+            Err(
+                "the stack is 0 deep falling through to label 0 but 1 deep jumping to it"
+                    .to_string()
+            )
+        );
+    }
+}
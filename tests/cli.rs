@@ -0,0 +1,103 @@
+use std::process::{Command, Output};
+
+//Runs the `do` binary from the crate root, so paths into resources resolve
+fn run_do(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_do"))
+        .args(args)
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .output()
+        .unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8(output.stderr.clone()).unwrap()
+}
+
+#[test]
+fn time_reports_each_phase() {
+    let output = run_do(&["--time", "-i", "resources/arithmetic.do"]);
+    assert!(output.status.success());
+    let phases: Vec<String> = stderr(&output)
+        .lines()
+        .map(|line| line.rsplit_once(' ').unwrap().0.trim().to_string())
+        .collect();
+    assert_eq!(
+        phases,
+        [
+            "lexing",
+            "parsing",
+            "type checking",
+            "lowering",
+            "verifying",
+            "interpreting",
+            "total"
+        ]
+    );
+}
+
+#[test]
+fn nothing_is_timed_without_the_flag() {
+    let output = run_do(&["-i", "resources/arithmetic.do"]);
+    assert!(output.status.success());
+    assert_eq!(stderr(&output), "");
+}
+
+#[test]
+fn help_lists_every_mode() {
+    let output = run_do(&["--help"]);
+    assert!(output.status.success());
+    let help = stdout(&output);
+    for mode in [
+        "Start the REPL",
+        "-i file.do",
+        "do file.do",
+        "-r file.do",
+        "-b file.dob",
+        "--check",
+        "--fmt",
+    ] {
+        assert!(help.contains(mode), "`{}` missing from:\n{}", mode, help);
+    }
+}
+
+#[test]
+fn version_is_the_crate_version() {
+    let output = run_do(&["--version"]);
+    assert!(output.status.success());
+    assert_eq!(
+        stdout(&output),
+        format!("do {}\n", env!("CARGO_PKG_VERSION"))
+    );
+}
+
+#[test]
+fn trace_shows_each_instruction_and_the_stack_it_leaves() {
+    let output = run_do(&["--trace", "-i", "resources/trace.do"]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "3\n");
+    let trace: Vec<Vec<String>> = stderr(&output)
+        .lines()
+        .map(|line| {
+            let (pc, rest) = line.trim_start().split_once(' ').unwrap();
+            let (instruction, stack) = rest.trim_start().split_once(' ').unwrap();
+            vec![
+                pc.to_string(),
+                instruction.to_string(),
+                stack.trim().to_string(),
+            ]
+        })
+        .collect();
+    assert_eq!(
+        trace,
+        [
+            ["0", "Push(1)", "[1]"],
+            ["2", "Push(2)", "[1, 2]"],
+            ["4", "Add", "[3]"],
+            ["5", "Print", "[]"]
+        ]
+    );
+}
@@ -0,0 +1,437 @@
+use dolang::diagnostic::SourceMap;
+use dolang::diagnostic::{Diagnostic, DiagnosticCode};
+use dolang::formatter;
+use dolang::lexer::{Lexer, Span, Token, TokenKind};
+use dolang::parser::Parser;
+use dolang::timings::Timings;
+use dolang::typechecker::TypeChecker;
+use dolang::{CompileError, CompileOptions, RunError};
+use std::path::Path;
+
+fn run(source: &str) -> Result<String, RunError> {
+    let mut out = Vec::new();
+    dolang::run(source, &mut out)?;
+    Ok(String::from_utf8(out).unwrap())
+}
+
+#[test]
+fn output_is_written_to_the_writer() {
+    assert_eq!(
+        run("1 2 + print [1 2] print true print").unwrap(),
+        "3\n[1 2]\ntrue\n"
+    );
+}
+
+#[test]
+fn compile_errors_are_returned() {
+    match run("1 true + print") {
+        Err(RunError::Compile(diagnostics)) => assert!(!diagnostics.is_empty()),
+        _ => panic!("expected a type error"),
+    }
+}
+
+#[test]
+fn runtime_errors_are_returned() {
+    match run("1 print 1 0 / print") {
+        Err(RunError::Runtime(error)) => assert_eq!(error.message, "division by zero"),
+        _ => panic!("expected a runtime error"),
+    }
+}
+
+#[test]
+fn compile_file_resolves_imports() {
+    let mut sources = SourceMap::new();
+    let program = dolang::compile_file(
+        Path::new("resources/import.do"),
+        &CompileOptions::default(),
+        &mut sources,
+        &mut Timings::new(),
+    )
+    .unwrap();
+    assert!(program.constants.contains(&"square".to_string()));
+}
+
+#[test]
+fn compile_file_reports_a_missing_file() {
+    let result = dolang::compile_file(
+        Path::new("resources/missing.do"),
+        &CompileOptions::default(),
+        &mut SourceMap::new(),
+        &mut Timings::new(),
+    );
+    assert!(matches!(result, Err(CompileError::Io { .. })));
+}
+
+fn lex(source: &str) -> Vec<Token> {
+    let mut lexer = Lexer::new();
+    let tokens = lexer.lex(source);
+    assert!(lexer.diagnostics.is_empty(), "{:?}", lexer.diagnostics);
+    tokens
+}
+
+#[test]
+fn two_char_comparison_spans_start_at_the_operator() {
+    let tokens = lex("1 <= 2 >= 3");
+    assert_eq!(tokens[1].kind, TokenKind::OpenAngleEquals);
+    assert_eq!(
+        tokens[1].span,
+        Span {
+            offset: 2,
+            length: 2
+        }
+    );
+    assert_eq!(tokens[3].kind, TokenKind::CloseAngleEquals);
+    assert_eq!(
+        tokens[3].span,
+        Span {
+            offset: 7,
+            length: 2
+        }
+    );
+}
+
+#[test]
+fn comparison_operator_at_end_of_input_lexes_alone() {
+    let tokens = lex("1 <");
+    assert_eq!(tokens.len(), 2);
+    assert_eq!(tokens[1].kind, TokenKind::OpenAngle);
+    assert_eq!(
+        tokens[1].span,
+        Span {
+            offset: 2,
+            length: 1
+        }
+    );
+
+    let tokens = lex("2 >");
+    assert_eq!(tokens[1].kind, TokenKind::CloseAngle);
+    assert_eq!(
+        tokens[1].span,
+        Span {
+            offset: 2,
+            length: 1
+        }
+    );
+}
+
+//Type checks a line the way the REPL does, leaving whatever it pushes on the type checker's stack
+fn check_line(type_checker: &mut TypeChecker, line: &str) {
+    let tokens = lex(line);
+    let mut parser = Parser::new();
+    let ops = parser.parse(&tokens);
+    assert!(parser.diagnostics.is_empty(), "{:?}", parser.diagnostics);
+    type_checker.type_check(&ops);
+    assert!(
+        !type_checker.diagnostics.iter().any(Diagnostic::is_error),
+        "{:?}",
+        type_checker.diagnostics
+    );
+}
+
+//The types left on the stack by a line, as the REPL shows them
+fn stack_types(line: &str) -> Vec<String> {
+    let mut type_checker = TypeChecker::new(false);
+    check_line(&mut type_checker, line);
+    type_checker
+        .type_stack
+        .iter()
+        .map(|(type_kind, _)| match type_checker.erase(type_kind) {
+            Some(type_kind) => type_kind.to_string(),
+            None => type_kind.to_string(),
+        })
+        .collect()
+}
+
+#[test]
+fn generics_are_shown_as_letters() {
+    assert_eq!(stack_types("(dup)"), ["fn [<a> -- <a> <a>]"]);
+    assert_eq!(stack_types("1 (len)"), ["int", "fn [[<a>] -- int]"]);
+}
+
+#[test]
+fn generics_stay_bounded_across_repl_lines() {
+    let mut type_checker = TypeChecker::new(false);
+    check_line(&mut type_checker, "1 (dup)");
+    type_checker.compact_generics();
+    let after_first_line = type_checker.generic_count();
+
+    for _ in 0..1000 {
+        check_line(&mut type_checker, "swap dup pop swap");
+        type_checker.compact_generics();
+    }
+    assert_eq!(type_checker.generic_count(), after_first_line);
+    assert_eq!(type_checker.generic_count(), 1);
+}
+
+//The codes of the diagnostics a program is rejected with
+fn error_codes(source: &str) -> Vec<DiagnosticCode> {
+    match dolang::compile(source) {
+        Ok(_) => panic!("expected `{}` to be rejected", source),
+        Err(diagnostics) => diagnostics
+            .iter()
+            .filter(|diagnostic| diagnostic.is_error())
+            .map(Diagnostic::code)
+            .collect(),
+    }
+}
+
+#[test]
+fn parser_reports_every_syntax_error() {
+    assert_eq!(
+        error_codes("1 print ) 2 print } 3 print"),
+        [
+            DiagnosticCode::UnexpectedToken,
+            DiagnosticCode::UnexpectedToken
+        ]
+    );
+}
+
+#[test]
+fn an_error_in_an_if_body_is_reported_once() {
+    assert_eq!(
+        error_codes("true if { foo }"),
+        [DiagnosticCode::UnknownIdentifier]
+    );
+    assert_eq!(
+        error_codes("true if { foo } else { }"),
+        [DiagnosticCode::UnknownIdentifier]
+    );
+}
+
+#[test]
+fn an_underflow_after_an_error_is_assumed_only_by_the_next_op() {
+    assert_eq!(
+        error_codes("foo print"),
+        [DiagnosticCode::UnknownIdentifier]
+    );
+    assert_eq!(
+        error_codes("foo 1 + + print"),
+        [
+            DiagnosticCode::UnknownIdentifier,
+            DiagnosticCode::EmptyStack
+        ]
+    );
+    assert_eq!(
+        error_codes("rot 1 2 + + + + + print"),
+        [DiagnosticCode::EmptyStack; 4]
+    );
+}
+
+#[test]
+fn len_only_takes_lists() {
+    assert_eq!(error_codes("5 len print"), [DiagnosticCode::TypeMismatch]);
+    assert_eq!(stack_types("[1 2 3] len"), ["int"]);
+}
+
+#[test]
+fn nested_blocks_get_their_own_names() {
+    let program = dolang::compile("((1) do) do print").unwrap();
+    let mut blocks: Vec<&str> = program
+        .bytecode
+        .iter()
+        .map(|(name, _)| name.as_str())
+        .filter(|name| name.starts_with("block_"))
+        .collect();
+    blocks.sort();
+    assert_eq!(blocks, ["block_0", "block_1"]);
+    assert_eq!(program.constants, blocks);
+    assert_eq!(run("((1) do) do print").unwrap(), "1\n");
+}
+
+//The one diagnostic a program is rejected with
+fn only_error(source: &str) -> Diagnostic {
+    let mut diagnostics = dolang::compile(source)
+        .err()
+        .unwrap_or_else(|| panic!("expected `{}` to be rejected", source));
+    diagnostics.retain(Diagnostic::is_error);
+    assert_eq!(diagnostics.len(), 1, "{:?}", diagnostics);
+    diagnostics.remove(0)
+}
+
+#[test]
+fn redefining_a_function_points_at_the_first_definition() {
+    let diagnostic = only_error("fn foo (1) fn foo (2) foo print");
+    assert_eq!(diagnostic.code(), DiagnosticCode::DuplicateDefinition);
+    assert_eq!(diagnostic.message(), "function `foo` is already defined");
+    assert_eq!(
+        diagnostic.span(),
+        Span {
+            offset: 14,
+            length: 3
+        }
+    );
+    assert_eq!(
+        diagnostic.hint(),
+        Some(&(
+            "previously defined here".to_string(),
+            Span {
+                offset: 3,
+                length: 3
+            }
+        ))
+    );
+}
+
+#[test]
+fn binding_annotations_are_checked() {
+    assert_eq!(
+        run("4 [1 2] let a: int b: [int] { a print b print }").unwrap(),
+        "4\n[1 2]\n"
+    );
+    let diagnostic = only_error("true let c: int { }");
+    assert_eq!(diagnostic.code(), DiagnosticCode::TypeMismatch);
+    assert_eq!(diagnostic.message(), "expected int but got bool");
+}
+
+#[test]
+fn an_error_in_a_list_points_at_the_element() {
+    let diagnostic = only_error("[1 true] print");
+    assert_eq!(diagnostic.code(), DiagnosticCode::TypeMismatch);
+    assert_eq!(
+        diagnostic.span(),
+        Span {
+            offset: 3,
+            length: 4
+        }
+    );
+}
+
+#[test]
+fn an_error_in_a_function_body_points_at_the_op() {
+    let diagnostic = only_error("fn f (1 true +) f print");
+    assert_eq!(diagnostic.code(), DiagnosticCode::TypeMismatch);
+    assert_eq!(
+        diagnostic.span(),
+        Span {
+            offset: 13,
+            length: 1
+        }
+    );
+}
+
+//The one warning a program compiles with
+fn only_warning(source: &str) -> Diagnostic {
+    let program = dolang::compile(source)
+        .unwrap_or_else(|diagnostics| panic!("`{}` was rejected: {:?}", source, diagnostics));
+    assert_eq!(program.warnings.len(), 1, "{:?}", program.warnings);
+    program.warnings[0].clone()
+}
+
+#[test]
+fn an_unused_function_is_a_warning() {
+    let warning = only_warning("fn unused (1) 2 print");
+    assert!(!warning.is_error());
+    assert_eq!(warning.code(), DiagnosticCode::Unused);
+    assert_eq!(warning.message(), "function `unused` is never used");
+    assert_eq!(run("fn unused (1) 2 print").unwrap(), "2\n");
+}
+
+#[test]
+fn an_unused_binding_is_a_warning() {
+    let warning = only_warning("1 let unused { 2 print }");
+    assert!(!warning.is_error());
+    assert_eq!(warning.code(), DiagnosticCode::Unused);
+    assert_eq!(warning.message(), "binding `unused` is never used");
+    assert_eq!(run("1 let unused { 2 print }").unwrap(), "2\n");
+
+    //Using it inside a nested block counts
+    assert!(
+        dolang::compile("1 let used { (used print) do }")
+            .unwrap()
+            .warnings
+            .is_empty()
+    );
+}
+
+#[test]
+fn mis_shaped_functions_passed_to_combinators_say_what_was_expected() {
+    for (source, message) in [
+        (
+            "[1 2 3] (dup) map print",
+            "`map` expects a function [<a> -- <b>] but got [<a> -- <a> <a>]",
+        ),
+        (
+            "[1 2 3] (pop) filter print",
+            "`filter` expects a function [<a> -- bool] but got [<a> -- ]",
+        ),
+        (
+            "[1 2 3] (+ 1) 0 fold print",
+            "`fold` expects a function [<a> <b> -- <a>] but got [int int -- int int]",
+        ),
+        (
+            "[1 2 3] (dup) foreach",
+            "`foreach` expects a function [<a> -- ] but got [<a> -- <a> <a>]",
+        ),
+    ] {
+        let diagnostic = only_error(source);
+        assert_eq!(diagnostic.code(), DiagnosticCode::TypeMismatch);
+        assert_eq!(diagnostic.message(), message);
+        //Pointing at the function, not the combinator
+        assert_eq!(
+            diagnostic.span(),
+            Span {
+                offset: 8,
+                length: 5
+            }
+        );
+    }
+}
+
+//Formats a file the way `do --fmt` does
+fn format_file(path: &str) -> String {
+    let input = std::fs::read_to_string(path).unwrap();
+    let mut lexer = Lexer::with_comments();
+    let tokens = lexer.lex(&input);
+    assert!(lexer.diagnostics.is_empty(), "{:?}", lexer.diagnostics);
+    let mut parser = Parser::new();
+    let ops = parser.parse(&tokens);
+    assert!(parser.diagnostics.is_empty(), "{:?}", parser.diagnostics);
+    formatter::format(&input, &tokens, &ops)
+}
+
+#[test]
+fn formatting_matches_the_expected_files() {
+    for name in ["messy", "comments"] {
+        let expected =
+            std::fs::read_to_string(format!("resources/fmt/{}.expected.do", name)).unwrap();
+        assert_eq!(format_file(&format!("resources/fmt/{}.do", name)), expected);
+        //Formatting is already done, so doing it again changes nothing
+        assert_eq!(
+            format_file(&format!("resources/fmt/{}.expected.do", name)),
+            expected
+        );
+    }
+}
+
+#[test]
+fn known_bad_programs_are_rejected_with_their_codes() {
+    assert_eq!(
+        error_codes("1 true + print"),
+        [DiagnosticCode::TypeMismatch]
+    );
+    assert_eq!(error_codes("print"), [DiagnosticCode::EmptyStack]);
+    assert_eq!(
+        error_codes("1 2 print"),
+        [DiagnosticCode::NonEmptyStackAtEnd]
+    );
+    assert_eq!(error_codes("foo"), [DiagnosticCode::UnknownIdentifier]);
+    assert_eq!(DiagnosticCode::TypeMismatch.id(), "E006");
+    assert_eq!(DiagnosticCode::EmptyStack.id(), "E007");
+    assert_eq!(DiagnosticCode::NonEmptyStackAtEnd.id(), "E008");
+    assert_eq!(DiagnosticCode::UnknownIdentifier.id(), "E009");
+}
+
+#[test]
+fn a_block_given_to_a_combinator_can_call_the_elements() {
+    assert_eq!(run("[(1) (2)] (do) map print").unwrap(), "[1 2]\n");
+    assert_eq!(
+        run("[(true) (false)] (do) filter len print").unwrap(),
+        "1\n"
+    );
+    assert_eq!(run("[(1) (2)] (do print) foreach").unwrap(), "1\n2\n");
+    //Anywhere else the function's signature isn't known
+    assert_eq!(
+        error_codes("[(1 +) (2 *)] (5 swap do) map print"),
+        [DiagnosticCode::UninferableFunction]
+    );
+}